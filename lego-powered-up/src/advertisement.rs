@@ -0,0 +1,145 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The manufacturer data LWP3 hubs advertise, which describes a hub
+//! before connecting to it
+
+use crate::consts::{BLEManufacturerData, HubType};
+use crate::error::{Error, Result};
+use num_traits::FromPrimitive;
+
+/// Everything a hub advertises about itself
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Advertisement {
+    /// The hub's button is held down
+    pub button_pressed: bool,
+    /// System type in the top three bits, and device number in the rest
+    pub system_type: u8,
+    pub capabilities: HubCapabilities,
+    /// Network the hub was last connected to, or 0 if none
+    pub last_network_id: u8,
+    pub status: HubStatus,
+    /// Protocol option bits
+    pub options: u8,
+}
+
+impl Advertisement {
+    /// Parse the manufacturer data advertised under LEGO's company id
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let Some(data) = data.get(..6) else {
+            return Err(Error::ParseError(format!(
+                "Advertisement too short: {:02x?}",
+                data
+            )));
+        };
+        Ok(Self {
+            button_pressed: data[0] != 0,
+            system_type: data[1],
+            capabilities: HubCapabilities(data[2]),
+            last_network_id: data[3],
+            status: HubStatus(data[4]),
+            options: data[5],
+        })
+    }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        vec![
+            self.button_pressed as u8,
+            self.system_type,
+            self.capabilities.0,
+            self.last_network_id,
+            self.status.0,
+            self.options,
+        ]
+    }
+
+    /// The kind of hub, if the library knows it
+    pub fn hub_type(&self) -> Option<HubType> {
+        system_hub_type(self.system_type)
+    }
+
+    /// Distinguishes devices of the same system type
+    pub fn device_number(&self) -> u8 {
+        self.system_type & 0x1f
+    }
+
+    /// The hub has been connected to a network before, so it is likely
+    /// paired with something else
+    pub fn has_network(&self) -> bool {
+        self.last_network_id != 0
+    }
+}
+
+/// The kind of hub with a system type and device number
+pub(crate) fn system_hub_type(system_type: u8) -> Option<HubType> {
+    use BLEManufacturerData::*;
+    Some(match BLEManufacturerData::from_u8(system_type)? {
+        DuploTrainBaseId => HubType::DuploTrainBase,
+        HubId => HubType::Hub,
+        MarioId => HubType::Mario,
+        MoveHubId => HubType::MoveHub,
+        RemoteControlId => HubType::RemoteControl,
+        TechnicMediumHubId => HubType::TechnicMediumHub,
+    })
+}
+
+/// The roles a hub can take
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HubCapabilities(pub u8);
+impl HubCapabilities {
+    pub const CENTRAL_ROLE: u8 = 0b0001;
+    pub const PERIPHERAL_ROLE: u8 = 0b0010;
+    /// Has connectors for LPF2 devices
+    pub const LPF2_DEVICES: u8 = 0b0100;
+    pub const REMOTE_CONTROLLER: u8 = 0b1000;
+
+    /// Whether all the capabilities in `flags` are present
+    pub fn contains(&self, flags: u8) -> bool {
+        self.0 & flags == flags
+    }
+}
+
+/// What a hub is ready to do
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HubStatus(pub u8);
+impl HubStatus {
+    pub const CAN_BE_PERIPHERAL: u8 = 0b0000_0001;
+    pub const CAN_BE_CENTRAL: u8 = 0b0000_0010;
+    /// The hub is asking for a connection window, e.g. while pairing
+    pub const REQUEST_WINDOW: u8 = 0b0010_0000;
+    /// The hub is asking to be connected to
+    pub const REQUEST_CONNECT: u8 = 0b0100_0000;
+
+    /// Whether all the flags in `flags` are set
+    pub fn contains(&self, flags: u8) -> bool {
+        self.0 & flags == flags
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn technic_hub_advertisement() {
+        let data = [0x01, 0x80, 0x06, 0x00, 0x41, 0x00];
+        let advertisement = Advertisement::parse(&data).unwrap();
+        assert!(advertisement.button_pressed);
+        assert_eq!(advertisement.hub_type(), Some(HubType::TechnicMediumHub));
+        assert_eq!(advertisement.device_number(), 0);
+        assert!(advertisement.capabilities.contains(
+            HubCapabilities::PERIPHERAL_ROLE | HubCapabilities::LPF2_DEVICES
+        ));
+        assert!(!advertisement.has_network());
+        assert!(advertisement.status.contains(HubStatus::REQUEST_CONNECT));
+        assert_eq!(advertisement.serialise(), data);
+
+        let paired =
+            Advertisement::parse(&[0, 0x41, 0x02, 0x07, 0, 0, 0xff]).unwrap();
+        assert_eq!(paired.hub_type(), Some(HubType::Hub));
+        assert_eq!(paired.device_number(), 1);
+        assert!(paired.has_network());
+        assert!(Advertisement::parse(&data[..5]).is_err());
+    }
+}
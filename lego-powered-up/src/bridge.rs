@@ -0,0 +1,618 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Bridging hubs over TCP, so that a machine with a Bluetooth adapter
+//! near the hubs can lend them to programs running elsewhere.
+//!
+//! `BridgeServer` serves the raw LWP3 messages of the hubs added to it.
+//! `BridgeTransport` is the matching client: it is a `Transport`, so a
+//! remote hub is used just like a local one.
+//!
+//! ```no_run
+//! # async fn example() -> lego_powered_up::Result<()> {
+//! use lego_powered_up::bridge::{self, BridgeServer};
+//! use lego_powered_up::PoweredUp;
+//!
+//! // Next to the hubs
+//! let mut pu = PoweredUp::init().await?;
+//! let discovered = pu.wait_for_hub().await?;
+//! let (transport, properties) = pu.connect_transport(&discovered).await?;
+//! let server = BridgeServer::bind("0.0.0.0:4000").await?;
+//! server.add_hub("crane", discovered.hub_type, transport, properties);
+//!
+//! // Anywhere else
+//! for hub in bridge::list_hubs("raspberrypi:4000").await? {
+//!     println!("{} is a {}", hub.id, hub.hub_type);
+//! }
+//! let hub = bridge::connect_hub("raspberrypi:4000", "crane").await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Each message on the connection is a frame of a u16 little endian
+//! length, then a kind byte and its payload. The client may list the
+//! hubs, then opens one; after that LWP3 messages are carried in both
+//! directions, and the client may ask the server to subscribe to the
+//! hub's notifications. Closing a connection leaves the hub connected
+//! to the bridge.
+
+use crate::consts::HubType;
+use crate::error::{Error, Result};
+use crate::hubs::{Hub, HubProperties};
+use crate::transport::{broadcast_stream, MessageStream, Transport};
+use async_trait::async_trait;
+use futures::stream::StreamExt;
+use num_traits::FromPrimitive;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::broadcast;
+use tokio::task::{JoinHandle, JoinSet};
+
+const LIST: u8 = 0x01;
+const OPEN: u8 = 0x02;
+const SUBSCRIBE: u8 = 0x03;
+const MESSAGE: u8 = 0x04;
+const HUBS: u8 = 0x81;
+const OPENED: u8 = 0x82;
+const ERROR: u8 = 0xff;
+
+/// A hub offered by a bridge
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BridgedHub {
+    /// Name the hub was added to the bridge under
+    pub id: String,
+    pub hub_type: HubType,
+    /// Friendly name of the hub
+    pub name: String,
+    pub mac_address: String,
+}
+
+impl BridgedHub {
+    fn serialise(&self, buf: &mut Vec<u8>) {
+        buf.push(self.hub_type as u8);
+        for text in [&self.id, &self.name, &self.mac_address] {
+            buf.push(text.len().min(u8::MAX as usize) as u8);
+            buf.extend(text.bytes().take(u8::MAX as usize));
+        }
+    }
+
+    fn parse(payload: &mut &[u8]) -> Result<Self> {
+        let hub_type = take(payload, 1)?[0];
+        let hub_type = HubType::from_u8(hub_type).ok_or_else(|| {
+            Error::ParseError(format!("Invalid hub type {}", hub_type))
+        })?;
+        let mut text = || -> Result<String> {
+            let len = take(payload, 1)?[0] as usize;
+            Ok(String::from_utf8_lossy(take(payload, len)?).into_owned())
+        };
+        Ok(Self {
+            id: text()?,
+            hub_type,
+            name: text()?,
+            mac_address: text()?,
+        })
+    }
+
+    fn properties(&self) -> HubProperties {
+        HubProperties {
+            name: self.name.clone(),
+            mac_address: self.mac_address.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Split `len` bytes off the front of a payload
+fn take<'a>(payload: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if payload.len() < len {
+        return Err(Error::ParseError("Bridge frame too short".to_string()));
+    }
+    let (head, tail) = payload.split_at(len);
+    *payload = tail;
+    Ok(head)
+}
+
+/// One message on a bridge connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Frame {
+    List,
+    Hubs(Vec<BridgedHub>),
+    Open(String),
+    Opened(BridgedHub),
+    Subscribe,
+    Message(Vec<u8>),
+    Error(String),
+}
+
+impl Frame {
+    /// Fails if the frame is too long for its length field
+    fn serialise(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![0, 0];
+        match self {
+            Frame::List => buf.push(LIST),
+            Frame::Hubs(hubs) => {
+                buf.push(HUBS);
+                for hub in hubs {
+                    hub.serialise(&mut buf);
+                }
+            }
+            Frame::Open(id) => {
+                buf.push(OPEN);
+                buf.extend(id.bytes());
+            }
+            Frame::Opened(hub) => {
+                buf.push(OPENED);
+                hub.serialise(&mut buf);
+            }
+            Frame::Subscribe => buf.push(SUBSCRIBE),
+            Frame::Message(msg) => {
+                buf.push(MESSAGE);
+                buf.extend(msg);
+            }
+            Frame::Error(e) => {
+                buf.push(ERROR);
+                buf.extend(e.bytes());
+            }
+        }
+        let len = u16::try_from(buf.len() - 2).map_err(|_| {
+            Error::HubError(format!(
+                "Bridge frame of {} bytes is too long",
+                buf.len() - 2
+            ))
+        })?;
+        buf[..2].copy_from_slice(&len.to_le_bytes());
+        Ok(buf)
+    }
+
+    fn parse(kind: u8, mut payload: &[u8]) -> Result<Self> {
+        let text = |payload: &[u8]| String::from_utf8_lossy(payload).into();
+        Ok(match kind {
+            LIST => Frame::List,
+            HUBS => {
+                let mut hubs = Vec::new();
+                while !payload.is_empty() {
+                    hubs.push(BridgedHub::parse(&mut payload)?);
+                }
+                Frame::Hubs(hubs)
+            }
+            OPEN => Frame::Open(text(payload)),
+            OPENED => Frame::Opened(BridgedHub::parse(&mut payload)?),
+            SUBSCRIBE => Frame::Subscribe,
+            MESSAGE => Frame::Message(payload.to_vec()),
+            ERROR => Frame::Error(text(payload)),
+            _ => {
+                return Err(Error::ParseError(format!(
+                    "Invalid bridge frame kind {:#04x}",
+                    kind
+                )))
+            }
+        })
+    }
+
+    /// Read the next frame, or `None` if the connection has closed
+    async fn read_from(
+        mut reader: impl AsyncRead + Unpin,
+    ) -> Result<Option<Self>> {
+        let mut len = [0; 2];
+        match reader.read_exact(&mut len).await {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Ok(None)
+            }
+            result => result?,
+        };
+        let mut buf = vec![0; u16::from_le_bytes(len) as usize];
+        reader.read_exact(&mut buf).await?;
+        let Some((&kind, payload)) = buf.split_first() else {
+            return Err(Error::ParseError("Empty bridge frame".to_string()));
+        };
+        Self::parse(kind, payload).map(Some)
+    }
+
+    async fn write_to(
+        &self,
+        mut writer: impl AsyncWrite + Unpin,
+    ) -> Result<()> {
+        writer.write_all(&self.serialise()?).await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ServedHub {
+    hub: BridgedHub,
+    transport: Arc<dyn Transport>,
+}
+
+type ServedHubs = Arc<Mutex<BTreeMap<String, ServedHub>>>;
+
+/// Serves hubs to bridge clients. Serving stops when the server is
+/// dropped.
+#[derive(Debug)]
+pub struct BridgeServer {
+    local_addr: SocketAddr,
+    hubs: ServedHubs,
+    task: JoinHandle<()>,
+}
+
+impl BridgeServer {
+    /// Listen for clients on `addr`, on the current tokio runtime
+    pub async fn bind(addr: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let hubs = ServedHubs::default();
+        let task = tokio::spawn(accept(listener, hubs.clone()));
+        info!("Bridge listening on {}", local_addr);
+        Ok(Self {
+            local_addr,
+            hubs,
+            task,
+        })
+    }
+
+    /// The address the server is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Offer a connected hub to clients as `id`, replacing any hub
+    /// already offered under that id
+    pub fn add_hub(
+        &self,
+        id: &str,
+        hub_type: HubType,
+        transport: Arc<dyn Transport>,
+        properties: HubProperties,
+    ) {
+        let hub = BridgedHub {
+            id: id.to_string(),
+            hub_type,
+            name: properties.name,
+            mac_address: properties.mac_address,
+        };
+        self.hubs
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), ServedHub { hub, transport });
+    }
+
+    /// Stop offering a hub. Clients already using it keep it.
+    pub fn remove_hub(&self, id: &str) -> Option<Arc<dyn Transport>> {
+        let hub = self.hubs.lock().unwrap().remove(id)?;
+        Some(hub.transport)
+    }
+
+    /// The hubs being offered
+    pub fn hubs(&self) -> Vec<BridgedHub> {
+        let hubs = self.hubs.lock().unwrap();
+        hubs.values().map(|served| served.hub.clone()).collect()
+    }
+}
+
+impl Drop for BridgeServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn accept(listener: TcpListener, hubs: ServedHubs) {
+    // Dropped along with the accept loop, which closes every connection
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    debug!("Bridge client {} connected", peer);
+                    let hubs = hubs.clone();
+                    connections.spawn(async move {
+                        if let Err(e) = serve_client(stream, hubs).await {
+                            warn!("Bridge client {}: {}", peer, e);
+                        }
+                        debug!("Bridge client {} disconnected", peer);
+                    });
+                }
+                Err(e) => warn!("Bridge failed to accept a client: {}", e),
+            },
+            Some(_) = connections.join_next() => {}
+        }
+    }
+}
+
+async fn serve_client(stream: TcpStream, hubs: ServedHubs) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    // The stream keeps a partly read frame when the select below picks
+    // a notification instead
+    let requests = futures::stream::unfold(reader, |mut reader| async move {
+        let frame = Frame::read_from(&mut reader).await;
+        Some((frame, reader))
+    });
+    let mut requests = Box::pin(requests);
+    let mut transport: Option<Arc<dyn Transport>> = None;
+    let mut notifications: MessageStream = Box::pin(futures::stream::pending());
+    loop {
+        let request = tokio::select! {
+            request = requests.next() => request,
+            msg = notifications.next() => match msg {
+                Some(msg) => {
+                    Frame::Message(msg).write_to(&mut writer).await?;
+                    continue;
+                }
+                None => return Err(Error::HubError("Hub disconnected".into())),
+            },
+        };
+        let Some(request) = request.transpose()?.flatten() else {
+            return Ok(());
+        };
+        let reply = match (request, &transport) {
+            (Frame::List, _) => {
+                let hubs = hubs.lock().unwrap();
+                Some(Frame::Hubs(
+                    hubs.values().map(|served| served.hub.clone()).collect(),
+                ))
+            }
+            (Frame::Open(id), None) => {
+                let served = hubs.lock().unwrap().get(&id).map(|served| {
+                    (served.hub.clone(), served.transport.clone())
+                });
+                match served {
+                    Some((hub, opened)) => {
+                        notifications = opened.notifications().await?;
+                        transport = Some(opened);
+                        Some(Frame::Opened(hub))
+                    }
+                    None => Some(Frame::Error(format!("No hub named {}", id))),
+                }
+            }
+            (Frame::Subscribe, Some(transport)) => {
+                match transport.subscribe().await {
+                    Ok(()) => None,
+                    Err(e) => Some(Frame::Error(e.to_string())),
+                }
+            }
+            (Frame::Message(msg), Some(transport)) => {
+                match transport.write(&msg).await {
+                    Ok(()) => None,
+                    Err(e) => Some(Frame::Error(e.to_string())),
+                }
+            }
+            (request, _) => {
+                Some(Frame::Error(format!("Unexpected request {:?}", request)))
+            }
+        };
+        if let Some(reply) = reply {
+            reply.write_to(&mut writer).await?;
+        }
+    }
+}
+
+/// Ask a bridge which hubs it offers
+pub async fn list_hubs(addr: impl ToSocketAddrs) -> Result<Vec<BridgedHub>> {
+    let mut stream = TcpStream::connect(addr).await?;
+    Frame::List.write_to(&mut stream).await?;
+    match Frame::read_from(&mut stream).await? {
+        Some(Frame::Hubs(hubs)) => Ok(hubs),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+/// Connect to a hub offered by a bridge
+pub async fn connect_hub(
+    addr: impl ToSocketAddrs,
+    id: &str,
+) -> Result<Box<dyn Hub>> {
+    let transport = BridgeTransport::connect(addr, id).await?;
+    let hub_type = transport.hub.hub_type;
+    let properties = transport.properties();
+    crate::init_hub(hub_type, Arc::new(transport), properties).await
+}
+
+fn unexpected(reply: Option<Frame>) -> Error {
+    match reply {
+        Some(Frame::Error(e)) => Error::HubError(e),
+        Some(reply) => {
+            Error::HubError(format!("Unexpected reply from bridge {:?}", reply))
+        }
+        None => Error::HubError("Bridge closed the connection".to_string()),
+    }
+}
+
+/// Transport to a hub offered by a bridge
+#[derive(Debug)]
+pub struct BridgeTransport {
+    hub: BridgedHub,
+    writer: tokio::sync::Mutex<OwnedWriteHalf>,
+    /// Dropped when the connection closes, so that notification streams
+    /// end
+    notifications: Arc<Mutex<Option<broadcast::Sender<Vec<u8>>>>>,
+    connected: Arc<AtomicBool>,
+    reader: JoinHandle<()>,
+}
+
+impl BridgeTransport {
+    /// Open the hub offered by the bridge at `addr` as `id`
+    pub async fn connect(addr: impl ToSocketAddrs, id: &str) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let (mut reader, mut writer) = stream.into_split();
+        Frame::Open(id.to_string()).write_to(&mut writer).await?;
+        let hub = match Frame::read_from(&mut reader).await? {
+            Some(Frame::Opened(hub)) => hub,
+            reply => return Err(unexpected(reply)),
+        };
+
+        let (sender, _) = broadcast::channel(256);
+        let notifications = Arc::new(Mutex::new(Some(sender.clone())));
+        let connected = Arc::new(AtomicBool::new(true));
+        let reader = {
+            let notifications = notifications.clone();
+            let connected = connected.clone();
+            tokio::spawn(async move {
+                loop {
+                    match Frame::read_from(&mut reader).await {
+                        Ok(Some(Frame::Message(msg))) => {
+                            let _ = sender.send(msg);
+                        }
+                        Ok(Some(Frame::Error(e))) => warn!("Bridge: {}", e),
+                        Ok(Some(frame)) => {
+                            warn!("Unexpected frame from bridge {:?}", frame)
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            warn!("Lost bridge connection: {}", e);
+                            break;
+                        }
+                    }
+                }
+                connected.store(false, Ordering::Relaxed);
+                notifications.lock().unwrap().take();
+            })
+        };
+        Ok(Self {
+            hub,
+            writer: tokio::sync::Mutex::new(writer),
+            notifications,
+            connected,
+            reader,
+        })
+    }
+
+    /// The hub as the bridge describes it
+    pub fn hub(&self) -> &BridgedHub {
+        &self.hub
+    }
+
+    /// What the bridge told us about the hub, for passing to
+    /// `TechnicHub::init`
+    pub fn properties(&self) -> HubProperties {
+        self.hub.properties()
+    }
+
+    async fn send(&self, frame: Frame) -> Result<()> {
+        if !self.connected.load(Ordering::Relaxed) {
+            return Err(Error::HubError("Bridge is disconnected".to_string()));
+        }
+        frame.write_to(&mut *self.writer.lock().await).await
+    }
+}
+
+impl Drop for BridgeTransport {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[async_trait]
+impl Transport for BridgeTransport {
+    async fn write(&self, msg: &[u8]) -> Result<()> {
+        self.send(Frame::Message(msg.to_vec())).await
+    }
+
+    async fn notifications(&self) -> Result<MessageStream> {
+        let notifications = self.notifications.lock().unwrap();
+        let sender = notifications.as_ref().ok_or_else(|| {
+            Error::HubError("Bridge is disconnected".to_string())
+        })?;
+        Ok(broadcast_stream(sender.subscribe()))
+    }
+
+    async fn subscribe(&self) -> Result<()> {
+        self.send(Frame::Subscribe).await
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        Ok(self.connected.load(Ordering::Relaxed))
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.connected.store(false, Ordering::Relaxed);
+        self.reader.abort();
+        self.notifications.lock().unwrap().take();
+        self.writer.lock().await.shutdown().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::consts::DeviceType;
+    use crate::hubs::Port;
+    use crate::simulator::SimulatedHub;
+
+    #[test]
+    fn frames_round_trip() {
+        let hub = BridgedHub {
+            id: "crane".to_string(),
+            hub_type: HubType::TechnicMediumHub,
+            name: "Crane hub".to_string(),
+            mac_address: "90:84:2B:00:00:01".to_string(),
+        };
+        for frame in [
+            Frame::List,
+            Frame::Hubs(vec![hub.clone(), hub.clone()]),
+            Frame::Open("crane".to_string()),
+            Frame::Opened(hub),
+            Frame::Subscribe,
+            Frame::Message(vec![5, 0, 0x21, 1, 1]),
+            Frame::Error("oops".to_string()),
+        ] {
+            let buf = frame.serialise().unwrap();
+            assert_eq!(
+                buf.len() - 2,
+                u16::from_le_bytes([buf[0], buf[1]]) as usize
+            );
+            assert_eq!(Frame::parse(buf[2], &buf[3..]).unwrap(), frame);
+        }
+        assert!(Frame::parse(OPENED, &[6, 5, b'c']).is_err());
+        assert!(Frame::parse(0x42, &[]).is_err());
+        // The kind byte takes the last byte the length can cover
+        assert!(Frame::Message(vec![0; 65534]).serialise().is_ok());
+        assert!(Frame::Message(vec![0; 65535]).serialise().is_err());
+    }
+
+    #[tokio::test]
+    async fn remote_hub_on_localhost() {
+        let simulated = SimulatedHub::new("Crane hub")
+            .attach(0, DeviceType::TechnicLargeAngularMotor)
+            .connect();
+        let server = BridgeServer::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr();
+        server.add_hub(
+            "crane",
+            HubType::TechnicMediumHub,
+            simulated.clone(),
+            simulated.properties(),
+        );
+
+        let hubs = list_hubs(addr).await.unwrap();
+        assert_eq!(hubs, server.hubs());
+        assert_eq!(hubs[0].name, "Crane hub");
+        assert!(BridgeTransport::connect(addr, "digger").await.is_err());
+
+        let hub = connect_hub(addr, "crane").await.unwrap();
+        assert_eq!(hub.properties().await.name, "Crane hub");
+        let description = hub.describe_port(Port::A).await.unwrap();
+        assert_eq!(description.modes.len(), 4);
+        let mut motor = hub.port(Port::A).await.unwrap();
+        motor
+            .start_speed(50, crate::notifications::Power::Cw(100))
+            .await
+            .unwrap();
+
+        // Closing the server ends the connection
+        drop(server);
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while hub.is_connected().await.unwrap() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(simulated.is_connected().await.unwrap());
+    }
+}
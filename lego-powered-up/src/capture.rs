@@ -0,0 +1,407 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Extraction of hub traffic from Bluetooth captures.
+//!
+//! Reads btsnoop files, as written by Android's HCI snoop log and by
+//! Wireshark, and the captures written by `btmon -w` on Linux. The ATT
+//! writes to the LPF2 characteristic and the notifications from it are
+//! returned as recording records, so they can be decoded or replayed
+//! like a session recorded by this library.
+//!
+//! The characteristic's handle is learned from GATT discovery in the
+//! capture. If the capture starts after discovery, every write and
+//! notification whose first byte is its own length is taken to be an
+//! LPF2 message.
+
+use crate::consts::blecharacteristic::LPF2_ALL;
+use crate::error::{Error, Result};
+use crate::recording::{Direction, Record};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read};
+use std::path::Path;
+use std::time::Duration;
+
+const MAGIC: &[u8; 8] = b"btsnoop\0";
+
+/// HCI packets without a packet type indicator
+const DATALINK_H1: u32 = 1001;
+/// HCI packets preceded by their UART packet type
+const DATALINK_H4: u32 = 1002;
+/// Linux monitor packets, as written by btmon
+const DATALINK_MONITOR: u32 = 2001;
+
+/// Longest packet we accept. HCI packets are far shorter, so anything
+/// longer is a corrupt length rather than something worth allocating.
+const MAX_PACKET_LEN: u32 = 64 * 1024;
+
+const H4_ACL: u8 = 0x02;
+const MONITOR_ACL_TX: u32 = 4;
+const MONITOR_ACL_RX: u32 = 5;
+
+const L2CAP_ATT_CID: u16 = 0x0004;
+
+const ATT_READ_BY_TYPE_RESPONSE: u8 = 0x09;
+const ATT_WRITE_REQUEST: u8 = 0x12;
+const ATT_WRITE_COMMAND: u8 = 0x52;
+const ATT_HANDLE_VALUE_NOTIFICATION: u8 = 0x1b;
+
+/// One packet of a btsnoop file
+struct Packet {
+    flags: u32,
+    /// Microseconds since midnight, 1 January 0 AD
+    timestamp: i64,
+    data: Vec<u8>,
+}
+
+impl Packet {
+    /// Read the next packet, or `None` at the end of the capture
+    fn read_from(mut reader: impl Read) -> Result<Option<Self>> {
+        let mut header = [0; 24];
+        match reader.read_exact(&mut header) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        let word = |i: usize| {
+            u32::from_be_bytes(header[i * 4..i * 4 + 4].try_into().unwrap())
+        };
+        let length = word(1);
+        if length > MAX_PACKET_LEN {
+            return Err(Error::ParseError(format!(
+                "Capture packet of {length} bytes is too long"
+            )));
+        }
+        let mut data = vec![0; length as usize];
+        reader.read_exact(&mut data)?;
+        Ok(Some(Self {
+            flags: word(2),
+            timestamp: i64::from_be_bytes(header[16..].try_into().unwrap()),
+            data,
+        }))
+    }
+}
+
+/// Reassembles L2CAP frames from ACL fragments and picks out the ATT
+/// traffic of the LPF2 characteristic
+#[derive(Default)]
+struct Decoder {
+    /// Partial frames, by connection handle and whether they were received
+    partial: HashMap<(u16, bool), Vec<u8>>,
+    /// Value handle of the LPF2 characteristic, by connection handle
+    lpf2_handles: HashMap<u16, u16>,
+}
+
+impl Decoder {
+    /// Feed in an ACL packet, returning an LPF2 message once one is
+    /// complete
+    fn acl(
+        &mut self,
+        packet: &[u8],
+        received: bool,
+    ) -> Option<(Direction, Vec<u8>)> {
+        if packet.len() < 4 {
+            return None;
+        }
+        let header = u16::from_le_bytes([packet[0], packet[1]]);
+        let connection = header & 0x0fff;
+        let continuation = (header >> 12) & 0x3 == 0x1;
+        let length = u16::from_le_bytes([packet[2], packet[3]]) as usize;
+        let payload = packet.get(4..4 + length)?;
+
+        let key = (connection, received);
+        if !continuation {
+            self.partial.insert(key, Vec::new());
+        }
+        let frame = self.partial.get_mut(&key)?;
+        frame.extend_from_slice(payload);
+        if frame.len() < 4 {
+            return None;
+        }
+        let frame_length = u16::from_le_bytes([frame[0], frame[1]]) as usize;
+        if frame.len() < frame_length + 4 {
+            return None;
+        }
+        let frame = self.partial.remove(&key)?;
+        let cid = u16::from_le_bytes([frame[2], frame[3]]);
+        if cid != L2CAP_ATT_CID {
+            return None;
+        }
+        self.att(connection, &frame[4..frame_length + 4])
+    }
+
+    fn att(
+        &mut self,
+        connection: u16,
+        pdu: &[u8],
+    ) -> Option<(Direction, Vec<u8>)> {
+        let (&opcode, params) = pdu.split_first()?;
+        let direction = match opcode {
+            ATT_READ_BY_TYPE_RESPONSE => {
+                self.characteristics(connection, params);
+                return None;
+            }
+            ATT_WRITE_REQUEST | ATT_WRITE_COMMAND => Direction::Sent,
+            ATT_HANDLE_VALUE_NOTIFICATION => Direction::Received,
+            _ => return None,
+        };
+        if params.len() < 2 {
+            return None;
+        }
+        let handle = u16::from_le_bytes([params[0], params[1]]);
+        let value = &params[2..];
+        let lpf2 = match self.lpf2_handles.get(&connection) {
+            Some(lpf2) => *lpf2 == handle,
+            None => value.len() >= 3 && value[0] as usize == value.len(),
+        };
+        lpf2.then(|| (direction, value.to_vec()))
+    }
+
+    /// Look for the LPF2 characteristic among the declarations in a Read
+    /// By Type response
+    fn characteristics(&mut self, connection: u16, params: &[u8]) {
+        let Some((&entry_length, entries)) = params.split_first() else {
+            return;
+        };
+        // Characteristic declarations with a 128 bit UUID: the attribute
+        // handle, properties, value handle and UUID
+        if entry_length != 21 {
+            return;
+        }
+        for entry in entries.chunks_exact(21) {
+            let uuid = u128::from_le_bytes(entry[5..].try_into().unwrap());
+            if uuid == LPF2_ALL.as_u128() {
+                let handle = u16::from_le_bytes([entry[3], entry[4]]);
+                debug!("LPF2 characteristic has handle {:#06x}", handle);
+                self.lpf2_handles.insert(connection, handle);
+            }
+        }
+    }
+}
+
+/// Read the LPF2 messages from a btsnoop or btmon capture. Timestamps
+/// are relative to the first packet of the capture.
+pub fn read_capture(reader: impl Read) -> Result<Vec<Record>> {
+    let mut reader = BufReader::new(reader);
+    let mut header = [0; 16];
+    reader.read_exact(&mut header)?;
+    if &header[..8] != MAGIC {
+        return Err(Error::ParseError("Not a btsnoop capture".to_string()));
+    }
+    let datalink = u32::from_be_bytes(header[12..].try_into().unwrap());
+    if ![DATALINK_H1, DATALINK_H4, DATALINK_MONITOR].contains(&datalink) {
+        return Err(Error::NotImplementedError(format!(
+            "Captures of datalink type {}",
+            datalink
+        )));
+    }
+
+    let mut decoder = Decoder::default();
+    let mut start = None;
+    let mut records = Vec::new();
+    while let Some(packet) = Packet::read_from(&mut reader)? {
+        let start = *start.get_or_insert(packet.timestamp);
+        let (acl, received) = match datalink {
+            // Bit 1 of the flags marks commands and events
+            DATALINK_H1 if packet.flags & 0x2 == 0 => {
+                (&packet.data[..], packet.flags & 0x1 != 0)
+            }
+            DATALINK_H4 if packet.data.first() == Some(&H4_ACL) => {
+                (&packet.data[1..], packet.flags & 0x1 != 0)
+            }
+            // The low half of the flags is the opcode, the high half the
+            // index of the controller
+            DATALINK_MONITOR => match packet.flags & 0xffff {
+                MONITOR_ACL_TX => (&packet.data[..], false),
+                MONITOR_ACL_RX => (&packet.data[..], true),
+                _ => continue,
+            },
+            _ => continue,
+        };
+        if let Some((direction, data)) = decoder.acl(acl, received) {
+            let micros = packet.timestamp.saturating_sub(start).max(0);
+            records.push(Record {
+                timestamp: Duration::from_micros(micros as u64),
+                direction,
+                data,
+            });
+        }
+    }
+    Ok(records)
+}
+
+/// Read the LPF2 messages from a btsnoop or btmon capture file
+pub fn open_capture(path: impl AsRef<Path>) -> Result<Vec<Record>> {
+    read_capture(File::open(path)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::consts::MessageType;
+
+    /// A capture with the given datalink type and packets of flags,
+    /// milliseconds and data
+    fn capture(datalink: u32, packets: &[(u32, i64, Vec<u8>)]) -> Vec<u8> {
+        let mut buf = MAGIC.to_vec();
+        buf.extend(1_u32.to_be_bytes());
+        buf.extend(datalink.to_be_bytes());
+        for (flags, millis, data) in packets {
+            let length = (data.len() as u32).to_be_bytes();
+            buf.extend(length);
+            buf.extend(length);
+            buf.extend(flags.to_be_bytes());
+            buf.extend(0_u32.to_be_bytes());
+            buf.extend((0x00dc_ddb3_0f2f_8000 + millis * 1000).to_be_bytes());
+            buf.extend(data);
+        }
+        buf
+    }
+
+    /// An ATT PDU on connection 0x0040, split into ACL packets carrying
+    /// at most `mtu` bytes of L2CAP frame each
+    fn acl(pdu: &[u8], mtu: usize) -> Vec<Vec<u8>> {
+        let mut frame = (pdu.len() as u16).to_le_bytes().to_vec();
+        frame.extend(L2CAP_ATT_CID.to_le_bytes());
+        frame.extend(pdu);
+        frame
+            .chunks(mtu)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let header: u16 = if i == 0 { 0x2040 } else { 0x1040 };
+                let mut packet = header.to_le_bytes().to_vec();
+                packet.extend((chunk.len() as u16).to_le_bytes());
+                packet.extend(chunk);
+                packet
+            })
+            .collect()
+    }
+
+    fn att(opcode: u8, handle: u16, value: &[u8]) -> Vec<u8> {
+        let mut pdu = vec![opcode];
+        pdu.extend(handle.to_le_bytes());
+        pdu.extend(value);
+        pdu
+    }
+
+    #[test]
+    fn btsnoop_capture() {
+        // Characteristic declaration at 0x0d, with the value at 0x0e
+        let mut discovery = vec![ATT_READ_BY_TYPE_RESPONSE, 21, 0x0d, 0, 0x1e];
+        discovery.extend(0x0e_u16.to_le_bytes());
+        discovery.extend(LPF2_ALL.as_u128().to_le_bytes());
+
+        let request = [5, 0, 0x21, 0, 0];
+        let attached = [15, 0, 4, 0, 1, 0x2e, 0, 0, 0, 0, 0x10, 0, 0, 0, 0x10];
+        let other = [3, 0, 0];
+        let h4 = |packet: Vec<u8>| [vec![H4_ACL], packet].concat();
+
+        let mut packets = vec![(1, 0, h4(acl(&discovery, 64).remove(0)))];
+        packets.push((
+            0,
+            10,
+            h4(acl(&att(ATT_WRITE_COMMAND, 0x0e, &request), 64).remove(0)),
+        ));
+        // A command, which isn't ACL data
+        packets.push((0, 11, vec![0x01, 0x03, 0x0c, 0x00]));
+        for packet in
+            acl(&att(ATT_HANDLE_VALUE_NOTIFICATION, 0x0e, &attached), 8)
+        {
+            packets.push((1, 25, h4(packet)));
+        }
+        packets.push((
+            1,
+            30,
+            h4(acl(&att(ATT_HANDLE_VALUE_NOTIFICATION, 0x11, &other), 64)
+                .remove(0)),
+        ));
+
+        let records =
+            read_capture(&capture(DATALINK_H4, &packets)[..]).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                Record {
+                    timestamp: Duration::from_millis(10),
+                    direction: Direction::Sent,
+                    data: request.to_vec(),
+                },
+                Record {
+                    timestamp: Duration::from_millis(25),
+                    direction: Direction::Received,
+                    data: attached.to_vec(),
+                },
+            ]
+        );
+        assert!(records.iter().all(|r| r.parse().is_ok()));
+    }
+
+    #[test]
+    fn btmon_capture_without_discovery() {
+        let request = [5, 0, 0x21, 0, 0];
+        let packets = vec![
+            // New index, then traffic on controller 1
+            (0, 0, vec![0; 16]),
+            (
+                (1 << 16) | MONITOR_ACL_TX,
+                2,
+                acl(&att(ATT_WRITE_REQUEST, 0x0e, &request), 64).remove(0),
+            ),
+            // Not the length of the message, so not LPF2
+            (
+                (1 << 16) | MONITOR_ACL_RX,
+                3,
+                acl(&att(ATT_HANDLE_VALUE_NOTIFICATION, 0x03, b"name"), 64)
+                    .remove(0),
+            ),
+        ];
+        let records =
+            read_capture(&capture(DATALINK_MONITOR, &packets)[..]).unwrap();
+        assert_eq!(
+            records,
+            vec![Record {
+                timestamp: Duration::from_millis(2),
+                direction: Direction::Sent,
+                data: request.to_vec(),
+            }]
+        );
+        assert!(read_capture(&b"LPUREC01"[..]).is_err());
+        assert!(read_capture(&capture(1, &[])[..]).is_err());
+        let mut oversized = capture(DATALINK_MONITOR, &[]);
+        oversized.extend(u32::MAX.to_be_bytes());
+        oversized.extend(u32::MAX.to_be_bytes());
+        oversized.extend([0; 16]);
+        assert!(matches!(
+            read_capture(&oversized[..]),
+            Err(Error::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn unparsed_messages_are_errors() {
+        // Network commands which have no parser yet
+        let unparsed = [0x05, 0x06, 0x08, 0x0b, 0x0e];
+        let packets: Vec<_> = unparsed
+            .iter()
+            .map(|command| {
+                let msg =
+                    [4, 0, MessageType::HwNetworkCommands as u8, *command];
+                (
+                    (1 << 16) | MONITOR_ACL_TX,
+                    0,
+                    acl(&att(ATT_WRITE_COMMAND, 0x0e, &msg), 64).remove(0),
+                )
+            })
+            .collect();
+        let records =
+            read_capture(&capture(DATALINK_MONITOR, &packets)[..]).unwrap();
+        assert_eq!(records.len(), unparsed.len());
+        for record in records {
+            assert!(matches!(
+                record.parse(),
+                Err(Error::NotImplementedError(_))
+            ));
+        }
+    }
+}
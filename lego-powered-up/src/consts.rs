@@ -20,7 +20,7 @@ use std::fmt::{self, Display};
 /// @property {number} MARIO 7
 /// ```
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive)]
 pub enum HubType {
     Unknown = 0,
     Wedo2SmartHub = 1,
@@ -73,7 +73,7 @@ impl Display for HubType {
 /// @property {number} CONTROL_PLUS_TILT 59
 /// ```
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive)]
 pub enum DeviceType {
     Unknown = 0,
     SimpleMediumLinearMotor = 1,
@@ -130,7 +130,7 @@ pub enum DeviceType {
 /// @property {number} NONE 255
 /// ```
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive)]
 pub enum Color {
     Black = 0,
     Pink = 1,
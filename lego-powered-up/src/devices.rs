@@ -4,34 +4,123 @@
 
 //! Definitions for the various devices which can attach to hubs, e.g. motors
 
-use crate::error::{Error, Result};
+use crate::consts::Color;
+use crate::error::{Error, OptionContext, Result};
 use crate::hubs::Port;
-use crate::notifications::{HubLedMode, NotificationMessage, Power};
+use crate::notifications::{
+    EndState, HubLedMode, InformationRequest, InformationType,
+    InputSetupSingle, ModeInformationRequest, ModeInformationType,
+    NotificationMessage, PortInformationType, PortModeInformationType,
+    PortOutputSubcommand, Power, WriteDirectModeDataPayload,
+};
+use crate::transport::{MessageStream, Transport};
 use async_trait::async_trait;
-use btleplug::api::{Characteristic, Peripheral as _, WriteType};
-use btleplug::platform::Peripheral;
+use futures::{future, Stream, StreamExt};
+use std::any::Any;
 use std::fmt::Debug;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicI8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+mod basic_motor;
+mod calibration;
+mod color_distance;
+mod distance;
+mod force;
+mod generic;
+mod imu;
+mod motion;
+mod piezo;
+mod power;
+mod stall;
+mod technic_color;
+mod temperature;
+mod tilt;
+
+pub use basic_motor::BasicMotor;
+pub use calibration::{calibrate, Calibration, CalibrationOptions};
+pub use color_distance::{ColorDistance, ColorDistanceSensor};
+pub use distance::{DistanceSensor, EyeLights};
+pub use force::ForceSensor;
+pub(crate) use generic::describe_port;
+pub use generic::{GenericDevice, ModeDescription, PortDescription};
+pub use imu::{
+    Accelerometer, Gesture, GestureSensor, GyroSensor, Tilt, TiltSensor,
+};
+pub use motion::MotionSensor;
+pub use piezo::{parse_melody, Note, PiezoBuzzer};
+pub use power::{CurrentSensor, Scale, VoltageSensor};
+pub use stall::{
+    StallAction, StallConfig, StallEvent, StallMonitor, StallReason,
+};
+pub use technic_color::{Hsv, TechnicColorSensor};
+pub use temperature::TemperatureSensor;
+pub use tilt::{
+    ExternalTiltSensor, MoveHubTiltSensor, TiltAngle, TiltDirection,
+};
+
+/// A value reported by a device, stamped with the time it was received
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Reading<T> {
+    pub value: T,
+    pub timestamp: Instant,
+}
+
+/// Stream of readings from a device subscription
+pub type ReadingStream<T> = Pin<Box<dyn Stream<Item = Reading<T>> + Send>>;
+
+/// How long to wait for a device to report a value
+const READING_TIMEOUT: Duration = Duration::from_secs(1);
 
 /// Trait that any device may implement. Having a single trait covering
 /// every device is probably the wrong design, and we should have better
 /// abstractions for e.g. motors vs. sensors & LEDs.
+///
+/// What only some devices can do, such as reading a sensor, is done with
+/// methods of the device's own type, which `downcast` gives access to.
 #[async_trait]
-pub trait Device: Debug + Send + Sync {
+pub trait Device: AsAny + Debug + Send + Sync {
     fn port(&self) -> Port;
-    fn peripheral(&self) -> &Peripheral;
-    fn characteristic(&self) -> &Characteristic;
+    fn port_id(&self) -> u8;
+    fn transport(&self) -> &dyn Transport;
     async fn send(&mut self, msg: NotificationMessage) -> Result<()> {
         let buf = msg.serialise();
-        self.peripheral()
-            .write(self.characteristic(), &buf, WriteType::WithoutResponse)
-            .await?;
-        Ok(())
+        self.transport().write(&buf).await
+    }
+    /// Put the port into `mode` and enable value notifications whenever
+    /// the value changes by at least `delta`. The stream yields the raw
+    /// value bytes. A port reports only one mode at a time, so a new
+    /// subscription replaces any previous one on the same port.
+    async fn subscribe(
+        &mut self,
+        mode: u8,
+        delta: u32,
+    ) -> Result<ReadingStream<Vec<u8>>> {
+        subscribe_port(self.transport(), self.port_id(), mode, delta).await
+    }
+    /// Wait for the next value of `mode`, as raw bytes
+    async fn read_mode(&mut self, mode: u8) -> Result<Vec<u8>> {
+        let mut stream = self.subscribe(mode, 1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+    /// Ask the hub which modes this device offers
+    async fn describe(&self) -> Result<PortDescription> {
+        describe_port(self.transport(), self.port_id()).await
     }
     async fn set_rgb(&mut self, _rgb: &[u8; 3]) -> Result<()> {
         Err(Error::NotImplementedError(
             "Not implemented for type".to_string(),
         ))
     }
+    /// Set a light to one of the named colours
+    async fn set_color(&mut self, _color: Color) -> Result<()> {
+        Err(Error::NotImplementedError(
+            "Not implemented for type".to_string(),
+        ))
+    }
     async fn start_speed(
         &mut self,
         _speed: i8,
@@ -43,14 +132,304 @@ pub trait Device: Debug + Send + Sync {
     }
 }
 
+/// Access to a device as its concrete type. Implemented for every type,
+/// so that devices need not implement it themselves.
+pub trait AsAny: Any + Send + Sync {
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<T: Any + Send + Sync> AsAny for T {
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl dyn Device {
+    /// The device as its concrete type, or `None` if it is of another type
+    pub fn downcast_mut<T: Device>(&mut self) -> Option<&mut T> {
+        AsAny::as_any_mut(self).downcast_mut()
+    }
+
+    /// Convert a device handed out by `Hub::port` into its concrete type,
+    /// e.g. `ForceSensor`, for the methods only that type has
+    pub fn downcast<T: Device>(self: Box<Self>) -> Result<Box<T>> {
+        let port = self.port();
+        <dyn Device as AsAny>::into_any(self)
+            .downcast()
+            .map_err(|_| {
+                let name = std::any::type_name::<T>();
+                Error::HubError(format!(
+                    "Device on port `{port:?}` is not a {}",
+                    name.rsplit("::").next().unwrap_or(name)
+                ))
+            })
+    }
+}
+
+/// Enable value notifications for `mode` on any port of the hub, which
+/// need not be the port of a device we hold, e.g. the hub's current sensor
+pub(crate) async fn subscribe_port(
+    transport: &dyn Transport,
+    port_id: u8,
+    mode: u8,
+    delta: u32,
+) -> Result<ReadingStream<Vec<u8>>> {
+    // Subscribe before enabling so that the first value isn't missed
+    let notifications = transport.notifications().await?;
+    let setup =
+        NotificationMessage::PortInputFormatSetupSingle(InputSetupSingle {
+            port_id,
+            mode,
+            delta,
+            notification_enabled: true,
+        });
+    transport.write(&setup.serialise()).await?;
+
+    Ok(stamped(
+        notifications,
+        move |msg| match NotificationMessage::parse(msg) {
+            Ok(NotificationMessage::PortValueSingle(value))
+                if value.port_id() == Some(port_id) =>
+            {
+                Some(value.data().to_vec())
+            }
+            _ => None,
+        },
+    ))
+}
+
+/// Pick values out of incoming messages, stamping each with the time it
+/// arrived rather than the time the consumer gets round to polling for
+/// it. The messages are read on a task of their own, which ends along
+/// with the returned stream.
+fn stamped<T, F>(mut notifications: MessageStream, f: F) -> ReadingStream<T>
+where
+    T: Send + 'static,
+    F: Fn(&[u8]) -> Option<T> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                msg = notifications.next() => {
+                    let Some(msg) = msg else { break };
+                    let Some(value) = f(&msg) else { continue };
+                    // On tokio's clock, which tests can pause
+                    let reading = Reading {
+                        value,
+                        timestamp: tokio::time::Instant::now().into_std(),
+                    };
+                    if sender.send(reading).is_err() {
+                        break;
+                    }
+                }
+                _ = sender.closed() => break,
+            }
+        }
+    });
+    Box::pin(futures::stream::unfold(
+        receiver,
+        |mut receiver| async move {
+            let reading = receiver.recv().await?;
+            Some((reading, receiver))
+        },
+    ))
+}
+
+/// Ask the hub for one piece of information about a mode of a port
+pub(crate) async fn mode_information(
+    transport: &dyn Transport,
+    port_id: u8,
+    mode: u8,
+    information_type: ModeInformationType,
+) -> Result<PortModeInformationType> {
+    let request = NotificationMessage::PortModeInformationRequest(
+        ModeInformationRequest {
+            port_id,
+            mode,
+            information_type,
+        },
+    );
+    request_reply(transport, request, |reply| match reply {
+        NotificationMessage::PortModeInformation(info)
+            if info.port_id() == port_id
+                && info.mode() == mode
+                && info.information_type().kind() == information_type =>
+        {
+            Some(info.information_type().clone())
+        }
+        _ => None,
+    })
+    .await
+}
+
+/// Ask the hub for information about a port
+pub(crate) async fn port_information(
+    transport: &dyn Transport,
+    port_id: u8,
+    information_type: InformationType,
+) -> Result<PortInformationType> {
+    let request =
+        NotificationMessage::PortInformationRequest(InformationRequest {
+            port_id,
+            information_type,
+        });
+    request_reply(transport, request, |reply| match reply {
+        NotificationMessage::PortInformation(info)
+            if info.port_id() == port_id =>
+        {
+            Some(info.information_type().clone())
+        }
+        _ => None,
+    })
+    .await
+}
+
+/// Send a request and wait for the first message that `reply` accepts.
+/// The hub answers requests it can't fulfil with an error message, which
+/// fails the request rather than waiting for the timeout.
+async fn request_reply<T, F>(
+    transport: &dyn Transport,
+    request: NotificationMessage,
+    reply: F,
+) -> Result<T>
+where
+    F: Fn(NotificationMessage) -> Option<T>,
+{
+    // Subscribe before asking so that the reply isn't missed
+    let mut notifications = transport.notifications().await?;
+    let request_type = request.message_type();
+    transport.write(&request.serialise()).await?;
+
+    let wait = async {
+        while let Some(msg) = notifications.next().await {
+            match NotificationMessage::parse(&msg) {
+                Ok(NotificationMessage::GenericErrorMessages(error))
+                    if error.command_type() == request_type =>
+                {
+                    return Err(Error::HubError(format!(
+                        "Request {:?} failed: {:?}",
+                        request,
+                        error.error_code()
+                    )));
+                }
+                Ok(msg) => {
+                    if let Some(value) = reply(msg) {
+                        return Ok(value);
+                    }
+                }
+                Err(_) => {}
+            }
+        }
+        Err(Error::HubError("Notification stream ended".to_string()))
+    };
+    timeout(READING_TIMEOUT, wait)
+        .await
+        .map_err(|_| Error::TimeoutError(format!("No reply to {request:?}")))?
+}
+
+/// Resample a stream so that it yields the latest reading every `period`,
+/// even when the value hasn't changed. Nothing is yielded until the first
+/// reading arrives, and the stream ends when the source does.
+pub fn sample<T>(stream: ReadingStream<T>, period: Duration) -> ReadingStream<T>
+where
+    T: Clone + Send + 'static,
+{
+    let ticks = tokio::time::interval(period);
+    Box::pin(futures::stream::unfold(
+        (stream, ticks, None::<Reading<T>>),
+        |(mut stream, mut ticks, mut latest)| async move {
+            loop {
+                tokio::select! {
+                    reading = stream.next() => {
+                        latest = Some(reading?);
+                    }
+                    tick = ticks.tick() => {
+                        if let Some(reading) = &latest {
+                            let sampled = Reading {
+                                value: reading.value.clone(),
+                                timestamp: tick.into_std(),
+                            };
+                            return Some((sampled, (stream, ticks, latest)));
+                        }
+                    }
+                }
+            }
+        },
+    ))
+}
+
+/// Convert a stream of raw readings into typed readings, dropping any
+/// which are too short to decode
+fn decode<T, F>(stream: ReadingStream<Vec<u8>>, f: F) -> ReadingStream<T>
+where
+    T: Send + 'static,
+    F: Fn(&[u8]) -> Option<T> + Send + 'static,
+{
+    Box::pin(stream.filter_map(move |reading| {
+        future::ready(f(&reading.value).map(|value| Reading {
+            value,
+            timestamp: reading.timestamp,
+        }))
+    }))
+}
+
+/// Switch a device to an output mode and write to it. This replaces any
+/// subscription on the port.
+pub(crate) async fn write_mode<D: Device + ?Sized>(
+    device: &mut D,
+    mode: u8,
+    data: Vec<u8>,
+) -> Result<()> {
+    use crate::notifications::*;
+
+    let port_id = device.port_id();
+    device
+        .send(NotificationMessage::PortInputFormatSetupSingle(
+            InputSetupSingle {
+                port_id,
+                mode,
+                delta: 1,
+                notification_enabled: false,
+            },
+        ))
+        .await?;
+    let msg = NotificationMessage::PortOutputCommand(PortOutputCommandFormat {
+        port_id,
+        startup_info: StartupInfo::ExecuteImmediately,
+        completion_info: CompletionInfo::NoAction,
+        subcommand: PortOutputSubcommand::WriteDirectModeData(
+            WriteDirectModeDataPayload::ModeData { mode, data },
+        ),
+    });
+    device.send(msg).await
+}
+
+/// Wait for the next reading, giving up if the device stays silent
+pub(crate) async fn next_reading<T>(
+    stream: &mut ReadingStream<T>,
+) -> Result<Reading<T>> {
+    timeout(READING_TIMEOUT, stream.next())
+        .await
+        .map_err(|_| {
+            Error::TimeoutError("Device did not report a value".to_string())
+        })?
+        .context("Reading stream ended")
+}
+
 /// Struct representing a Hub LED
 #[derive(Debug, Clone)]
 pub struct HubLED {
     /// RGB colour value
     rgb: [u8; 3],
-    _mode: HubLedMode,
-    peripheral: Peripheral,
-    characteristic: Characteristic,
+    /// Mode the LED was last put into, if any
+    mode: Option<HubLedMode>,
+    transport: Arc<dyn Transport>,
     port_id: u8,
 }
 
@@ -60,71 +439,95 @@ impl Device for HubLED {
         Port::HubLed
     }
 
-    fn peripheral(&self) -> &Peripheral {
-        &self.peripheral
+    fn port_id(&self) -> u8 {
+        self.port_id
     }
 
-    fn characteristic(&self) -> &Characteristic {
-        &self.characteristic
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
     }
 
     async fn set_rgb(&mut self, rgb: &[u8; 3]) -> Result<()> {
-        use crate::notifications::*;
-
         self.rgb = *rgb;
+        self.set_mode(HubLedMode::Rgb).await?;
+        self.write(WriteDirectModeDataPayload::SetRgbColors {
+            red: rgb[0],
+            green: rgb[1],
+            blue: rgb[2],
+        })
+        .await
+    }
 
+    async fn set_color(&mut self, color: Color) -> Result<()> {
+        self.set_mode(HubLedMode::Colour).await?;
+        self.write(WriteDirectModeDataPayload::SetRgbColorNo(color as i8))
+            .await
+    }
+}
+
+impl HubLED {
+    pub(crate) fn new(transport: Arc<dyn Transport>, port_id: u8) -> Self {
+        Self {
+            rgb: [0; 3],
+            mode: None,
+            transport,
+            port_id,
+        }
+    }
+
+    /// Switch the LED mode, unless it is already in that mode
+    async fn set_mode(&mut self, mode: HubLedMode) -> Result<()> {
+        if self.mode == Some(mode) {
+            return Ok(());
+        }
         let mode_set_msg =
             NotificationMessage::PortInputFormatSetupSingle(InputSetupSingle {
-                port_id: 50,
-                mode: 0x01,
+                port_id: self.port_id,
+                mode: mode as u8,
                 delta: 0x00000001,
                 notification_enabled: false,
             });
         self.send(mode_set_msg).await?;
+        self.mode = Some(mode);
+        Ok(())
+    }
 
-        let subcommand = PortOutputSubcommand::WriteDirectModeData(
-            WriteDirectModeDataPayload::SetRgbColors {
-                red: rgb[0],
-                green: rgb[1],
-                blue: rgb[2],
-            },
-        );
+    async fn write(
+        &mut self,
+        payload: WriteDirectModeDataPayload,
+    ) -> Result<()> {
+        use crate::notifications::*;
 
         let msg =
             NotificationMessage::PortOutputCommand(PortOutputCommandFormat {
                 port_id: self.port_id,
                 startup_info: StartupInfo::ExecuteImmediately,
                 completion_info: CompletionInfo::NoAction,
-                subcommand,
+                subcommand: PortOutputSubcommand::WriteDirectModeData(payload),
             });
         self.send(msg).await
     }
 }
 
-impl HubLED {
-    pub(crate) fn new(
-        peripheral: Peripheral,
-        characteristic: Characteristic,
-        port_id: u8,
-    ) -> Self {
-        let mode = HubLedMode::Rgb;
-        Self {
-            rgb: [0; 3],
-            _mode: mode,
-            characteristic,
-            peripheral,
-            port_id,
-        }
-    }
-}
-
 /// Struct representing a motor
 #[derive(Debug, Clone)]
 pub struct Motor {
-    peripheral: Peripheral,
-    characteristic: Characteristic,
+    transport: Arc<dyn Transport>,
     port: Port,
     port_id: u8,
+    /// The hub's current sensor, if it has one
+    current_port_id: Option<u8>,
+    /// Speed the last motion command set the motor going at, shared
+    /// with the stall detector
+    commanded_speed: Arc<AtomicI8>,
+    /// The last motion command stops by itself, e.g. on reaching a
+    /// position, rather than running until told otherwise
+    until_done: Arc<AtomicBool>,
+    /// Stall detectors running on the motor, which need the hub's
+    /// command feedback
+    stall_monitors: Arc<AtomicUsize>,
+    /// Scaling of the hub's current sensor, fetched on first use
+    current_scale: Option<Scale>,
 }
 
 #[async_trait]
@@ -133,46 +536,360 @@ impl Device for Motor {
         self.port
     }
 
-    fn peripheral(&self) -> &Peripheral {
-        &self.peripheral
+    fn port_id(&self) -> u8 {
+        self.port_id
     }
 
-    fn characteristic(&self) -> &Characteristic {
-        &self.characteristic
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
     }
 
     async fn start_speed(&mut self, speed: i8, max_power: Power) -> Result<()> {
-        use crate::notifications::*;
-
         let subcommand = PortOutputSubcommand::StartSpeed {
             speed,
             max_power,
             use_acc_profile: true,
             use_dec_profile: true,
         };
-        let msg =
-            NotificationMessage::PortOutputCommand(PortOutputCommandFormat {
-                port_id: self.port_id,
-                startup_info: StartupInfo::ExecuteImmediately,
-                completion_info: CompletionInfo::NoAction,
-                subcommand,
-            });
-        self.send(msg).await
+        self.send_output(subcommand).await
     }
 }
 
 impl Motor {
+    /// Power applied, in percent
+    pub const MODE_POWER: u8 = 0;
+    /// Speed, in percent of maximum
+    pub const MODE_SPEED: u8 = 1;
+    /// Relative encoder position, in degrees (i32)
+    pub const MODE_POSITION: u8 = 2;
+    /// Absolute position, in degrees (i16)
+    pub const MODE_ABSOLUTE_POSITION: u8 = 3;
+
     pub(crate) fn new(
-        peripheral: Peripheral,
-        characteristic: Characteristic,
+        transport: Arc<dyn Transport>,
         port: Port,
         port_id: u8,
+        current_port_id: Option<u8>,
     ) -> Self {
         Self {
-            peripheral,
-            characteristic,
+            transport,
             port,
             port_id,
+            current_port_id,
+            commanded_speed: Default::default(),
+            until_done: Default::default(),
+            stall_monitors: Default::default(),
+            current_scale: None,
+        }
+    }
+
+    /// Drive a motor at a fixed power, without regulating its speed
+    pub async fn start_power(&mut self, power: Power) -> Result<()> {
+        let subcommand = PortOutputSubcommand::WriteDirectModeData(
+            WriteDirectModeDataPayload::StartPower(power),
+        );
+        self.send_output(subcommand).await
+    }
+
+    /// Drive both motors of a virtual port at individual powers
+    pub async fn start_power2(
+        &mut self,
+        power1: Power,
+        power2: Power,
+    ) -> Result<()> {
+        self.require_virtual()?;
+        self.send_output(PortOutputSubcommand::StartPower2 { power1, power2 })
+            .await
+    }
+
+    /// Move the motor to a position relative to the encoder zero
+    pub async fn goto_absolute_position(
+        &mut self,
+        abs_pos: i32,
+        speed: i8,
+        max_power: Power,
+        end_state: EndState,
+    ) -> Result<()> {
+        let subcommand = PortOutputSubcommand::GotoAbsolutePosition {
+            abs_pos,
+            speed,
+            max_power: max_power.to_u8() as i8,
+            end_state,
+            use_acc_profile: true,
+            use_dec_profile: true,
+        };
+        self.send_output(subcommand).await
+    }
+
+    /// Redefine the motor's current encoder position
+    pub async fn preset_encoder(&mut self, position: i32) -> Result<()> {
+        let subcommand = PortOutputSubcommand::WriteDirectModeData(
+            WriteDirectModeDataPayload::PresetEncoder(position),
+        );
+        self.send_output(subcommand).await
+    }
+
+    /// Start both motors of a virtual port at individual speeds
+    pub async fn start_speed2(
+        &mut self,
+        speed1: i8,
+        speed2: i8,
+        max_power: Power,
+    ) -> Result<()> {
+        self.require_virtual()?;
+        let subcommand = PortOutputSubcommand::StartSpeed2 {
+            speed1,
+            speed2,
+            max_power: max_power.to_u8() as i8,
+            use_acc_profile: true,
+            use_dec_profile: true,
+        };
+        self.send_output(subcommand).await
+    }
+
+    /// Run both motors of a virtual port until the pair has turned by
+    /// `degrees`
+    pub async fn start_speed_for_degrees2(
+        &mut self,
+        degrees: i32,
+        speed_l: i8,
+        speed_r: i8,
+        max_power: Power,
+        end_state: EndState,
+    ) -> Result<()> {
+        self.require_virtual()?;
+        let subcommand = PortOutputSubcommand::StartSpeedForDegrees2 {
+            degrees,
+            speed_l,
+            speed_r,
+            max_power: max_power.to_u8() as i8,
+            end_state,
+            use_acc_profile: true,
+            use_dec_profile: true,
+        };
+        self.send_output(subcommand).await
+    }
+
+    /// Move both motors of a virtual port to absolute encoder positions
+    pub async fn goto_absolute_position2(
+        &mut self,
+        abs_pos1: i32,
+        abs_pos2: i32,
+        speed: i8,
+        max_power: Power,
+        end_state: EndState,
+    ) -> Result<()> {
+        self.require_virtual()?;
+        let subcommand = PortOutputSubcommand::GotoAbsolutePosition2 {
+            abs_pos1,
+            abs_pos2,
+            speed,
+            max_power: max_power.to_u8() as i8,
+            end_state,
+            use_acc_profile: true,
+            use_dec_profile: true,
+        };
+        self.send_output(subcommand).await
+    }
+
+    /// Set the encoder positions of both motors of a virtual port
+    pub async fn preset_encoder2(
+        &mut self,
+        left_position: i32,
+        right_position: i32,
+    ) -> Result<()> {
+        self.require_virtual()?;
+        let subcommand = PortOutputSubcommand::PresetEncoder2 {
+            left_position,
+            right_position,
+        };
+        self.send_output(subcommand).await
+    }
+
+    /// Watch the motor for stalls and react as configured. This uses the
+    /// speed mode of the port, so replaces any other subscription on it.
+    pub async fn detect_stalls(
+        &mut self,
+        config: StallConfig,
+    ) -> Result<StallMonitor> {
+        let speed = self.subscribe_speed(1).await?;
+        let current = match (config.current_limit, self.current_port_id) {
+            (Some(_), Some(port_id)) => {
+                let mode = CurrentSensor::MODE_CURRENT;
+                let scale = match self.current_scale {
+                    Some(scale) => scale,
+                    None => *self.current_scale.insert(
+                        Scale::query_port(
+                            self.transport.as_ref(),
+                            port_id,
+                            mode,
+                        )
+                        .await?,
+                    ),
+                };
+                let stream =
+                    subscribe_port(self.transport.as_ref(), port_id, mode, 1)
+                        .await?;
+                Some(decode(stream, move |data| {
+                    power::decode_scaled(data, &scale)
+                }))
+            }
+            (Some(_), None) => {
+                return Err(Error::NotImplementedError(
+                    "Hub has no current sensor".to_string(),
+                ))
+            }
+            (None, _) => None,
+        };
+        let feedback = self.transport.notifications().await?;
+        Ok(stall::spawn(self.clone(), speed, current, feedback, config))
+    }
+
+    /// Stream motor speed in percent of maximum
+    pub async fn subscribe_speed(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<i8>> {
+        let stream = self.subscribe(Self::MODE_SPEED, delta).await?;
+        Ok(decode(stream, |data| {
+            Some(i8::from_le_bytes([*data.first()?]))
+        }))
+    }
+
+    /// Stream the encoder position in degrees relative to the position at
+    /// power-up or the last preset
+    pub async fn subscribe_position(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<i32>> {
+        let stream = self.subscribe(Self::MODE_POSITION, delta).await?;
+        Ok(decode(stream, |data| {
+            Some(i32::from_le_bytes(data.get(..4)?.try_into().ok()?))
+        }))
+    }
+
+    /// Stream the absolute position in degrees (-180..=179). Only
+    /// available on motors with an absolute encoder.
+    pub async fn subscribe_absolute_position(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<i16>> {
+        let stream =
+            self.subscribe(Self::MODE_ABSOLUTE_POSITION, delta).await?;
+        Ok(decode(stream, |data| {
+            Some(i16::from_le_bytes(data.get(..2)?.try_into().ok()?))
+        }))
+    }
+
+    async fn send_output(
+        &mut self,
+        subcommand: PortOutputSubcommand,
+    ) -> Result<()> {
+        use crate::notifications::*;
+
+        let motion = motion(&subcommand);
+        // Feedback tells a stall detector when a command that stops by
+        // itself has finished. Nothing else needs it.
+        let completion_info = if self.stall_monitors.load(Ordering::Relaxed) > 0
+        {
+            CompletionInfo::CommandFeedback
+        } else {
+            CompletionInfo::NoAction
+        };
+        let msg =
+            NotificationMessage::PortOutputCommand(PortOutputCommandFormat {
+                port_id: self.port_id,
+                startup_info: StartupInfo::ExecuteImmediately,
+                completion_info,
+                subcommand,
+            });
+        self.send(msg).await?;
+        if let Some((speed, until_done)) = motion {
+            self.commanded_speed.store(speed, Ordering::Relaxed);
+            self.until_done.store(until_done, Ordering::Relaxed);
         }
+        Ok(())
+    }
+
+    /// The paired commands are only accepted on virtual ports
+    fn require_virtual(&self) -> Result<()> {
+        match self.port {
+            Port::Virtual(_) => Ok(()),
+            port => Err(Error::HubError(format!(
+                "Port `{port:?}` is not a virtual port"
+            ))),
+        }
+    }
+}
+
+/// The speed a motion command sets a motor going at, and whether it
+/// stops by itself. Only whether the motor should be turning matters to
+/// the stall detector, so a pair of motors counts at the faster speed.
+fn motion(subcommand: &PortOutputSubcommand) -> Option<(i8, bool)> {
+    use PortOutputSubcommand::*;
+
+    let faster = |a: i8, b: i8| {
+        if a.unsigned_abs() >= b.unsigned_abs() {
+            a
+        } else {
+            b
+        }
+    };
+    let percent = |power: Power| match power {
+        Power::Cw(power) => power as i8,
+        Power::Ccw(power) => -(power as i8),
+        Power::Float | Power::Brake => 0,
+    };
+    match *subcommand {
+        StartSpeed { speed, .. } => Some((speed, false)),
+        StartSpeed2 { speed1, speed2, .. } => {
+            Some((faster(speed1, speed2), false))
+        }
+        StartPower2 { power1, power2 } => {
+            Some((faster(percent(power1), percent(power2)), false))
+        }
+        WriteDirectModeData(WriteDirectModeDataPayload::StartPower(power)) => {
+            Some((percent(power), false))
+        }
+        StartSpeedForTime { speed, .. }
+        | StartSpeedForDegrees { speed, .. }
+        | GotoAbsolutePosition { speed, .. }
+        | GotoAbsolutePosition2 { speed, .. } => Some((speed, true)),
+        StartSpeedForTime2 {
+            speed_l, speed_r, ..
+        }
+        | StartSpeedForDegrees2 {
+            speed_l, speed_r, ..
+        } => Some((faster(speed_l, speed_r), true)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::broadcast_stream;
+    use tokio::sync::broadcast;
+
+    #[tokio::test(start_paused = true)]
+    async fn readings_are_stamped_on_arrival() {
+        let (sender, receiver) = broadcast::channel(8);
+        let mut readings =
+            stamped(broadcast_stream(receiver), |msg| msg.first().copied());
+        let start = tokio::time::Instant::now().into_std();
+
+        // Readings left waiting keep the time they arrived
+        sender.send(vec![1]).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        sender.send(vec![2]).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let first = readings.next().await.unwrap();
+        assert_eq!((first.value, first.timestamp), (1, start));
+        let second = readings.next().await.unwrap();
+        assert_eq!(
+            (second.value, second.timestamp),
+            (2, start + Duration::from_millis(200))
+        );
     }
 }
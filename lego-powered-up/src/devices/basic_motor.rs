@@ -0,0 +1,123 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Motors without an encoder, e.g. train motors, which can only be driven
+//! at a given power rather than a regulated speed
+
+use super::Device;
+use crate::error::{Error, Result};
+use crate::hubs::Port;
+use crate::notifications::{
+    CompletionInfo, NotificationMessage, PortOutputCommandFormat,
+    PortOutputSubcommand, Power, StartupInfo, WriteDirectModeDataPayload,
+};
+use crate::transport::Transport;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Struct representing a motor without an encoder
+#[derive(Debug, Clone)]
+pub struct BasicMotor {
+    transport: Arc<dyn Transport>,
+    port: Port,
+    port_id: u8,
+}
+
+#[async_trait]
+impl Device for BasicMotor {
+    fn port(&self) -> Port {
+        self.port
+    }
+
+    fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+
+    /// Without an encoder the speed can't be regulated, so this sets the
+    /// power to `speed` percent instead. When stopping, `max_power` picks
+    /// whether to brake or float.
+    async fn start_speed(&mut self, speed: i8, max_power: Power) -> Result<()> {
+        self.start_power(speed_to_power(speed, max_power)).await
+    }
+}
+
+impl BasicMotor {
+    pub(crate) fn new(
+        transport: Arc<dyn Transport>,
+        port: Port,
+        port_id: u8,
+    ) -> Self {
+        Self {
+            transport,
+            port,
+            port_id,
+        }
+    }
+
+    async fn send_output(
+        &mut self,
+        subcommand: PortOutputSubcommand,
+    ) -> Result<()> {
+        let msg =
+            NotificationMessage::PortOutputCommand(PortOutputCommandFormat {
+                port_id: self.port_id,
+                startup_info: StartupInfo::ExecuteImmediately,
+                completion_info: CompletionInfo::NoAction,
+                subcommand,
+            });
+        self.send(msg).await
+    }
+
+    /// Drive a motor at a fixed power, without regulating its speed
+    pub async fn start_power(&mut self, power: Power) -> Result<()> {
+        self.send_output(PortOutputSubcommand::WriteDirectModeData(
+            WriteDirectModeDataPayload::StartPower(power),
+        ))
+        .await
+    }
+
+    /// Drive both motors of a virtual port at individual powers
+    pub async fn start_power2(
+        &mut self,
+        power1: Power,
+        power2: Power,
+    ) -> Result<()> {
+        if !matches!(self.port, Port::Virtual(_)) {
+            return Err(Error::HubError(format!(
+                "Port `{:?}` is not a virtual port",
+                self.port
+            )));
+        }
+        self.send_output(PortOutputSubcommand::StartPower2 { power1, power2 })
+            .await
+    }
+}
+
+fn speed_to_power(speed: i8, max_power: Power) -> Power {
+    match speed.clamp(-100, 100) {
+        0 => match max_power {
+            Power::Brake => Power::Brake,
+            _ => Power::Float,
+        },
+        s if s > 0 => Power::Cw(s as u8),
+        s => Power::Ccw(s.unsigned_abs()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn speed_as_power() {
+        assert_eq!(speed_to_power(50, Power::Cw(100)), Power::Cw(50));
+        assert_eq!(speed_to_power(-128, Power::Cw(100)), Power::Ccw(100));
+        assert_eq!(speed_to_power(0, Power::Brake), Power::Brake);
+        assert_eq!(speed_to_power(0, Power::Cw(100)), Power::Float);
+    }
+}
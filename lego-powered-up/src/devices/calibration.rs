@@ -0,0 +1,250 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! End stop calibration for motors driving mechanisms with limited travel,
+//! e.g. steering racks
+
+use super::{next_reading, Device, Motor, Reading, ReadingStream};
+use crate::error::{Error, OptionContext, Result};
+use crate::notifications::{EndState, Power};
+use futures::StreamExt;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// Tuning for the calibration sweep
+#[derive(Copy, Clone, Debug)]
+pub struct CalibrationOptions {
+    /// Speed at which to search for the end stops, in percent
+    pub speed: i8,
+    /// Power limit while searching, in percent. Keep this low so that
+    /// running into an end stop doesn't strip any gears.
+    pub max_power: u8,
+    /// How long the motor must stay put to count as stalled
+    pub stall_time: Duration,
+    /// Movements up to this many degrees are treated as jitter
+    pub tolerance: i32,
+    /// Give up if an end stop hasn't been found within this time
+    pub timeout: Duration,
+}
+
+impl Default for CalibrationOptions {
+    fn default() -> Self {
+        Self {
+            speed: 20,
+            max_power: 30,
+            stall_time: Duration::from_millis(300),
+            tolerance: 2,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Result of a calibration run. Positions are encoder degrees with the
+/// centre at zero.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Calibration {
+    /// End stop in the negative direction
+    pub min: i32,
+    /// End stop in the positive direction
+    pub max: i32,
+    /// Absolute position of the centre, which allows `apply` to find the
+    /// centre again after the hub has been switched off
+    pub centre_absolute: i16,
+}
+
+impl Calibration {
+    /// Total travel between the end stops, in degrees
+    pub fn range(&self) -> u32 {
+        self.max.abs_diff(self.min)
+    }
+
+    /// Limit a target position to the calibrated travel
+    pub fn clamp(&self, position: i32) -> i32 {
+        position.clamp(self.min, self.max)
+    }
+
+    /// Map a fraction in -1.0..=1.0 onto the calibrated travel, so that
+    /// 0.0 is the centre and ±1.0 are the end stops
+    pub fn position(&self, fraction: f32) -> i32 {
+        let fraction = fraction.clamp(-1.0, 1.0);
+        let end = if fraction < 0.0 { -self.min } else { self.max };
+        (fraction * end as f32).round() as i32
+    }
+
+    /// Preset the encoder of a previously calibrated motor so that the
+    /// centre is at zero again, without sweeping to the end stops. This
+    /// relies on the absolute encoder, so is only valid when the whole
+    /// travel is less than one revolution of the motor.
+    pub async fn apply(&self, motor: &mut Motor) -> Result<()> {
+        let mut absolute = motor.subscribe_absolute_position(1).await?;
+        let current = next_reading(&mut absolute).await?.value;
+        let offset = wrap_degrees(current as i32 - self.centre_absolute as i32);
+        motor.preset_encoder(offset).await
+    }
+}
+
+/// Find the end stops of a motor by running it into each of them, then
+/// move it to the centre and preset the encoder to zero there
+pub async fn calibrate(
+    motor: &mut Motor,
+    options: CalibrationOptions,
+) -> Result<Calibration> {
+    let a = find_end_stop(motor, options.speed, &options).await?;
+    let b = find_end_stop(motor, -options.speed, &options).await?;
+    let (min, max) = (a.min(b), a.max(b));
+    if max - min <= options.tolerance {
+        return Err(Error::HubError(
+            "Motor did not move between end stops".to_string(),
+        ));
+    }
+    debug!("End stops at {} and {}", min, max);
+
+    let centre = min + (max - min) / 2;
+    let mut positions = motor.subscribe_position(1).await?;
+    motor
+        .goto_absolute_position(
+            centre,
+            options.speed.saturating_abs(),
+            Power::Cw(options.max_power),
+            EndState::Hold,
+        )
+        .await?;
+    let centred = async {
+        while let Some(reading) = positions.next().await {
+            if (reading.value - centre).abs() <= options.tolerance {
+                return Ok(());
+            }
+        }
+        Err(Error::HubError("Position stream ended".to_string()))
+    };
+    timeout(options.timeout, centred).await.map_err(|_| {
+        Error::TimeoutError("Motor did not reach the centre".to_string())
+    })??;
+    motor.preset_encoder(0).await?;
+
+    let mut absolute = motor.subscribe_absolute_position(1).await?;
+    let centre_absolute = next_reading(&mut absolute).await?.value;
+
+    Ok(Calibration {
+        min: min - centre,
+        max: max - centre,
+        centre_absolute,
+    })
+}
+
+/// Run the motor until it stalls, returning the position it stopped at.
+/// The motor is left floating whatever the outcome. Each sweep has a
+/// subscription of its own, so that readings left over from the last
+/// one can't pass for a stall.
+async fn find_end_stop(
+    motor: &mut Motor,
+    speed: i8,
+    options: &CalibrationOptions,
+) -> Result<i32> {
+    let mut positions = motor.subscribe_position(1).await?;
+    motor
+        .start_speed(speed, Power::Cw(options.max_power))
+        .await?;
+    let stall =
+        timeout(options.timeout, wait_for_stall(&mut positions, options)).await;
+    motor.start_power(Power::Float).await?;
+    stall.map_err(|_| Error::TimeoutError("No end stop found".to_string()))?
+}
+
+async fn wait_for_stall(
+    positions: &mut ReadingStream<i32>,
+    options: &CalibrationOptions,
+) -> Result<i32> {
+    let mut last_move: Option<Reading<i32>> = None;
+    loop {
+        match timeout(options.stall_time, positions.next()).await {
+            Ok(Some(reading)) => match last_move {
+                Some(last)
+                    if (reading.value - last.value).abs()
+                        <= options.tolerance =>
+                {
+                    if reading.timestamp - last.timestamp >= options.stall_time
+                    {
+                        return Ok(reading.value);
+                    }
+                }
+                _ => last_move = Some(reading),
+            },
+            Ok(None) => {
+                return Err(Error::HubError(
+                    "Position stream ended".to_string(),
+                ))
+            }
+            // Nothing reported for a whole stall period, so the motor
+            // isn't moving
+            Err(_) => {
+                return last_move
+                    .map(|reading| reading.value)
+                    .context("Motor did not report its position")
+            }
+        }
+    }
+}
+
+/// Wrap an angle in degrees into -180..=179
+fn wrap_degrees(degrees: i32) -> i32 {
+    (degrees + 180).rem_euclid(360) - 180
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::consts::DeviceType;
+    use crate::hubs::{Hub, Port, TechnicHub};
+    use crate::simulator::SimulatedHub;
+
+    #[test]
+    fn calibration_positions() {
+        let cal = Calibration {
+            min: -90,
+            max: 110,
+            centre_absolute: 0,
+        };
+        assert_eq!(cal.range(), 200);
+        assert_eq!(cal.clamp(-120), -90);
+        assert_eq!(cal.clamp(50), 50);
+        assert_eq!(cal.position(0.0), 0);
+        assert_eq!(cal.position(-1.0), -90);
+        assert_eq!(cal.position(0.5), 55);
+        assert_eq!(cal.position(2.0), 110);
+    }
+
+    #[test]
+    fn wrap() {
+        assert_eq!(wrap_degrees(0), 0);
+        assert_eq!(wrap_degrees(180), -180);
+        assert_eq!(wrap_degrees(-181), 179);
+        assert_eq!(wrap_degrees(350), -10);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn calibrate_against_end_stops() {
+        let transport = SimulatedHub::new("Sim")
+            .attach(0, DeviceType::TechnicLargeAngularMotor)
+            .end_stops(0, -100, 140)
+            .connect();
+        let hub = TechnicHub::init(transport.clone(), transport.properties())
+            .await
+            .unwrap();
+        let mut motor = hub
+            .port(Port::A)
+            .await
+            .unwrap()
+            .downcast::<Motor>()
+            .unwrap();
+        let options = CalibrationOptions {
+            speed: 50,
+            stall_time: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let cal = calibrate(motor.as_mut(), options).await.unwrap();
+        assert_eq!((cal.min, cal.max), (-120, 120));
+        assert_eq!(cal.centre_absolute, 20);
+    }
+}
@@ -0,0 +1,235 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The BOOST Colour & Distance sensor
+
+use super::{decode, next_reading, write_mode, Device, ReadingStream};
+use crate::consts::Color;
+use crate::error::Result;
+use crate::hubs::Port;
+use crate::transport::Transport;
+use async_trait::async_trait;
+use num_traits::FromPrimitive;
+use std::sync::Arc;
+
+/// Output of the combined colour and distance mode
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ColorDistance {
+    pub color: Color,
+    /// Coarse distance to an object, 0 (touching) to 10 (nothing seen)
+    pub proximity: u8,
+    /// Estimated distance to an object, in mm
+    pub distance: u32,
+}
+
+/// Struct representing a Colour & Distance sensor
+#[derive(Debug, Clone)]
+pub struct ColorDistanceSensor {
+    transport: Arc<dyn Transport>,
+    port: Port,
+    port_id: u8,
+}
+
+#[async_trait]
+impl Device for ColorDistanceSensor {
+    fn port(&self) -> Port {
+        self.port
+    }
+
+    fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+
+    /// Light the sensor's LED. Only black (off), blue, green, red and
+    /// white are shown.
+    async fn set_color(&mut self, color: Color) -> Result<()> {
+        write_mode(self, Self::MODE_LED, vec![color as u8]).await
+    }
+}
+
+impl ColorDistanceSensor {
+    /// Detected colour (`Color`, or 255 for none)
+    pub const MODE_COLOR: u8 = 0;
+    /// Proximity, 0..=10
+    pub const MODE_PROXIMITY: u8 = 1;
+    /// Count of objects passing the sensor (u32)
+    pub const MODE_COUNT: u8 = 2;
+    /// Reflected light, in percent
+    pub const MODE_REFLECTED: u8 = 3;
+    /// Ambient light, in percent
+    pub const MODE_AMBIENT: u8 = 4;
+    /// LED colour (output)
+    pub const MODE_LED: u8 = 5;
+    /// Raw red, green and blue values (3 x u16)
+    pub const MODE_RGB: u8 = 6;
+    /// Raw Power Functions IR code (output, u16)
+    pub const MODE_IR_TX: u8 = 7;
+    /// Colour, proximity and fine distance combined
+    pub const MODE_COLOR_DISTANCE: u8 = 8;
+
+    pub(crate) fn new(
+        transport: Arc<dyn Transport>,
+        port: Port,
+        port_id: u8,
+    ) -> Self {
+        Self {
+            transport,
+            port,
+            port_id,
+        }
+    }
+
+    /// Stream the colour the sensor sees
+    pub async fn subscribe_color(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<Color>> {
+        let stream = self.subscribe(Self::MODE_COLOR, delta).await?;
+        Ok(decode(stream, |data| Some(decode_color(*data.first()?))))
+    }
+
+    pub async fn read_color(&mut self) -> Result<Color> {
+        let mut stream = self.subscribe_color(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+
+    /// Stream the coarse distance to an object, 0 (close) to 10 (far)
+    pub async fn subscribe_proximity(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<u8>> {
+        let stream = self.subscribe(Self::MODE_PROXIMITY, delta).await?;
+        Ok(decode(stream, |data| data.first().copied()))
+    }
+
+    pub async fn read_proximity(&mut self) -> Result<u8> {
+        let mut stream = self.subscribe_proximity(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+
+    /// Stream the reflected light intensity, in percent
+    pub async fn subscribe_reflected_light(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<u8>> {
+        let stream = self.subscribe(Self::MODE_REFLECTED, delta).await?;
+        Ok(decode(stream, |data| data.first().copied()))
+    }
+
+    pub async fn read_reflected_light(&mut self) -> Result<u8> {
+        let mut stream = self.subscribe_reflected_light(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+
+    /// Stream the ambient light intensity, in percent
+    pub async fn subscribe_ambient_light(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<u8>> {
+        let stream = self.subscribe(Self::MODE_AMBIENT, delta).await?;
+        Ok(decode(stream, |data| data.first().copied()))
+    }
+
+    pub async fn read_ambient_light(&mut self) -> Result<u8> {
+        let mut stream = self.subscribe_ambient_light(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+
+    /// Stream raw red, green and blue intensities
+    pub async fn subscribe_rgb(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<[u16; 3]>> {
+        let stream = self.subscribe(Self::MODE_RGB, delta).await?;
+        Ok(decode(stream, decode_rgb))
+    }
+
+    pub async fn read_rgb(&mut self) -> Result<[u16; 3]> {
+        let mut stream = self.subscribe_rgb(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+
+    /// Stream colour and distance together
+    pub async fn subscribe_color_distance(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<ColorDistance>> {
+        let stream = self.subscribe(Self::MODE_COLOR_DISTANCE, delta).await?;
+        Ok(decode(stream, decode_color_distance))
+    }
+
+    pub async fn read_color_distance(&mut self) -> Result<ColorDistance> {
+        let mut stream = self.subscribe_color_distance(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+
+    /// Transmit a raw Power Functions infrared code
+    pub async fn send_ir(&mut self, code: u16) -> Result<()> {
+        write_mode(self, Self::MODE_IR_TX, code.to_le_bytes().to_vec()).await
+    }
+}
+
+pub(super) fn decode_color(value: u8) -> Color {
+    Color::from_u8(value).unwrap_or(Color::None)
+}
+
+fn decode_rgb(data: &[u8]) -> Option<[u16; 3]> {
+    let channel = |i: usize| {
+        Some(u16::from_le_bytes(
+            data.get(i * 2..i * 2 + 2)?.try_into().ok()?,
+        ))
+    };
+    Some([channel(0)?, channel(1)?, channel(2)?])
+}
+
+fn decode_color_distance(data: &[u8]) -> Option<ColorDistance> {
+    let color = decode_color(*data.first()?);
+    let proximity = *data.get(1)?;
+    // The fractional part of the distance is reported as a reciprocal
+    let partial = *data.get(3)?;
+    let mut inches = proximity as f32;
+    if partial > 0 {
+        inches += 1.0 / partial as f32;
+    }
+    let distance = ((inches * 25.4).floor() - 20.0).max(0.0) as u32;
+    Some(ColorDistance {
+        color,
+        proximity,
+        distance,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_values() {
+        assert_eq!(decode_color(9), Color::Red);
+        assert_eq!(decode_color(255), Color::None);
+        assert_eq!(decode_color(42), Color::None);
+        assert_eq!(decode_rgb(&[1, 0, 2, 1, 0xff, 3]), Some([1, 258, 1023]));
+        assert_eq!(decode_rgb(&[1, 0, 2]), None);
+        assert_eq!(
+            decode_color_distance(&[3, 2, 0, 2]),
+            Some(ColorDistance {
+                color: Color::Blue,
+                proximity: 2,
+                distance: 43,
+            })
+        );
+        assert_eq!(
+            decode_color_distance(&[255, 0, 0, 0]),
+            Some(ColorDistance {
+                color: Color::None,
+                proximity: 0,
+                distance: 0,
+            })
+        );
+    }
+}
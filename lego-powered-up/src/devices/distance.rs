@@ -0,0 +1,143 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The Technic (SPIKE) ultrasonic distance sensor
+
+use super::{decode, next_reading, write_mode, Device, ReadingStream};
+use crate::error::Result;
+use crate::hubs::Port;
+use crate::transport::Transport;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Brightness of each of the four lights around the sensor's eyes, in
+/// percent
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct EyeLights {
+    pub top_left: u8,
+    pub top_right: u8,
+    pub bottom_left: u8,
+    pub bottom_right: u8,
+}
+
+impl EyeLights {
+    /// All four lights at the same brightness
+    pub fn all(brightness: u8) -> Self {
+        Self {
+            top_left: brightness,
+            top_right: brightness,
+            bottom_left: brightness,
+            bottom_right: brightness,
+        }
+    }
+
+    fn serialise(&self) -> Vec<u8> {
+        [
+            self.top_left,
+            self.bottom_left,
+            self.top_right,
+            self.bottom_right,
+        ]
+        .iter()
+        .map(|brightness| (*brightness).min(100))
+        .collect()
+    }
+}
+
+/// Struct representing a Technic distance sensor
+#[derive(Debug, Clone)]
+pub struct DistanceSensor {
+    transport: Arc<dyn Transport>,
+    port: Port,
+    port_id: u8,
+}
+
+#[async_trait]
+impl Device for DistanceSensor {
+    fn port(&self) -> Port {
+        self.port
+    }
+
+    fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+}
+
+impl DistanceSensor {
+    /// Distance in mm, up to 2 m (i16, -1 if nothing seen)
+    pub const MODE_DISTANCE: u8 = 0;
+    /// Distance in mm, up to 32 cm but updated faster
+    pub const MODE_FAST_DISTANCE: u8 = 1;
+    /// Eye light brightness (output, 4 x percent)
+    pub const MODE_LIGHT: u8 = 5;
+
+    pub(crate) fn new(
+        transport: Arc<dyn Transport>,
+        port: Port,
+        port_id: u8,
+    ) -> Self {
+        Self {
+            transport,
+            port,
+            port_id,
+        }
+    }
+
+    /// Stream the distance to an object in mm, or `None` if nothing is in
+    /// range
+    pub async fn subscribe_distance(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<Option<u16>>> {
+        let stream = self.subscribe(Self::MODE_DISTANCE, delta).await?;
+        Ok(decode(stream, decode_distance))
+    }
+
+    pub async fn read_distance(&mut self) -> Result<Option<u16>> {
+        let mut stream = self.subscribe_distance(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+
+    /// Like `subscribe_distance`, but updating faster over a shorter range
+    pub async fn subscribe_fast_distance(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<Option<u16>>> {
+        let stream = self.subscribe(Self::MODE_FAST_DISTANCE, delta).await?;
+        Ok(decode(stream, decode_distance))
+    }
+
+    /// Set the brightness of the lights around the sensor's eyes
+    pub async fn set_eye_lights(&mut self, lights: EyeLights) -> Result<()> {
+        write_mode(self, Self::MODE_LIGHT, lights.serialise()).await
+    }
+}
+
+fn decode_distance(data: &[u8]) -> Option<Option<u16>> {
+    let distance = i16::from_le_bytes(data.get(..2)?.try_into().ok()?);
+    Some(u16::try_from(distance).ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_values() {
+        assert_eq!(decode_distance(&[0xd2, 0x04]), Some(Some(1234)));
+        assert_eq!(decode_distance(&[0xff, 0xff]), Some(None));
+        assert_eq!(decode_distance(&[0x01]), None);
+        let lights = EyeLights {
+            top_left: 10,
+            top_right: 20,
+            bottom_left: 30,
+            bottom_right: 120,
+        };
+        assert_eq!(lights.serialise(), vec![10, 30, 20, 100]);
+    }
+}
@@ -0,0 +1,108 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The Technic (SPIKE) force sensor
+
+use super::{decode, next_reading, Device, ReadingStream};
+use crate::error::Result;
+use crate::hubs::Port;
+use crate::transport::Transport;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Struct representing a Technic force sensor
+#[derive(Debug, Clone)]
+pub struct ForceSensor {
+    transport: Arc<dyn Transport>,
+    port: Port,
+    port_id: u8,
+}
+
+#[async_trait]
+impl Device for ForceSensor {
+    fn port(&self) -> Port {
+        self.port
+    }
+
+    fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+}
+
+impl ForceSensor {
+    /// Force, in tenths of a newton (0..=100)
+    pub const MODE_FORCE: u8 = 0;
+    /// Whether the button is touched at all (0 or 1)
+    pub const MODE_TOUCHED: u8 = 1;
+    /// Taps detected (0..=3)
+    pub const MODE_TAPPED: u8 = 2;
+
+    pub(crate) fn new(
+        transport: Arc<dyn Transport>,
+        port: Port,
+        port_id: u8,
+    ) -> Self {
+        Self {
+            transport,
+            port,
+            port_id,
+        }
+    }
+
+    /// Stream the force applied to the sensor, in newtons
+    pub async fn subscribe_force(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<f32>> {
+        let stream = self.subscribe(Self::MODE_FORCE, delta).await?;
+        Ok(decode(stream, decode_force))
+    }
+
+    pub async fn read_force(&mut self) -> Result<f32> {
+        let mut stream = self.subscribe_force(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+
+    /// Stream whether the sensor is pressed at all, as the sensor
+    /// itself judges it
+    pub async fn subscribe_touched(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<bool>> {
+        let stream = self.subscribe(Self::MODE_TOUCHED, delta).await?;
+        Ok(decode(stream, decode_touched))
+    }
+
+    pub async fn read_touched(&mut self) -> Result<bool> {
+        let mut stream = self.subscribe_touched(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+}
+
+fn decode_force(data: &[u8]) -> Option<f32> {
+    Some(*data.first()? as f32 / 10.0)
+}
+
+fn decode_touched(data: &[u8]) -> Option<bool> {
+    Some(*data.first()? != 0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_values() {
+        assert_eq!(decode_force(&[0]), Some(0.0));
+        assert_eq!(decode_force(&[45]), Some(4.5));
+        assert_eq!(decode_force(&[]), None);
+        assert_eq!(decode_touched(&[1]), Some(true));
+        assert_eq!(decode_touched(&[0]), Some(false));
+        assert_eq!(decode_touched(&[]), None);
+    }
+}
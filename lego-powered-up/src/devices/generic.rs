@@ -0,0 +1,303 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Introspection of the modes a port offers, and a device which can use
+//! them without knowing what is attached
+
+use super::{mode_information, port_information, Device};
+use crate::error::{Error, Result};
+use crate::hubs::Port;
+use crate::notifications::{
+    DatasetType, InformationType, MappingValue, ModeInformationType,
+    PortCapabilities, PortInformationType, PortModeInformationType,
+    ValueFormatType,
+};
+use crate::transport::Transport;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Everything the hub reports about a port and its modes
+#[derive(Clone, Debug, PartialEq)]
+pub struct PortDescription {
+    pub port_id: u8,
+    pub capabilities: PortCapabilities,
+    pub modes: Vec<ModeDescription>,
+    /// Sets of modes which may be combined, as bitmasks of mode numbers
+    pub combinations: Vec<u16>,
+}
+
+impl PortDescription {
+    /// Look a mode up by its name, e.g. `"POS"`
+    pub fn mode(&self, name: &str) -> Option<&ModeDescription> {
+        self.modes.iter().find(|mode| mode.name == name)
+    }
+}
+
+/// Everything the hub reports about one mode of a port
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModeDescription {
+    pub mode: u8,
+    pub name: String,
+    /// Unit of the SI range, e.g. `"DEG"`
+    pub symbol: String,
+    /// Values can be read from the mode
+    pub input: bool,
+    /// Values can be written to the mode
+    pub output: bool,
+    pub raw_range: (f32, f32),
+    pub pct_range: (f32, f32),
+    pub si_range: (f32, f32),
+    /// Input and output mapping flags
+    pub mapping: Option<(MappingValue, MappingValue)>,
+    pub value_format: Option<ValueFormatType>,
+}
+
+impl ModeDescription {
+    /// Split a raw value into its datasets, following the value format
+    pub fn decode(&self, data: &[u8]) -> Vec<f32> {
+        let Some(format) = self.value_format else {
+            return Vec::new();
+        };
+        let size = match format.dataset_type() {
+            DatasetType::Bits8 => 1,
+            DatasetType::Bits16 => 2,
+            DatasetType::Bits32 | DatasetType::Float => 4,
+        };
+        data.chunks_exact(size)
+            .take(format.number_of_datasets() as usize)
+            .map(|chunk| match format.dataset_type() {
+                DatasetType::Bits8 => chunk[0] as i8 as f32,
+                DatasetType::Bits16 => {
+                    i16::from_le_bytes([chunk[0], chunk[1]]) as f32
+                }
+                DatasetType::Bits32 => {
+                    i32::from_le_bytes(chunk.try_into().unwrap()) as f32
+                }
+                DatasetType::Float => {
+                    f32::from_le_bytes(chunk.try_into().unwrap())
+                }
+            })
+            .collect()
+    }
+
+    /// Convert a raw dataset value to SI units
+    pub fn to_si(&self, raw: f32) -> f32 {
+        let (raw_min, raw_max) = self.raw_range;
+        let (si_min, si_max) = self.si_range;
+        if raw_max == raw_min {
+            return raw;
+        }
+        si_min + (raw - raw_min) * (si_max - si_min) / (raw_max - raw_min)
+    }
+}
+
+/// Query the hub for everything it knows about a port
+pub(crate) async fn describe_port(
+    transport: &dyn Transport,
+    port_id: u8,
+) -> Result<PortDescription> {
+    let info =
+        port_information(transport, port_id, InformationType::ModeInfo).await?;
+    let PortInformationType::ModeInfo {
+        capabilities,
+        mode_count,
+        input_modes,
+        output_modes,
+    } = info
+    else {
+        return Err(Error::HubError("Unexpected port information".to_string()));
+    };
+
+    let combinations =
+        if capabilities.contains(PortCapabilities::LOGICAL_COMBINABLE) {
+            match port_information(
+                transport,
+                port_id,
+                InformationType::PossibleModeCombinations,
+            )
+            .await?
+            {
+                PortInformationType::PossibleModeCombinations(bytes) => bytes
+                    .chunks_exact(2)
+                    .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                    .take_while(|combination| *combination != 0)
+                    .collect(),
+                _ => Vec::new(),
+            }
+        } else {
+            Vec::new()
+        };
+
+    let mut modes = Vec::with_capacity(mode_count as usize);
+    for mode in 0..mode_count {
+        let info = |information_type| {
+            mode_information(transport, port_id, mode, information_type)
+        };
+        let mut description = ModeDescription {
+            mode,
+            name: String::new(),
+            symbol: String::new(),
+            input: has_mode(input_modes, mode),
+            output: has_mode(output_modes, mode),
+            raw_range: (0.0, 0.0),
+            pct_range: (0.0, 0.0),
+            si_range: (0.0, 0.0),
+            mapping: None,
+            value_format: None,
+        };
+        for information_type in [
+            ModeInformationType::Name,
+            ModeInformationType::Raw,
+            ModeInformationType::Pct,
+            ModeInformationType::Si,
+            ModeInformationType::Symbol,
+            ModeInformationType::Mapping,
+            ModeInformationType::ValueFormat,
+        ] {
+            // Not every device answers every query, so describe what we can
+            let value = match info(information_type).await {
+                Ok(value) => value,
+                Err(e) => {
+                    debug!(
+                        "No {:?} for mode {} of port {}: {}",
+                        information_type, mode, port_id, e
+                    );
+                    continue;
+                }
+            };
+            match value {
+                PortModeInformationType::Name(name) => {
+                    description.name = text(&name)
+                }
+                PortModeInformationType::Symbol(symbol) => {
+                    description.symbol = text(&symbol)
+                }
+                PortModeInformationType::RawRange { min, max } => {
+                    description.raw_range = (min, max)
+                }
+                PortModeInformationType::PctRange { min, max } => {
+                    description.pct_range = (min, max)
+                }
+                PortModeInformationType::SiRange { min, max } => {
+                    description.si_range = (min, max)
+                }
+                PortModeInformationType::Mapping { input, output } => {
+                    description.mapping = Some((input, output))
+                }
+                PortModeInformationType::ValueFormat(format) => {
+                    description.value_format = Some(format)
+                }
+                _ => {}
+            }
+        }
+        modes.push(description);
+    }
+
+    Ok(PortDescription {
+        port_id,
+        capabilities,
+        modes,
+        combinations,
+    })
+}
+
+/// Names and symbols are NUL padded ASCII
+fn text(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+/// Whether a bitmask of mode numbers includes `mode`. Only modes 0 to 15
+/// fit in the mask.
+fn has_mode(modes: u16, mode: u8) -> bool {
+    1u16.checked_shl(mode.into())
+        .is_some_and(|bit| modes & bit != 0)
+}
+
+/// Struct representing a device the library has no specific support for.
+/// It can still be used through `Device::describe` together with
+/// `Device::subscribe` and `Device::read_mode`.
+#[derive(Debug, Clone)]
+pub struct GenericDevice {
+    transport: Arc<dyn Transport>,
+    port: Port,
+    port_id: u8,
+    io_type_id: u16,
+}
+
+#[async_trait]
+impl Device for GenericDevice {
+    fn port(&self) -> Port {
+        self.port
+    }
+
+    fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+}
+
+impl GenericDevice {
+    pub(crate) fn new(
+        transport: Arc<dyn Transport>,
+        port: Port,
+        port_id: u8,
+        io_type_id: u16,
+    ) -> Self {
+        Self {
+            transport,
+            port,
+            port_id,
+            io_type_id,
+        }
+    }
+
+    /// Type id the hub reported for the device, which may be one this
+    /// library has no name for
+    pub fn io_type_id(&self) -> u16 {
+        self.io_type_id
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::notifications::PortModeInformationType;
+
+    #[test]
+    fn decode_mode_values() {
+        // Build the value format via the parser, as its fields are private
+        let format =
+            match PortModeInformationType::parse([0x80_u8, 3, 1, 4, 1].iter())
+                .unwrap()
+            {
+                PortModeInformationType::ValueFormat(format) => format,
+                _ => unreachable!(),
+            };
+        let mode = ModeDescription {
+            mode: 0,
+            name: "POS".to_string(),
+            symbol: "DEG".to_string(),
+            input: true,
+            output: false,
+            raw_range: (-1000.0, 1000.0),
+            pct_range: (-100.0, 100.0),
+            si_range: (-100.0, 100.0),
+            mapping: None,
+            value_format: Some(format),
+        };
+        assert_eq!(
+            mode.decode(&[0x10, 0x00, 0xf0, 0xff, 0x00, 0x01, 0x07]),
+            vec![16.0, -16.0, 256.0]
+        );
+        assert_eq!(mode.to_si(500.0), 50.0);
+        assert_eq!(text(b"POS\0\0\0"), "POS");
+        assert!(has_mode(0b1000_0000_0000_0001, 15));
+        assert!(!has_mode(0b1000_0000_0000_0001, 14));
+        assert!(!has_mode(u16::MAX, 16));
+    }
+}
@@ -0,0 +1,318 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The motion sensors built into the Technic hub
+
+use super::{decode, next_reading, Device, ReadingStream, Scale};
+use crate::error::Result;
+use crate::hubs::Port;
+use crate::transport::Transport;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Orientation of the hub, in degrees
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Tilt {
+    pub yaw: i16,
+    pub pitch: i16,
+    pub roll: i16,
+}
+
+/// Gestures recognised by the hub
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Gesture {
+    None,
+    Tap,
+    DoubleTap,
+    Shake,
+    FreeFall,
+    /// A gesture code this library doesn't know about
+    Other(u8),
+}
+
+impl From<u8> for Gesture {
+    fn from(code: u8) -> Self {
+        match code {
+            0 => Gesture::None,
+            1 => Gesture::Tap,
+            2 => Gesture::DoubleTap,
+            3 => Gesture::Shake,
+            4 => Gesture::FreeFall,
+            code => Gesture::Other(code),
+        }
+    }
+}
+
+/// Struct representing the hub's accelerometer
+#[derive(Debug, Clone)]
+pub struct Accelerometer {
+    transport: Arc<dyn Transport>,
+    port: Port,
+    port_id: u8,
+    /// Fetched from the hub on first use
+    scale: Option<Scale>,
+}
+
+#[async_trait]
+impl Device for Accelerometer {
+    fn port(&self) -> Port {
+        self.port
+    }
+
+    fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+}
+
+impl Accelerometer {
+    /// Acceleration along x, y and z (3 x i16, scaled to mG by the
+    /// mode's SI range)
+    pub const MODE_ACCELERATION: u8 = 0;
+
+    pub(crate) fn new(
+        transport: Arc<dyn Transport>,
+        port: Port,
+        port_id: u8,
+    ) -> Self {
+        Self {
+            transport,
+            port,
+            port_id,
+            scale: None,
+        }
+    }
+
+    /// Stream acceleration along the x, y and z axes, in g
+    pub async fn subscribe_acceleration(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<[f32; 3]>> {
+        let scale = match self.scale {
+            Some(scale) => scale,
+            None => *self
+                .scale
+                .insert(Scale::query(self, Self::MODE_ACCELERATION).await?),
+        };
+        let stream = self.subscribe(Self::MODE_ACCELERATION, delta).await?;
+        // The SI range is in milli-g
+        Ok(decode(stream, move |data| {
+            Some(decode_scaled(data, &scale)?.map(|mg| mg / 1000.0))
+        }))
+    }
+
+    pub async fn read_acceleration(&mut self) -> Result<[f32; 3]> {
+        let mut stream = self.subscribe_acceleration(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+}
+
+/// Struct representing the hub's gyro
+#[derive(Debug, Clone)]
+pub struct GyroSensor {
+    transport: Arc<dyn Transport>,
+    port: Port,
+    port_id: u8,
+    /// Fetched from the hub on first use
+    scale: Option<Scale>,
+}
+
+#[async_trait]
+impl Device for GyroSensor {
+    fn port(&self) -> Port {
+        self.port
+    }
+
+    fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+}
+
+impl GyroSensor {
+    /// Angular rate around x, y and z (3 x i16, scaled to deg/s by the
+    /// mode's SI range)
+    pub const MODE_ANGULAR_RATE: u8 = 0;
+
+    pub(crate) fn new(
+        transport: Arc<dyn Transport>,
+        port: Port,
+        port_id: u8,
+    ) -> Self {
+        Self {
+            transport,
+            port,
+            port_id,
+            scale: None,
+        }
+    }
+
+    /// Stream angular rate around the x, y and z axes, in deg/s
+    pub async fn subscribe_angular_rate(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<[f32; 3]>> {
+        let scale = match self.scale {
+            Some(scale) => scale,
+            None => *self
+                .scale
+                .insert(Scale::query(self, Self::MODE_ANGULAR_RATE).await?),
+        };
+        let stream = self.subscribe(Self::MODE_ANGULAR_RATE, delta).await?;
+        Ok(decode(stream, move |data| decode_scaled(data, &scale)))
+    }
+
+    pub async fn read_angular_rate(&mut self) -> Result<[f32; 3]> {
+        let mut stream = self.subscribe_angular_rate(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+}
+
+/// Struct representing the hub's tilt sensor
+#[derive(Debug, Clone)]
+pub struct TiltSensor {
+    transport: Arc<dyn Transport>,
+    port: Port,
+    port_id: u8,
+}
+
+#[async_trait]
+impl Device for TiltSensor {
+    fn port(&self) -> Port {
+        self.port
+    }
+
+    fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+}
+
+impl TiltSensor {
+    /// Yaw, pitch and roll in degrees (3 x i16)
+    pub const MODE_POSITION: u8 = 0;
+
+    pub(crate) fn new(
+        transport: Arc<dyn Transport>,
+        port: Port,
+        port_id: u8,
+    ) -> Self {
+        Self {
+            transport,
+            port,
+            port_id,
+        }
+    }
+
+    /// Stream the orientation of the hub
+    pub async fn subscribe_tilt(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<Tilt>> {
+        let stream = self.subscribe(Self::MODE_POSITION, delta).await?;
+        Ok(decode(stream, |data| {
+            let [yaw, pitch, roll] = decode_i16s(data)?;
+            Some(Tilt { yaw, pitch, roll })
+        }))
+    }
+
+    pub async fn read_tilt(&mut self) -> Result<Tilt> {
+        let mut stream = self.subscribe_tilt(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+}
+
+/// Struct representing the hub's gesture detector
+#[derive(Debug, Clone)]
+pub struct GestureSensor {
+    transport: Arc<dyn Transport>,
+    port: Port,
+    port_id: u8,
+}
+
+#[async_trait]
+impl Device for GestureSensor {
+    fn port(&self) -> Port {
+        self.port
+    }
+
+    fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+}
+
+impl GestureSensor {
+    /// Last gesture detected (u8)
+    pub const MODE_GESTURE: u8 = 0;
+
+    pub(crate) fn new(
+        transport: Arc<dyn Transport>,
+        port: Port,
+        port_id: u8,
+    ) -> Self {
+        Self {
+            transport,
+            port,
+            port_id,
+        }
+    }
+
+    /// Stream gestures as the hub detects them
+    pub async fn subscribe_gestures(
+        &mut self,
+    ) -> Result<ReadingStream<Gesture>> {
+        let stream = self.subscribe(Self::MODE_GESTURE, 1).await?;
+        Ok(decode(stream, |data| Some(Gesture::from(*data.first()?))))
+    }
+}
+
+fn decode_scaled(data: &[u8], scale: &Scale) -> Option<[f32; 3]> {
+    Some(decode_i16s(data)?.map(|raw| scale.apply(raw as f32)))
+}
+
+fn decode_i16s(data: &[u8]) -> Option<[i16; 3]> {
+    let value = |i: usize| {
+        Some(i16::from_le_bytes(
+            data.get(i * 2..i * 2 + 2)?.try_into().ok()?,
+        ))
+    };
+    Some([value(0)?, value(1)?, value(2)?])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_values() {
+        let data = [0x00, 0x10, 0x00, 0xf0, 0x90, 0x01];
+        assert_eq!(decode_i16s(&data), Some([4096, -4096, 400]));
+        let scale = Scale {
+            raw_min: -32768.0,
+            raw_max: 32768.0,
+            si_min: -8000.0,
+            si_max: 8000.0,
+        };
+        assert_eq!(
+            decode_scaled(&data, &scale),
+            Some([1000.0, -1000.0, 97.65625])
+        );
+        assert_eq!(decode_i16s(&[0, 0, 0, 0]), None);
+        assert_eq!(Gesture::from(3), Gesture::Shake);
+        assert_eq!(Gesture::from(9), Gesture::Other(9));
+    }
+}
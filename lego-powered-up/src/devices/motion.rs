@@ -0,0 +1,101 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The WeDo 2.0 motion sensor
+
+use super::{decode, next_reading, Device, ReadingStream};
+use crate::error::Result;
+use crate::hubs::Port;
+use crate::transport::Transport;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Struct representing a WeDo 2.0 motion sensor
+#[derive(Debug, Clone)]
+pub struct MotionSensor {
+    transport: Arc<dyn Transport>,
+    port: Port,
+    port_id: u8,
+}
+
+#[async_trait]
+impl Device for MotionSensor {
+    fn port(&self) -> Port {
+        self.port
+    }
+
+    fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+}
+
+impl MotionSensor {
+    /// Distance in cm (u8), with a second byte set once past 255
+    pub const MODE_DISTANCE: u8 = 0;
+    /// Number of objects detected (u32)
+    pub const MODE_COUNT: u8 = 1;
+
+    pub(crate) fn new(
+        transport: Arc<dyn Transport>,
+        port: Port,
+        port_id: u8,
+    ) -> Self {
+        Self {
+            transport,
+            port,
+            port_id,
+        }
+    }
+
+    /// Stream the distance to an object in mm, or `None` if nothing is in
+    /// range
+    pub async fn subscribe_distance(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<Option<u16>>> {
+        let stream = self.subscribe(Self::MODE_DISTANCE, delta).await?;
+        Ok(decode(stream, |data| Some(decode_distance(data))))
+    }
+
+    pub async fn read_distance(&mut self) -> Result<Option<u16>> {
+        let mut stream = self.subscribe_distance(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+
+    /// Stream the number of objects the sensor has detected
+    pub async fn subscribe_motion_count(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<u32>> {
+        let stream = self.subscribe(Self::MODE_COUNT, delta).await?;
+        Ok(decode(stream, |data| {
+            Some(u32::from_le_bytes(data.get(..4)?.try_into().ok()?))
+        }))
+    }
+}
+
+/// Distance in mm
+fn decode_distance(data: &[u8]) -> Option<u16> {
+    let mut distance = *data.first()? as u16;
+    if data.get(1) == Some(&1) {
+        distance += 255;
+    }
+    Some(distance * 10)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_values() {
+        assert_eq!(decode_distance(&[12, 0]), Some(120));
+        assert_eq!(decode_distance(&[10, 1]), Some(2650));
+        assert_eq!(decode_distance(&[]), None);
+    }
+}
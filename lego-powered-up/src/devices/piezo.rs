@@ -0,0 +1,232 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Piezo buzzers, and a simple notation for melodies to play on them
+
+use super::{write_mode, Device};
+use crate::error::{Error, Result};
+use crate::hubs::Port;
+use crate::transport::Transport;
+use async_trait::async_trait;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A single note of a melody
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Note {
+    /// Pitch in Hz, or `None` for a rest
+    pub frequency: Option<u16>,
+    pub duration: Duration,
+}
+
+impl Note {
+    /// Parse a note such as `C4/4`, `F#5/8.` or `R/2` at `tempo` beats
+    /// (quarter notes) per minute. The name is a letter, an optional `#`
+    /// or `b` and an octave, or `R` for a rest. The length is the fraction
+    /// of a whole note, optionally dotted to make it half as long again.
+    pub fn parse(note: &str, tempo: u16) -> Result<Self> {
+        let invalid = || Error::ParseError(format!("Invalid note `{note}`"));
+
+        let (name, length) = note.split_once('/').ok_or_else(invalid)?;
+        let (length, dotted) = match length.strip_suffix('.') {
+            Some(length) => (length, true),
+            None => (length, false),
+        };
+        let length = u32::from_str(length).map_err(|_| invalid())?;
+        if length == 0 || tempo == 0 {
+            return Err(invalid());
+        }
+        let whole = 4 * 60_000 / tempo as u32;
+        let mut millis = whole / length;
+        if dotted {
+            millis += millis / 2;
+        }
+
+        let frequency = if name == "R" {
+            None
+        } else {
+            Some(frequency(name).ok_or_else(invalid)?)
+        };
+        Ok(Self {
+            frequency,
+            duration: Duration::from_millis(millis as u64),
+        })
+    }
+}
+
+/// Parse a whitespace separated sequence of notes, see `Note::parse`
+pub fn parse_melody(melody: &str, tempo: u16) -> Result<Vec<Note>> {
+    melody
+        .split_whitespace()
+        .map(|note| Note::parse(note, tempo))
+        .collect()
+}
+
+/// Frequency of a named note in equal temperament, with A4 at 440 Hz
+fn frequency(name: &str) -> Option<u16> {
+    let mut chars = name.chars();
+    let mut semitone: i32 = match chars.next()? {
+        'C' => -9,
+        'D' => -7,
+        'E' => -5,
+        'F' => -4,
+        'G' => -2,
+        'A' => 0,
+        'B' => 2,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let octave = if let Some(octave) = rest.strip_prefix('#') {
+        semitone += 1;
+        octave
+    } else if let Some(octave) = rest.strip_prefix('b') {
+        semitone -= 1;
+        octave
+    } else {
+        rest
+    };
+    let octave = i32::from_str(octave).ok()?;
+    semitone += (octave - 4) * 12;
+    let hz = 440.0 * 2f32.powf(semitone as f32 / 12.0);
+    (1.0..=u16::MAX as f32)
+        .contains(&hz)
+        .then(|| hz.round() as u16)
+}
+
+/// Struct representing a piezo buzzer
+#[derive(Debug, Clone)]
+pub struct PiezoBuzzer {
+    transport: Arc<dyn Transport>,
+    port: Port,
+    port_id: u8,
+}
+
+#[async_trait]
+impl Device for PiezoBuzzer {
+    fn port(&self) -> Port {
+        self.port
+    }
+
+    fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+}
+
+impl PiezoBuzzer {
+    /// Tone output: frequency in Hz and duration in ms (2 x u16)
+    pub const MODE_TONE: u8 = 0;
+
+    pub(crate) fn new(
+        transport: Arc<dyn Transport>,
+        port: Port,
+        port_id: u8,
+    ) -> Self {
+        Self {
+            transport,
+            port,
+            port_id,
+        }
+    }
+
+    /// Start playing a tone. This returns straight away, while the hub
+    /// plays the tone for `duration`.
+    pub async fn play_tone(
+        &mut self,
+        frequency: u16,
+        duration: Duration,
+    ) -> Result<()> {
+        let millis = duration.as_millis().min(u16::MAX as u128) as u16;
+        let mut data = frequency.to_le_bytes().to_vec();
+        data.extend_from_slice(&millis.to_le_bytes());
+        write_mode(self, Self::MODE_TONE, data).await
+    }
+
+    /// Play a sequence of notes, returning once the last one has finished
+    pub async fn play_melody(&mut self, notes: &[Note]) -> Result<()> {
+        for note in notes {
+            if let Some(frequency) = note.frequency {
+                self.play_tone(frequency, note.duration).await?;
+            }
+            tokio::time::sleep(note.duration).await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::consts::DeviceType;
+    use crate::hubs::{Hub, TechnicHub};
+    use crate::notifications::*;
+    use crate::simulator::{SimulatedHub, Spy};
+
+    #[test]
+    fn notes() {
+        assert_eq!(frequency("A4"), Some(440));
+        assert_eq!(frequency("C4"), Some(262));
+        assert_eq!(frequency("C#4"), frequency("Db4"));
+        assert_eq!(frequency("A5"), Some(880));
+        assert_eq!(frequency("H4"), None);
+        assert_eq!(
+            parse_melody("C4/4 R/8 E4/8.", 120).unwrap(),
+            vec![
+                Note {
+                    frequency: Some(262),
+                    duration: Duration::from_millis(500),
+                },
+                Note {
+                    frequency: None,
+                    duration: Duration::from_millis(250),
+                },
+                Note {
+                    frequency: Some(330),
+                    duration: Duration::from_millis(375),
+                },
+            ]
+        );
+        assert!(Note::parse("C4", 120).is_err());
+        assert!(Note::parse("C4/0", 120).is_err());
+    }
+
+    #[tokio::test]
+    async fn play_note() {
+        let hub = SimulatedHub::new("Sim")
+            .attach(2, DeviceType::PiezoBuzzer)
+            .connect();
+        let spy = Spy::new(hub.clone());
+        let hub = TechnicHub::init(spy.clone(), hub.properties())
+            .await
+            .unwrap();
+        let mut buzzer = hub
+            .port(Port::C)
+            .await
+            .unwrap()
+            .downcast::<PiezoBuzzer>()
+            .unwrap();
+
+        spy.take();
+        let note = Note::parse("A4/4", 120).unwrap();
+        buzzer.play_melody(&[note]).await.unwrap();
+        let tone =
+            NotificationMessage::PortOutputCommand(PortOutputCommandFormat {
+                port_id: 2,
+                startup_info: StartupInfo::ExecuteImmediately,
+                completion_info: CompletionInfo::NoAction,
+                subcommand: PortOutputSubcommand::WriteDirectModeData(
+                    WriteDirectModeDataPayload::ModeData {
+                        mode: PiezoBuzzer::MODE_TONE,
+                        // 440 Hz for 500 ms
+                        data: vec![0xb8, 0x01, 0xf4, 0x01],
+                    },
+                ),
+            });
+        assert_eq!(spy.take().last(), Some(&tone.serialise()));
+    }
+}
@@ -0,0 +1,232 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The battery voltage and motor current sensors built into hubs
+
+use super::{decode, mode_information, next_reading, Device, ReadingStream};
+use crate::error::{Error, Result};
+use crate::hubs::Port;
+use crate::notifications::{ModeInformationType, PortModeInformationType};
+use crate::transport::Transport;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Linear mapping from raw values to SI units, as described by the mode
+/// information of a port
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Scale {
+    pub raw_min: f32,
+    pub raw_max: f32,
+    pub si_min: f32,
+    pub si_max: f32,
+}
+
+impl Scale {
+    /// Convert a raw value to SI units
+    pub fn apply(&self, raw: f32) -> f32 {
+        let raw_range = self.raw_max - self.raw_min;
+        if raw_range == 0.0 {
+            return self.si_min;
+        }
+        self.si_min
+            + (raw - self.raw_min) * (self.si_max - self.si_min) / raw_range
+    }
+
+    /// Ask the hub for the raw and SI ranges of a mode
+    pub(crate) async fn query<D: Device + ?Sized>(
+        device: &D,
+        mode: u8,
+    ) -> Result<Self> {
+        Self::query_port(device.transport(), device.port_id(), mode).await
+    }
+
+    /// Ask the hub for the raw and SI ranges of a mode of any port, which
+    /// need not be the port of a device we hold
+    pub(crate) async fn query_port(
+        transport: &dyn Transport,
+        port_id: u8,
+        mode: u8,
+    ) -> Result<Self> {
+        let raw = mode_information(
+            transport,
+            port_id,
+            mode,
+            ModeInformationType::Raw,
+        )
+        .await?;
+        let si =
+            mode_information(transport, port_id, mode, ModeInformationType::Si)
+                .await?;
+        match (raw, si) {
+            (
+                PortModeInformationType::RawRange {
+                    min: raw_min,
+                    max: raw_max,
+                },
+                PortModeInformationType::SiRange {
+                    min: si_min,
+                    max: si_max,
+                },
+            ) => Ok(Self {
+                raw_min,
+                raw_max,
+                si_min,
+                si_max,
+            }),
+            _ => {
+                Err(Error::HubError("Unexpected mode information".to_string()))
+            }
+        }
+    }
+}
+
+/// Struct representing a hub's battery voltage sensor
+#[derive(Debug, Clone)]
+pub struct VoltageSensor {
+    transport: Arc<dyn Transport>,
+    port: Port,
+    port_id: u8,
+    /// Fetched from the hub on first use
+    scale: Option<Scale>,
+}
+
+#[async_trait]
+impl Device for VoltageSensor {
+    fn port(&self) -> Port {
+        self.port
+    }
+
+    fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+}
+
+impl VoltageSensor {
+    /// Battery voltage (u16, scaled to mV by the mode's SI range)
+    pub const MODE_VOLTAGE: u8 = 0;
+
+    pub(crate) fn new(
+        transport: Arc<dyn Transport>,
+        port: Port,
+        port_id: u8,
+    ) -> Self {
+        Self {
+            transport,
+            port,
+            port_id,
+            scale: None,
+        }
+    }
+
+    /// Stream the hub's battery voltage, in mV
+    pub async fn subscribe_voltage(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<u32>> {
+        let scale = match self.scale {
+            Some(scale) => scale,
+            None => *self
+                .scale
+                .insert(Scale::query(self, Self::MODE_VOLTAGE).await?),
+        };
+        let stream = self.subscribe(Self::MODE_VOLTAGE, delta).await?;
+        Ok(decode(stream, move |data| decode_scaled(data, &scale)))
+    }
+
+    pub async fn read_voltage(&mut self) -> Result<u32> {
+        let mut stream = self.subscribe_voltage(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+}
+
+/// Struct representing a hub's motor current sensor
+#[derive(Debug, Clone)]
+pub struct CurrentSensor {
+    transport: Arc<dyn Transport>,
+    port: Port,
+    port_id: u8,
+    /// Fetched from the hub on first use
+    scale: Option<Scale>,
+}
+
+#[async_trait]
+impl Device for CurrentSensor {
+    fn port(&self) -> Port {
+        self.port
+    }
+
+    fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+}
+
+impl CurrentSensor {
+    /// Current drawn by the motors (u16, scaled to mA by the mode's SI
+    /// range)
+    pub const MODE_CURRENT: u8 = 0;
+
+    pub(crate) fn new(
+        transport: Arc<dyn Transport>,
+        port: Port,
+        port_id: u8,
+    ) -> Self {
+        Self {
+            transport,
+            port,
+            port_id,
+            scale: None,
+        }
+    }
+
+    /// Stream the current drawn by the hub's motors, in mA
+    pub async fn subscribe_current(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<u32>> {
+        let scale = match self.scale {
+            Some(scale) => scale,
+            None => *self
+                .scale
+                .insert(Scale::query(self, Self::MODE_CURRENT).await?),
+        };
+        let stream = self.subscribe(Self::MODE_CURRENT, delta).await?;
+        Ok(decode(stream, move |data| decode_scaled(data, &scale)))
+    }
+
+    pub async fn read_current(&mut self) -> Result<u32> {
+        let mut stream = self.subscribe_current(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+}
+
+pub(super) fn decode_scaled(data: &[u8], scale: &Scale) -> Option<u32> {
+    let raw = u16::from_le_bytes(data.get(..2)?.try_into().ok()?);
+    Some(scale.apply(raw as f32).round().max(0.0) as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scaling() {
+        let scale = Scale {
+            raw_min: 0.0,
+            raw_max: 4095.0,
+            si_min: 0.0,
+            si_max: 9620.0,
+        };
+        assert_eq!(decode_scaled(&[0xff, 0x0f], &scale), Some(9620));
+        assert_eq!(decode_scaled(&[0x00, 0x08], &scale), Some(4811));
+        assert_eq!(decode_scaled(&[0x00], &scale), None);
+    }
+}
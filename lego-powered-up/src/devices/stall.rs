@@ -0,0 +1,410 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Stall detection for motors, to avoid stripping gears when a mechanism
+//! runs into an end stop
+
+use super::{Device, Motor, Reading, ReadingStream};
+use crate::hubs::Port;
+use crate::notifications::{NotificationMessage, Power};
+use crate::transport::MessageStream;
+use futures::stream::{self, Stream, StreamExt};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+/// Shortest interval between checks for a stall, however short the
+/// stall time
+const MIN_POLL: Duration = Duration::from_millis(10);
+
+/// What to do with a motor once it has stalled
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StallAction {
+    /// Only report the stall
+    Notify,
+    /// Stop driving the motor and let it spin freely
+    Float,
+    /// Hold the motor in place, but limit the power to `max_power` percent
+    Hold { max_power: u8 },
+}
+
+/// Per-motor stall detection settings
+#[derive(Copy, Clone, Debug)]
+pub struct StallConfig {
+    pub action: StallAction,
+    /// Speeds (in percent) up to this magnitude count as not moving
+    pub min_speed: i8,
+    /// How long the motor must be driven without moving, or over the
+    /// current limit, before it counts as stalled
+    pub stall_time: Duration,
+    /// Hub current, in mA, above which the motor counts as stalled. The
+    /// current sensor measures the whole hub, so this is only meaningful
+    /// with a single motor running.
+    pub current_limit: Option<u32>,
+}
+
+impl Default for StallConfig {
+    fn default() -> Self {
+        Self {
+            action: StallAction::Float,
+            min_speed: 2,
+            stall_time: Duration::from_millis(500),
+            current_limit: None,
+        }
+    }
+}
+
+/// Why a motor was considered stalled
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StallReason {
+    /// Driven, but not turning
+    NotMoving,
+    /// Hub current above `StallConfig::current_limit`
+    OverCurrent,
+}
+
+/// Reported each time a motor stalls
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StallEvent {
+    pub port: Port,
+    pub reason: StallReason,
+    /// Speed the motor had been commanded to run at
+    pub commanded_speed: i8,
+    pub timestamp: Instant,
+}
+
+/// Handle to a running stall detector, which yields an event for each
+/// stall. Dropping the handle stops the detection.
+#[derive(Debug)]
+pub struct StallMonitor {
+    events: mpsc::UnboundedReceiver<StallEvent>,
+    task: JoinHandle<()>,
+    /// The motor's count of running detectors
+    monitors: Arc<AtomicUsize>,
+}
+
+impl Stream for StallMonitor {
+    type Item = StallEvent;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<StallEvent>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+impl Drop for StallMonitor {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.monitors.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+enum Feedback {
+    Speed(Reading<i8>),
+    /// Hub current, in mA
+    Current(Reading<u32>),
+    /// Whether the hub is running a command on the motor's port
+    Running(bool),
+}
+
+/// Spawn the detector task for a motor. The motor handle is a clone of
+/// the caller's, so shares its record of the commanded speed, and asks
+/// for command feedback for as long as the monitor is alive. `output`
+/// carries the hub's command feedback, which tells when a command that
+/// stops by itself has finished.
+pub(super) fn spawn(
+    mut motor: Motor,
+    speed: ReadingStream<i8>,
+    current: Option<ReadingStream<u32>>,
+    output: MessageStream,
+    config: StallConfig,
+) -> StallMonitor {
+    let (tx, events) = mpsc::unbounded_channel();
+    let port_id = motor.port_id;
+    let monitors = motor.stall_monitors.clone();
+    monitors.fetch_add(1, Ordering::Relaxed);
+    let running = output.flat_map(move |msg| {
+        let running = match NotificationMessage::parse(&msg) {
+            Ok(NotificationMessage::PortOutputCommandFeedback(feedback)) => {
+                feedback
+                    .messages()
+                    .filter(|m| m.port_id() == port_id)
+                    .filter(|m| m.in_progress() || m.idle())
+                    .map(|m| Feedback::Running(m.in_progress()))
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        stream::iter(running)
+    });
+    let speed = stream::select(speed.map(Feedback::Speed), running);
+    let mut feedback: Pin<Box<dyn Stream<Item = Feedback> + Send>> =
+        match current {
+            Some(current) => {
+                Box::pin(stream::select(speed, current.map(Feedback::Current)))
+            }
+            None => Box::pin(speed),
+        };
+
+    let task = tokio::spawn(async move {
+        // Poll often enough to notice a stall soon after stall_time
+        let poll = (config.stall_time / 4).max(MIN_POLL);
+        let mut moving = false;
+        let mut not_moving_since: Option<Instant> = None;
+        let mut over_current_since: Option<Instant> = None;
+        let mut last_commanded = 0;
+        let mut running = false;
+        let mut reported = false;
+
+        loop {
+            match timeout(poll, feedback.next()).await {
+                Ok(Some(Feedback::Speed(reading))) => {
+                    moving = reading.value.saturating_abs() > config.min_speed;
+                    if moving {
+                        not_moving_since = None;
+                        reported = false;
+                    } else {
+                        not_moving_since.get_or_insert(reading.timestamp);
+                    }
+                }
+                Ok(Some(Feedback::Running(now_running))) => {
+                    if now_running && !running {
+                        // A command has started, so give the motor time
+                        // to get going
+                        reported = false;
+                        if !moving {
+                            not_moving_since = Some(Instant::now());
+                        }
+                    }
+                    running = now_running;
+                }
+                Ok(Some(Feedback::Current(reading))) => {
+                    let over = config
+                        .current_limit
+                        .is_some_and(|limit| reading.value > limit);
+                    if over {
+                        over_current_since.get_or_insert(reading.timestamp);
+                    } else {
+                        over_current_since = None;
+                    }
+                }
+                Ok(None) => break,
+                Err(_) => {}
+            }
+
+            let commanded = motor.commanded_speed.load(Ordering::Relaxed);
+            if commanded != last_commanded {
+                // A new command restarts the clock, otherwise a motor
+                // that was sitting still would be reported immediately
+                last_commanded = commanded;
+                reported = false;
+                if !moving {
+                    not_moving_since = Some(Instant::now());
+                }
+            }
+            // A position or timed command which has finished leaves the
+            // motor still on purpose
+            let finished = motor.until_done.load(Ordering::Relaxed) && !running;
+            if commanded == 0 || finished || reported {
+                continue;
+            }
+
+            let now = Instant::now();
+            let stalled_for = |since: Option<Instant>| {
+                since.is_some_and(|t| now - t >= config.stall_time)
+            };
+            let reason = if stalled_for(not_moving_since) {
+                StallReason::NotMoving
+            } else if stalled_for(over_current_since) {
+                StallReason::OverCurrent
+            } else {
+                continue;
+            };
+            reported = true;
+            warn!("Motor on {:?} stalled: {:?}", motor.port, reason);
+
+            let result = match config.action {
+                StallAction::Notify => Ok(()),
+                StallAction::Float => motor.start_power(Power::Float).await,
+                StallAction::Hold { max_power } => {
+                    motor.start_speed(0, Power::Cw(max_power)).await
+                }
+            };
+            if let Err(e) = result {
+                error!("Failed to stop stalled motor: {}", e);
+            }
+            // Events are advisory, so carry on protecting the motor even
+            // if nobody is listening
+            let _ = tx.send(StallEvent {
+                port: motor.port,
+                reason,
+                commanded_speed: commanded,
+                timestamp: now,
+            });
+        }
+    });
+
+    StallMonitor {
+        events,
+        task,
+        monitors,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::consts::DeviceType;
+    use crate::hubs::{Hub, TechnicHub};
+    use crate::notifications::EndState;
+    use crate::notifications::{CompletionInfo, PortOutputCommandFormat};
+    use crate::simulator::{SimulatedHub, Spy};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    async fn hub(min: i32, max: i32) -> TechnicHub {
+        let transport = SimulatedHub::new("Sim")
+            .attach(0, DeviceType::TechnicLargeAngularMotor)
+            .end_stops(0, min, max)
+            .connect();
+        TechnicHub::init(transport.clone(), transport.properties())
+            .await
+            .unwrap()
+    }
+
+    fn config() -> StallConfig {
+        StallConfig {
+            stall_time: Duration::from_millis(200),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn stall_during_goto() {
+        let hub = hub(-1000, 90).await;
+        let mut motor = hub
+            .port(Port::A)
+            .await
+            .unwrap()
+            .downcast::<Motor>()
+            .unwrap();
+        let mut stalls = motor.detect_stalls(config()).await.unwrap();
+        motor
+            .goto_absolute_position(360, 50, Power::Cw(100), EndState::Hold)
+            .await
+            .unwrap();
+        let stall = timeout(TIMEOUT, stalls.next()).await.unwrap().unwrap();
+        assert_eq!(stall.reason, StallReason::NotMoving);
+        assert_eq!(stall.commanded_speed, 50);
+    }
+
+    #[tokio::test]
+    async fn finished_goto_is_not_a_stall() {
+        let hub = hub(-1000, 1000).await;
+        let mut motor = hub
+            .port(Port::A)
+            .await
+            .unwrap()
+            .downcast::<Motor>()
+            .unwrap();
+        let mut stalls = motor.detect_stalls(config()).await.unwrap();
+        motor
+            .goto_absolute_position(90, 50, Power::Cw(100), EndState::Hold)
+            .await
+            .unwrap();
+        let stall = timeout(Duration::from_secs(1), stalls.next()).await;
+        assert!(stall.is_err(), "unexpected {:?}", stall);
+    }
+
+    #[tokio::test]
+    async fn current_scaled_by_sensor_range() {
+        let hub = hub(-1000, 90).await;
+        let mut motor = hub
+            .port(Port::A)
+            .await
+            .unwrap()
+            .downcast::<Motor>()
+            .unwrap();
+        // Only the current counts. Stalled at 50% the simulated hub
+        // draws 2100 raw, which its SI range makes 2141mA.
+        let config = StallConfig {
+            min_speed: i8::MIN,
+            current_limit: Some(2120),
+            ..config()
+        };
+        let mut stalls = motor.detect_stalls(config).await.unwrap();
+        motor.start_speed(50, Power::Cw(100)).await.unwrap();
+        let stall = timeout(TIMEOUT, stalls.next()).await.unwrap().unwrap();
+        assert_eq!(stall.reason, StallReason::OverCurrent);
+    }
+
+    #[tokio::test]
+    async fn feedback_only_while_detecting() {
+        let transport = SimulatedHub::new("Sim")
+            .attach(0, DeviceType::TechnicLargeAngularMotor)
+            .connect();
+        let spy = Spy::new(transport.clone());
+        let hub = TechnicHub::init(spy.clone(), transport.properties())
+            .await
+            .unwrap();
+        let mut motor = hub
+            .port(Port::A)
+            .await
+            .unwrap()
+            .downcast::<Motor>()
+            .unwrap();
+        let completion = |spy: &Spy| {
+            spy.take()
+                .iter()
+                .filter_map(|msg| match NotificationMessage::parse(msg) {
+                    Ok(NotificationMessage::PortOutputCommand(
+                        PortOutputCommandFormat {
+                            completion_info, ..
+                        },
+                    )) => Some(completion_info),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        spy.take();
+        motor.start_speed(20, Power::Cw(100)).await.unwrap();
+        assert_eq!(completion(&spy), vec![CompletionInfo::NoAction]);
+
+        let stalls = motor.detect_stalls(config()).await.unwrap();
+        spy.take();
+        motor.start_speed(30, Power::Cw(100)).await.unwrap();
+        assert_eq!(completion(&spy), vec![CompletionInfo::CommandFeedback]);
+
+        drop(stalls);
+        motor.start_speed(0, Power::Cw(100)).await.unwrap();
+        assert_eq!(completion(&spy), vec![CompletionInfo::NoAction]);
+    }
+
+    #[tokio::test]
+    async fn zero_stall_time() {
+        let hub = hub(-1000, 90).await;
+        let mut motor = hub
+            .port(Port::A)
+            .await
+            .unwrap()
+            .downcast::<Motor>()
+            .unwrap();
+        let config = StallConfig {
+            stall_time: Duration::ZERO,
+            ..config()
+        };
+        let mut stalls = motor.detect_stalls(config).await.unwrap();
+        motor.start_speed(50, Power::Cw(100)).await.unwrap();
+        let stall = timeout(TIMEOUT, stalls.next()).await.unwrap().unwrap();
+        assert_eq!(stall.reason, StallReason::NotMoving);
+    }
+}
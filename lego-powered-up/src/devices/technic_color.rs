@@ -0,0 +1,192 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The Technic (SPIKE) colour sensor
+
+use super::color_distance::decode_color;
+use super::{decode, next_reading, write_mode, Device, ReadingStream};
+use crate::consts::Color;
+use crate::error::Result;
+use crate::hubs::Port;
+use crate::transport::Transport;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Colour as hue, saturation and value
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Hsv {
+    /// Hue, in degrees
+    pub hue: u16,
+    /// Saturation, in percent
+    pub saturation: u8,
+    /// Value (brightness), in percent
+    pub value: u8,
+}
+
+/// Struct representing a Technic colour sensor
+#[derive(Debug, Clone)]
+pub struct TechnicColorSensor {
+    transport: Arc<dyn Transport>,
+    port: Port,
+    port_id: u8,
+}
+
+#[async_trait]
+impl Device for TechnicColorSensor {
+    fn port(&self) -> Port {
+        self.port
+    }
+
+    fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+}
+
+impl TechnicColorSensor {
+    /// Detected colour (`Color`, or -1 for none)
+    pub const MODE_COLOR: u8 = 0;
+    /// Reflected light, in percent
+    pub const MODE_REFLECTED: u8 = 1;
+    /// Ambient light, in percent
+    pub const MODE_AMBIENT: u8 = 2;
+    /// Brightness of the three lights (output, 3 x percent)
+    pub const MODE_LIGHT: u8 = 3;
+    /// Raw red, green, blue and overall intensity (4 x u16)
+    pub const MODE_RGB: u8 = 5;
+    /// Hue in degrees, saturation and value in tenths of a percent
+    /// (3 x u16)
+    pub const MODE_HSV: u8 = 6;
+
+    pub(crate) fn new(
+        transport: Arc<dyn Transport>,
+        port: Port,
+        port_id: u8,
+    ) -> Self {
+        Self {
+            transport,
+            port,
+            port_id,
+        }
+    }
+
+    /// Stream the colour the sensor sees
+    pub async fn subscribe_color(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<Color>> {
+        let stream = self.subscribe(Self::MODE_COLOR, delta).await?;
+        Ok(decode(stream, |data| Some(decode_color(*data.first()?))))
+    }
+
+    pub async fn read_color(&mut self) -> Result<Color> {
+        let mut stream = self.subscribe_color(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+
+    /// Stream the reflected light intensity, in percent
+    pub async fn subscribe_reflected_light(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<u8>> {
+        let stream = self.subscribe(Self::MODE_REFLECTED, delta).await?;
+        Ok(decode(stream, |data| data.first().copied()))
+    }
+
+    pub async fn read_reflected_light(&mut self) -> Result<u8> {
+        let mut stream = self.subscribe_reflected_light(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+
+    /// Stream the ambient light intensity, in percent
+    pub async fn subscribe_ambient_light(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<u8>> {
+        let stream = self.subscribe(Self::MODE_AMBIENT, delta).await?;
+        Ok(decode(stream, |data| data.first().copied()))
+    }
+
+    pub async fn read_ambient_light(&mut self) -> Result<u8> {
+        let mut stream = self.subscribe_ambient_light(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+
+    /// Stream raw red, green and blue intensities
+    pub async fn subscribe_rgb(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<[u16; 3]>> {
+        let stream = self.subscribe(Self::MODE_RGB, delta).await?;
+        Ok(decode(stream, decode_u16s::<3>))
+    }
+
+    pub async fn read_rgb(&mut self) -> Result<[u16; 3]> {
+        let mut stream = self.subscribe_rgb(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+
+    /// Stream colour as hue, saturation and value
+    pub async fn subscribe_hsv(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<Hsv>> {
+        let stream = self.subscribe(Self::MODE_HSV, delta).await?;
+        Ok(decode(stream, decode_hsv))
+    }
+
+    pub async fn read_hsv(&mut self) -> Result<Hsv> {
+        let mut stream = self.subscribe_hsv(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+
+    /// Set the brightness, in percent, of each of the sensor's built-in
+    /// lights. Lights without a value are switched off.
+    pub async fn set_lights(&mut self, brightness: &[u8]) -> Result<()> {
+        let data = (0..3)
+            .map(|i| brightness.get(i).copied().unwrap_or(0).min(100))
+            .collect();
+        write_mode(self, Self::MODE_LIGHT, data).await
+    }
+}
+
+fn decode_u16s<const N: usize>(data: &[u8]) -> Option<[u16; N]> {
+    let mut values = [0; N];
+    for (i, value) in values.iter_mut().enumerate() {
+        *value =
+            u16::from_le_bytes(data.get(i * 2..i * 2 + 2)?.try_into().ok()?);
+    }
+    Some(values)
+}
+
+fn decode_hsv(data: &[u8]) -> Option<Hsv> {
+    let [hue, saturation, value] = decode_u16s::<3>(data)?;
+    Some(Hsv {
+        hue,
+        saturation: (saturation / 10).min(100) as u8,
+        value: (value / 10).min(100) as u8,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_values() {
+        assert_eq!(decode_u16s::<2>(&[1, 0, 0, 1]), Some([1, 256]));
+        assert_eq!(decode_u16s::<2>(&[1, 0, 0]), None);
+        assert_eq!(
+            decode_hsv(&[0x68, 0x01, 0xe8, 0x03, 0xf4, 0x01]),
+            Some(Hsv {
+                hue: 360,
+                saturation: 100,
+                value: 50,
+            })
+        );
+    }
+}
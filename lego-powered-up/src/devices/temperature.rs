@@ -0,0 +1,79 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Temperature sensors built into hubs
+
+use super::{decode, next_reading, Device, ReadingStream, Scale};
+use crate::error::Result;
+use crate::hubs::Port;
+use crate::transport::Transport;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Struct representing a hub's temperature sensor
+#[derive(Debug, Clone)]
+pub struct TemperatureSensor {
+    transport: Arc<dyn Transport>,
+    port: Port,
+    port_id: u8,
+    /// Fetched from the hub on first use
+    scale: Option<Scale>,
+}
+
+#[async_trait]
+impl Device for TemperatureSensor {
+    fn port(&self) -> Port {
+        self.port
+    }
+
+    fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+}
+
+impl TemperatureSensor {
+    /// Temperature (i16, scaled to degrees Celsius by the mode's SI
+    /// range)
+    pub const MODE_TEMPERATURE: u8 = 0;
+
+    pub(crate) fn new(
+        transport: Arc<dyn Transport>,
+        port: Port,
+        port_id: u8,
+    ) -> Self {
+        Self {
+            transport,
+            port,
+            port_id,
+            scale: None,
+        }
+    }
+
+    /// Stream temperature, in degrees Celsius
+    pub async fn subscribe_temperature(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<f32>> {
+        let scale = match self.scale {
+            Some(scale) => scale,
+            None => *self
+                .scale
+                .insert(Scale::query(self, Self::MODE_TEMPERATURE).await?),
+        };
+        let stream = self.subscribe(Self::MODE_TEMPERATURE, delta).await?;
+        Ok(decode(stream, move |data| {
+            let raw = i16::from_le_bytes(data.get(..2)?.try_into().ok()?);
+            Some(scale.apply(raw as f32))
+        }))
+    }
+
+    pub async fn read_temperature(&mut self) -> Result<f32> {
+        let mut stream = self.subscribe_temperature(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+}
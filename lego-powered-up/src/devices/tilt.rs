@@ -0,0 +1,261 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Simple tilt sensors: the WeDo 2.0 external sensor and the one built
+//! into the BOOST Move hub
+
+use super::{decode, next_reading, Device, ReadingStream};
+use crate::error::Result;
+use crate::hubs::Port;
+use crate::notifications::{
+    CompletionInfo, NotificationMessage, PortOutputCommandFormat,
+    PortOutputSubcommand, StartupInfo, WriteDirectModeDataPayload,
+};
+use crate::transport::Transport;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Tilt angles, in degrees
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TiltAngle {
+    pub roll: i8,
+    pub pitch: i8,
+}
+
+/// Direction a tilt sensor is tipped in
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TiltDirection {
+    Neutral,
+    Backward,
+    Right,
+    Left,
+    Forward,
+    /// The sensor couldn't tell, e.g. because it is upside down
+    Unknown,
+}
+
+impl From<u8> for TiltDirection {
+    fn from(code: u8) -> Self {
+        match code {
+            0 => TiltDirection::Neutral,
+            3 => TiltDirection::Backward,
+            5 => TiltDirection::Right,
+            7 => TiltDirection::Left,
+            9 => TiltDirection::Forward,
+            _ => TiltDirection::Unknown,
+        }
+    }
+}
+
+/// Struct representing a WeDo 2.0 tilt sensor
+#[derive(Debug, Clone)]
+pub struct ExternalTiltSensor {
+    transport: Arc<dyn Transport>,
+    port: Port,
+    port_id: u8,
+}
+
+#[async_trait]
+impl Device for ExternalTiltSensor {
+    fn port(&self) -> Port {
+        self.port
+    }
+
+    fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+}
+
+impl ExternalTiltSensor {
+    /// Roll and pitch in degrees (2 x i8)
+    pub const MODE_ANGLE: u8 = 0;
+    /// Tilt direction (u8)
+    pub const MODE_DIRECTION: u8 = 1;
+    /// Crash counts along each axis (3 x u8)
+    pub const MODE_CRASH: u8 = 2;
+
+    pub(crate) fn new(
+        transport: Arc<dyn Transport>,
+        port: Port,
+        port_id: u8,
+    ) -> Self {
+        Self {
+            transport,
+            port,
+            port_id,
+        }
+    }
+
+    /// Stream the roll and pitch of the sensor
+    pub async fn subscribe_tilt_angle(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<TiltAngle>> {
+        let stream = self.subscribe(Self::MODE_ANGLE, delta).await?;
+        Ok(decode(stream, decode_angle))
+    }
+
+    pub async fn read_tilt_angle(&mut self) -> Result<TiltAngle> {
+        let mut stream = self.subscribe_tilt_angle(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+
+    /// Stream the direction the sensor is tipped in
+    pub async fn subscribe_tilt_direction(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<TiltDirection>> {
+        let stream = self.subscribe(Self::MODE_DIRECTION, delta).await?;
+        Ok(decode(stream, |data| {
+            Some(TiltDirection::from(*data.first()?))
+        }))
+    }
+
+    pub async fn read_tilt_direction(&mut self) -> Result<TiltDirection> {
+        let mut stream = self.subscribe_tilt_direction(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+}
+
+/// Struct representing the tilt sensor built into the BOOST Move hub
+#[derive(Debug, Clone)]
+pub struct MoveHubTiltSensor {
+    transport: Arc<dyn Transport>,
+    port: Port,
+    port_id: u8,
+}
+
+#[async_trait]
+impl Device for MoveHubTiltSensor {
+    fn port(&self) -> Port {
+        self.port
+    }
+
+    fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+}
+
+impl MoveHubTiltSensor {
+    /// Roll and pitch in degrees (2 x i8)
+    pub const MODE_ANGLE: u8 = 0;
+    /// Simple tilt direction (u8)
+    pub const MODE_TILT: u8 = 1;
+    /// Orientation of the hub (u8)
+    pub const MODE_ORIENTATION: u8 = 2;
+    /// Number of impacts detected (u32)
+    pub const MODE_IMPACT_COUNT: u8 = 3;
+    /// Acceleration along x, y and z (3 x i8)
+    pub const MODE_ACCELERATION: u8 = 4;
+
+    pub(crate) fn new(
+        transport: Arc<dyn Transport>,
+        port: Port,
+        port_id: u8,
+    ) -> Self {
+        Self {
+            transport,
+            port,
+            port_id,
+        }
+    }
+
+    async fn write(
+        &mut self,
+        payload: WriteDirectModeDataPayload,
+    ) -> Result<()> {
+        let msg =
+            NotificationMessage::PortOutputCommand(PortOutputCommandFormat {
+                port_id: self.port_id,
+                startup_info: StartupInfo::ExecuteImmediately,
+                completion_info: CompletionInfo::NoAction,
+                subcommand: PortOutputSubcommand::WriteDirectModeData(payload),
+            });
+        self.send(msg).await
+    }
+
+    /// Stream the roll and pitch of the sensor
+    pub async fn subscribe_tilt_angle(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<TiltAngle>> {
+        let stream = self.subscribe(Self::MODE_ANGLE, delta).await?;
+        Ok(decode(stream, decode_angle))
+    }
+
+    pub async fn read_tilt_angle(&mut self) -> Result<TiltAngle> {
+        let mut stream = self.subscribe_tilt_angle(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+
+    /// Stream the number of impacts the sensor has detected
+    pub async fn subscribe_impacts(
+        &mut self,
+        delta: u32,
+    ) -> Result<ReadingStream<u32>> {
+        let stream = self.subscribe(Self::MODE_IMPACT_COUNT, delta).await?;
+        Ok(decode(stream, |data| {
+            Some(u32::from_le_bytes(data.get(..4)?.try_into().ok()?))
+        }))
+    }
+
+    pub async fn read_impacts(&mut self) -> Result<u32> {
+        let mut stream = self.subscribe_impacts(1).await?;
+        Ok(next_reading(&mut stream).await?.value)
+    }
+
+    /// Set the impact count, e.g. to reset it
+    pub async fn preset_impacts(&mut self, count: u32) -> Result<()> {
+        self.write(WriteDirectModeDataPayload::TiltImpactPreset(count as i32))
+            .await
+    }
+
+    /// Set how hard a knock must be to count as an impact, and for how
+    /// long (in tens of ms) further knocks are ignored afterwards
+    pub async fn configure_impacts(
+        &mut self,
+        threshold: i8,
+        holdoff: i8,
+    ) -> Result<()> {
+        self.write(WriteDirectModeDataPayload::TiltConfigImpact {
+            impact_threshold: threshold,
+            bump_holdoff: holdoff,
+        })
+        .await
+    }
+}
+
+fn decode_angle(data: &[u8]) -> Option<TiltAngle> {
+    Some(TiltAngle {
+        roll: *data.first()? as i8,
+        pitch: *data.get(1)? as i8,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_values() {
+        assert_eq!(
+            decode_angle(&[0xf6, 45]),
+            Some(TiltAngle {
+                roll: -10,
+                pitch: 45
+            })
+        );
+        assert_eq!(decode_angle(&[1]), None);
+        assert_eq!(TiltDirection::from(9), TiltDirection::Forward);
+        assert_eq!(TiltDirection::from(10), TiltDirection::Unknown);
+    }
+}
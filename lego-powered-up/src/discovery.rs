@@ -0,0 +1,198 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Continuous discovery, keeping track of which hubs are in range
+
+use crate::error::Result;
+use crate::{discovered_hub, DiscoveredHub, PoweredUp, ScanGuard};
+use btleplug::api::{Central, CentralEvent, Peripheral as _, ScanFilter};
+use btleplug::platform::{Adapter, PeripheralId};
+use futures::stream::{Stream, StreamExt};
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::time::Duration;
+use tokio::time::{Instant, MissedTickBehavior};
+
+/// A change in the hubs in range
+#[derive(Clone, Debug, PartialEq)]
+pub enum DiscoveryEvent {
+    /// A hub has come into range
+    Found(DiscoveredHub),
+    /// A hub's signal strength, name or advertisement has changed
+    Updated(DiscoveredHub),
+    /// A hub hasn't been heard from for the staleness timeout. It is
+    /// found again if it reappears.
+    Lost(DiscoveredHub),
+}
+
+/// Things seen recently, by key
+#[derive(Debug)]
+struct Tracker<K, T> {
+    seen: HashMap<K, (T, Instant)>,
+    stale_after: Duration,
+}
+
+#[derive(Debug, PartialEq)]
+enum Change<T> {
+    Found(T),
+    Updated(T),
+    Lost(T),
+}
+
+impl<K: Hash + Eq, T: Clone + PartialEq> Tracker<K, T> {
+    fn new(stale_after: Duration) -> Self {
+        Self {
+            seen: HashMap::new(),
+            stale_after,
+        }
+    }
+
+    /// Record a sighting, returning what changed
+    fn seen(&mut self, key: K, item: T, now: Instant) -> Option<Change<T>> {
+        match self.seen.insert(key, (item.clone(), now)) {
+            None => Some(Change::Found(item)),
+            Some((previous, _)) if previous != item => {
+                Some(Change::Updated(item))
+            }
+            Some(_) => None,
+        }
+    }
+
+    /// Forget everything not seen within the staleness timeout
+    fn expire(&mut self, now: Instant) -> Vec<Change<T>> {
+        let mut lost = Vec::new();
+        self.seen.retain(|_, (item, last_seen)| {
+            let stale = now.duration_since(*last_seen) >= self.stale_after;
+            if stale {
+                lost.push(Change::Lost(item.clone()));
+            }
+            !stale
+        });
+        lost
+    }
+}
+
+impl From<Change<DiscoveredHub>> for DiscoveryEvent {
+    fn from(change: Change<DiscoveredHub>) -> Self {
+        match change {
+            Change::Found(hub) => DiscoveryEvent::Found(hub),
+            Change::Updated(hub) => DiscoveryEvent::Updated(hub),
+            Change::Lost(hub) => DiscoveryEvent::Lost(hub),
+        }
+    }
+}
+
+impl PoweredUp {
+    /// Scan continuously, reporting hubs as they come into range, change
+    /// and go out of range. A hub is lost once nothing has been heard
+    /// from it for `stale_after`. Scanning stops when the stream is
+    /// dropped.
+    ///
+    /// Whether an unchanged hub keeps being heard from depends on the
+    /// platform; most report advertisements whenever the signal strength
+    /// changes, which it does constantly.
+    pub async fn discover(
+        &mut self,
+        stale_after: Duration,
+    ) -> Result<impl Stream<Item = DiscoveryEvent> + '_> {
+        let events = self.adapter.events().await?;
+        self.adapter.start_scan(ScanFilter::default()).await?;
+        let adapter = self.adapter.clone();
+        let scan = ScanGuard(Some(adapter.clone()));
+
+        let mut ticks = tokio::time::interval(
+            (stale_after / 4).max(Duration::from_millis(100)),
+        );
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let state = (
+            adapter,
+            events,
+            ticks,
+            Tracker::<PeripheralId, DiscoveredHub>::new(stale_after),
+            VecDeque::new(),
+            scan,
+        );
+        Ok(futures::stream::unfold(
+            state,
+            move |mut state| async move {
+                let (adapter, events, ticks, tracker, pending, _scan) =
+                    &mut state;
+                loop {
+                    if let Some(event) = pending.pop_front() {
+                        return Some((event, state));
+                    }
+                    tokio::select! {
+                        event = events.next() => {
+                            let (CentralEvent::DeviceDiscovered(id)
+                            | CentralEvent::DeviceUpdated(id)) = event?
+                            else {
+                                continue;
+                            };
+                            let Some(hub) = hub(adapter, &id).await else {
+                                continue;
+                            };
+                            if let Some(change) =
+                                tracker.seen(id, hub, Instant::now())
+                            {
+                                pending.push_back(change.into());
+                            }
+                        }
+                        now = ticks.tick() => {
+                            pending.extend(
+                                tracker.expire(now).into_iter().map(Into::into),
+                            );
+                        }
+                    }
+                }
+            },
+        ))
+    }
+}
+
+/// Describe a peripheral, if it is a hub
+async fn hub(adapter: &Adapter, id: &PeripheralId) -> Option<DiscoveredHub> {
+    let peripheral = adapter.peripheral(id).await.ok()?;
+    let props = peripheral.properties().await.ok()??;
+    discovered_hub(id.clone(), props).await.ok()?
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn found_updated_lost() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut tracker = Tracker::new(Duration::from_secs(1));
+
+        assert_eq!(
+            tracker.seen("a", ("crane", -60), at(0)),
+            Some(Change::Found(("crane", -60)))
+        );
+        assert_eq!(tracker.seen("a", ("crane", -60), at(100)), None);
+        assert_eq!(
+            tracker.seen("b", ("digger", -70), at(200)),
+            Some(Change::Found(("digger", -70)))
+        );
+        assert_eq!(
+            tracker.seen("a", ("crane", -55), at(900)),
+            Some(Change::Updated(("crane", -55)))
+        );
+
+        assert!(tracker.expire(at(1100)).is_empty());
+        assert_eq!(
+            tracker.expire(at(1200)),
+            vec![Change::Lost(("digger", -70))]
+        );
+        assert_eq!(
+            tracker.seen("b", ("digger", -70), at(1300)),
+            Some(Change::Found(("digger", -70)))
+        );
+        assert_eq!(
+            tracker.expire(at(1900)),
+            vec![Change::Lost(("crane", -55))]
+        );
+    }
+}
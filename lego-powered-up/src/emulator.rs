@@ -0,0 +1,1557 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The hub side of the LEGO Wireless Protocol, for building devices and
+//! bridges which apps see as a Powered Up hub.
+//!
+//! `HubEmulator` is a state machine: feed it the messages a central
+//! writes and the passing of time, and it keeps the state of its ports
+//! and queues the notifications a Technic hub would send in reply. The
+//! radio side is a `Backend`, which advertises the hub and carries
+//! messages to and from the central. `serve` joins the two together.
+//!
+//! btleplug can only act as a central, so the library provides just the
+//! in-memory backend from `memory_backend`, whose central end is a
+//! `Transport` the library's own hubs can use. Peripheral support on a
+//! platform, such as BlueZ's GATT server, can be plugged in by
+//! implementing `Backend`.
+//!
+//! ```no_run
+//! # async fn example() -> lego_powered_up::Result<()> {
+//! use lego_powered_up::consts::DeviceType;
+//! use lego_powered_up::emulator::{memory_backend, serve, HubEmulator};
+//! use lego_powered_up::hubs::TechnicHub;
+//! use std::sync::Arc;
+//!
+//! let mut emulator = HubEmulator::new("Emulated hub");
+//! emulator.attach(0, DeviceType::TechnicLargeLinearMotor);
+//! let properties = emulator.properties();
+//! let (backend, central) = memory_backend();
+//! tokio::spawn(serve(emulator, backend));
+//!
+//! central.connect().await?;
+//! let hub = TechnicHub::init(Arc::new(central), properties).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::advertisement::{Advertisement, HubCapabilities, HubStatus};
+use crate::consts::bleservice::LPF2_HUB;
+use crate::consts::{
+    BLEManufacturerData, DeviceType, HubPropertyOperation,
+    HubPropertyReference, MessageType,
+};
+use crate::error::{Error, Result};
+use crate::hubs::HubProperties;
+use crate::notifications::*;
+use crate::transport::{broadcast_stream, MessageStream, Transport};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::time::{Instant, MissedTickBehavior};
+use uuid::Uuid;
+
+/// How often motors are moved and port values sent
+pub(crate) const TICK: Duration = Duration::from_millis(10);
+/// Rate at which an emulated motor turns at 100% speed
+const DEGREES_PER_SECOND: f64 = 1000.0;
+/// Raw battery voltage reading, about 8.2V
+const VOLTAGE_RAW: u16 = 3500;
+/// Raw current drawn by the hub with every motor stopped
+const IDLE_CURRENT_RAW: u16 = 100;
+/// Raw acceleration of a hub lying flat, 1g downwards
+const ACCELERATION_RAW: [i16; 3] = [0, 0, 4096];
+/// Raw angular rate of a hub at rest
+const ANGULAR_RATE_RAW: [i16; 3] = [0, 0, 0];
+/// Raw hub temperature, 25°C
+const TEMPERATURE_RAW: i16 = 250;
+/// First port id given to virtual ports, as real hubs do
+const FIRST_VIRTUAL_PORT: u8 = 0x10;
+const REVISION: VersionNumber = VersionNumber {
+    major: 1,
+    minor: 0,
+    bugfix: 0,
+    build: 0,
+};
+
+const MAC_ADDRESS: [u8; 6] = [0x90, 0x84, 0x2b, 0x00, 0x00, 0x01];
+const RSSI: i8 = -50;
+const TECHNIC_MEDIUM_HUB_SYSTEM_TYPE: u8 =
+    BLEManufacturerData::TechnicMediumHubId as u8;
+
+fn format_mac(mac: &[u8; 6]) -> String {
+    mac.iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// What an emulated device can do
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    /// Motor with an encoder
+    TachoMotor,
+    /// Motor driven by power alone
+    Motor,
+    Voltage,
+    Current,
+    Accelerometer,
+    Gyro,
+    Temperature,
+    /// Announced, but without any modes
+    Other,
+}
+
+impl Kind {
+    fn of(device_type: DeviceType) -> Self {
+        use DeviceType::*;
+        match device_type {
+            MediumLinearMotor
+            | MoveHubMediumLinearMotor
+            | TechnicLargeLinearMotor
+            | TechnicXlargeLinearMotor
+            | TechnicMediumAngularMotor
+            | TechnicLargeAngularMotor
+            | TechnicMediumAngularMotorGrey
+            | TechnicLargeAngularMotorGrey => Kind::TachoMotor,
+            SimpleMediumLinearMotor | TrainMotor | DuploTrainBaseMotor => {
+                Kind::Motor
+            }
+            VoltageSensor => Kind::Voltage,
+            CurrentSensor => Kind::Current,
+            TechnicMediumHubAccelerometer => Kind::Accelerometer,
+            TechnicMediumHubGyroSensor => Kind::Gyro,
+            TechnicMediumHubTemperatureSensor => Kind::Temperature,
+            _ => Kind::Other,
+        }
+    }
+
+    fn modes(&self) -> &'static [ModeSpec] {
+        match self {
+            Kind::TachoMotor => &TACHO_MOTOR_MODES,
+            Kind::Motor => &TACHO_MOTOR_MODES[..1],
+            Kind::Voltage => &VOLTAGE_MODES,
+            Kind::Current => &CURRENT_MODES,
+            Kind::Accelerometer => &ACCELEROMETER_MODES,
+            Kind::Gyro => &GYRO_MODES,
+            Kind::Temperature => &TEMPERATURE_MODES,
+            Kind::Other => &[],
+        }
+    }
+
+    /// Capabilities, and the input and output modes as bitmasks
+    fn port_info(&self) -> (u8, u16, u16) {
+        match self {
+            Kind::TachoMotor => (
+                PortCapabilities::OUTPUT
+                    | PortCapabilities::INPUT
+                    | PortCapabilities::LOGICAL_COMBINABLE
+                    | PortCapabilities::LOGICAL_SYNCHRONIZABLE,
+                0b1110,
+                0b0001,
+            ),
+            Kind::Motor => (PortCapabilities::OUTPUT, 0, 0b0001),
+            Kind::Voltage
+            | Kind::Current
+            | Kind::Accelerometer
+            | Kind::Gyro
+            | Kind::Temperature => (PortCapabilities::INPUT, 1, 0),
+            Kind::Other => (0, 0, 0),
+        }
+    }
+
+    fn is_motor(&self) -> bool {
+        matches!(self, Kind::TachoMotor | Kind::Motor)
+    }
+}
+
+/// Everything the hub reports about one mode
+#[derive(Debug)]
+struct ModeSpec {
+    name: &'static str,
+    raw: (f32, f32),
+    pct: (f32, f32),
+    si: (f32, f32),
+    symbol: &'static str,
+    mapping: (u8, u8),
+    datasets: u8,
+    dataset_type: DatasetType,
+    total_figures: u8,
+    decimals: u8,
+}
+
+const TACHO_MOTOR_MODES: [ModeSpec; 4] = [
+    ModeSpec {
+        name: "POWER",
+        raw: (-100.0, 100.0),
+        pct: (-100.0, 100.0),
+        si: (-100.0, 100.0),
+        symbol: "PCT",
+        mapping: (0, MappingValue::ABS),
+        datasets: 1,
+        dataset_type: DatasetType::Bits8,
+        total_figures: 4,
+        decimals: 0,
+    },
+    ModeSpec {
+        name: "SPEED",
+        raw: (-100.0, 100.0),
+        pct: (-100.0, 100.0),
+        si: (-100.0, 100.0),
+        symbol: "PCT",
+        mapping: (MappingValue::ABS, MappingValue::ABS),
+        datasets: 1,
+        dataset_type: DatasetType::Bits8,
+        total_figures: 4,
+        decimals: 0,
+    },
+    ModeSpec {
+        name: "POS",
+        raw: (-360.0, 360.0),
+        pct: (-100.0, 100.0),
+        si: (-360.0, 360.0),
+        symbol: "DEG",
+        mapping: (MappingValue::REL, MappingValue::REL),
+        datasets: 1,
+        dataset_type: DatasetType::Bits32,
+        total_figures: 11,
+        decimals: 0,
+    },
+    ModeSpec {
+        name: "APOS",
+        raw: (-180.0, 179.0),
+        pct: (-200.0, 200.0),
+        si: (-180.0, 179.0),
+        symbol: "DEG",
+        mapping: (MappingValue::ABS, MappingValue::ABS),
+        datasets: 1,
+        dataset_type: DatasetType::Bits16,
+        total_figures: 3,
+        decimals: 0,
+    },
+];
+
+const VOLTAGE_MODES: [ModeSpec; 1] = [ModeSpec {
+    name: "VLT L",
+    raw: (0.0, 4095.0),
+    pct: (0.0, 100.0),
+    si: (0.0, 9620.0),
+    symbol: "mV",
+    mapping: (MappingValue::ABS, 0),
+    datasets: 1,
+    dataset_type: DatasetType::Bits16,
+    total_figures: 4,
+    decimals: 0,
+}];
+
+const CURRENT_MODES: [ModeSpec; 1] = [ModeSpec {
+    name: "CUR L",
+    raw: (0.0, 4095.0),
+    pct: (0.0, 100.0),
+    si: (0.0, 4175.0),
+    symbol: "mA",
+    mapping: (MappingValue::ABS, 0),
+    datasets: 1,
+    dataset_type: DatasetType::Bits16,
+    total_figures: 4,
+    decimals: 0,
+}];
+
+const ACCELEROMETER_MODES: [ModeSpec; 1] = [ModeSpec {
+    name: "GRV",
+    raw: (-32768.0, 32768.0),
+    pct: (-100.0, 100.0),
+    si: (-8000.0, 8000.0),
+    symbol: "mG",
+    mapping: (MappingValue::ABS, 0),
+    datasets: 3,
+    dataset_type: DatasetType::Bits16,
+    total_figures: 5,
+    decimals: 0,
+}];
+
+const GYRO_MODES: [ModeSpec; 1] = [ModeSpec {
+    name: "ROT",
+    raw: (-28571.4, 28571.4),
+    pct: (-100.0, 100.0),
+    si: (-2000.0, 2000.0),
+    symbol: "DPS",
+    mapping: (MappingValue::ABS, 0),
+    datasets: 3,
+    dataset_type: DatasetType::Bits16,
+    total_figures: 5,
+    decimals: 0,
+}];
+
+const TEMPERATURE_MODES: [ModeSpec; 1] = [ModeSpec {
+    name: "TEMP",
+    raw: (-900.0, 900.0),
+    pct: (-100.0, 100.0),
+    si: (-90.0, 90.0),
+    symbol: "DEG",
+    mapping: (MappingValue::ABS, 0),
+    datasets: 1,
+    dataset_type: DatasetType::Bits16,
+    total_figures: 5,
+    decimals: 1,
+}];
+
+/// Where a motor is heading
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Goal {
+    /// Keep turning until told otherwise
+    None,
+    /// Stop on reaching a position
+    Position(f64),
+    /// Stop once this much time has passed
+    Time(Duration),
+}
+
+/// What an output command asks of one motor
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Action {
+    Speed(i8),
+    Timed(i8, Duration),
+    /// Turn this many degrees, in the direction of the speed
+    Degrees(i32, i8),
+    Goto(i32, i8),
+    Preset(i32),
+}
+
+/// Value reporting set up by a PortInputFormatSetupSingle message
+#[derive(Copy, Clone, Debug)]
+struct InputFormat {
+    mode: u8,
+    delta: u32,
+    enabled: bool,
+    /// Last value sent, to compare against the delta
+    last_sent: Option<i64>,
+}
+
+#[derive(Clone, Debug)]
+struct EmulatedPort {
+    device_type: DeviceType,
+    /// Type id announced for the device, which needn't be a known type
+    io_type_id: u16,
+    /// The two ports making up a virtual port
+    members: Option<(u8, u8)>,
+    input: Option<InputFormat>,
+    speed: i8,
+    position: f64,
+    goal: Goal,
+    /// A command is running, and feedback is owed once it finishes
+    busy: bool,
+    /// Amount the encoder has been preset by, which moves the position
+    /// but not the absolute position or the end stops
+    preset: f64,
+    /// Absolute positions beyond which the mechanism can't turn
+    end_stops: Option<(f64, f64)>,
+    /// Driven against an end stop
+    blocked: bool,
+}
+
+impl EmulatedPort {
+    fn new(device_type: DeviceType) -> Self {
+        Self {
+            device_type,
+            io_type_id: device_type as u16,
+            members: None,
+            input: None,
+            speed: 0,
+            position: 0.0,
+            goal: Goal::None,
+            busy: false,
+            preset: 0.0,
+            end_stops: None,
+            blocked: false,
+        }
+    }
+
+    fn kind(&self) -> Kind {
+        Kind::of(self.device_type)
+    }
+
+    /// Move for `elapsed`, stopping if that reaches the goal
+    fn step(&mut self, elapsed: Duration) {
+        let travel = self.speed as f64 / 100.0
+            * DEGREES_PER_SECOND
+            * elapsed.as_secs_f64();
+        self.position += travel;
+        self.blocked = false;
+        if let Some((min, max)) = self.end_stops {
+            let absolute = self.position - self.preset;
+            if absolute < min || absolute > max {
+                self.position = absolute.clamp(min, max) + self.preset;
+                self.blocked = self.speed != 0;
+            }
+        }
+        match self.goal {
+            Goal::None => {}
+            Goal::Position(target) => {
+                if (self.speed >= 0 && self.position >= target)
+                    || (self.speed <= 0 && self.position <= target)
+                {
+                    self.position = target;
+                    self.stop();
+                }
+            }
+            Goal::Time(remaining) => {
+                let remaining = remaining.saturating_sub(elapsed);
+                self.goal = Goal::Time(remaining);
+                if remaining.is_zero() {
+                    self.stop();
+                }
+            }
+        }
+    }
+
+    fn stop(&mut self) {
+        self.speed = 0;
+        self.goal = Goal::None;
+        self.blocked = false;
+    }
+
+    /// Speed the motor is actually turning at
+    fn actual_speed(&self) -> i8 {
+        if self.blocked {
+            0
+        } else {
+            self.speed
+        }
+    }
+
+    fn apply(&mut self, action: Action) {
+        match action {
+            Action::Speed(speed) => {
+                self.stop();
+                self.speed = speed;
+            }
+            Action::Timed(speed, time) => {
+                self.speed = speed;
+                self.goal = Goal::Time(time);
+            }
+            Action::Degrees(degrees, speed) => {
+                let direction = speed.signum() as f64;
+                self.goto(
+                    self.position + degrees.unsigned_abs() as f64 * direction,
+                    speed,
+                );
+            }
+            Action::Goto(position, speed) => self.goto(position as f64, speed),
+            Action::Preset(position) => {
+                self.preset += position as f64 - self.position;
+                self.position = position as f64;
+            }
+        }
+    }
+
+    /// Head for `target` at `speed`, whichever way round that is
+    fn goto(&mut self, target: f64, speed: i8) {
+        let speed = speed.saturating_abs();
+        self.speed = if target >= self.position {
+            speed
+        } else {
+            -speed
+        };
+        self.goal = Goal::Position(target);
+    }
+
+    fn idle(&self) -> bool {
+        self.goal == Goal::None
+    }
+}
+
+/// What an emulated hub advertises while waiting for a connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AdvertisementData {
+    pub name: String,
+    /// Service which marks the device as an LWP3 hub
+    pub service: Uuid,
+    /// Manufacturer data under LEGO's company id, 919
+    pub manufacturer_data: Vec<u8>,
+}
+
+/// The state of one port, for devices built on the emulator
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PortState {
+    pub device_type: DeviceType,
+    /// Speed of a motor, in percent
+    pub speed: i8,
+    /// Position of a motor, in degrees
+    pub position: i32,
+    /// Mode the central has set up for reading, if any
+    pub input_mode: Option<u8>,
+}
+
+/// The hub side of a connection: port state, and the notifications
+/// waiting to be sent. Emulates a Technic hub with its own sensors
+/// attached.
+#[derive(Clone, Debug)]
+pub struct HubEmulator {
+    name: Vec<u8>,
+    ports: BTreeMap<u8, EmulatedPort>,
+    /// Attached devices have been reported since the central connected
+    announced: bool,
+    button: bool,
+    /// Properties the central asked to be told about when they change
+    updates: Vec<HubPropertyReference>,
+    outbox: Vec<NotificationMessage>,
+}
+
+impl HubEmulator {
+    pub fn new(name: &str) -> Self {
+        let devices = [
+            (50, DeviceType::HubLed),
+            (59, DeviceType::CurrentSensor),
+            (60, DeviceType::VoltageSensor),
+            (96, DeviceType::TechnicMediumHubTemperatureSensor),
+            (97, DeviceType::TechnicMediumHubAccelerometer),
+            (98, DeviceType::TechnicMediumHubGyroSensor),
+            (99, DeviceType::TechnicMediumHubTiltSensor),
+            (100, DeviceType::TechnicMediumHubGestSensor),
+        ];
+        Self {
+            name: name.as_bytes().to_vec(),
+            ports: devices
+                .into_iter()
+                .map(|(port_id, device_type)| {
+                    (port_id, EmulatedPort::new(device_type))
+                })
+                .collect(),
+            announced: false,
+            button: false,
+            updates: Vec::new(),
+            outbox: Vec::new(),
+        }
+    }
+
+    /// The advertising name, which the central may change
+    pub fn name(&self) -> String {
+        String::from_utf8_lossy(&self.name).into_owned()
+    }
+
+    /// What discovery would tell a central about the hub, for passing
+    /// to `TechnicHub::init`
+    pub fn properties(&self) -> HubProperties {
+        HubProperties {
+            name: self.name(),
+            mac_address: format_mac(&MAC_ADDRESS),
+            rssi: RSSI as i16,
+            ..Default::default()
+        }
+    }
+
+    pub fn advertisement(&self) -> AdvertisementData {
+        AdvertisementData {
+            name: self.name(),
+            service: *LPF2_HUB,
+            manufacturer_data: Advertisement {
+                button_pressed: self.button,
+                system_type: TECHNIC_MEDIUM_HUB_SYSTEM_TYPE,
+                capabilities: HubCapabilities(
+                    HubCapabilities::PERIPHERAL_ROLE
+                        | HubCapabilities::LPF2_DEVICES,
+                ),
+                last_network_id: 0,
+                status: HubStatus(HubStatus::CAN_BE_PERIPHERAL),
+                options: 0,
+            }
+            .serialise(),
+        }
+    }
+
+    /// The state of the device on a port
+    pub fn port(&self, port_id: u8) -> Option<PortState> {
+        self.ports.get(&port_id).map(|port| PortState {
+            device_type: port.device_type,
+            speed: port.speed,
+            position: port.position.round() as i32,
+            input_mode: port.input.map(|input| input.mode),
+        })
+    }
+
+    /// Plug a device into a port. Ports A to D have ids 0 to 3.
+    pub fn attach(&mut self, port_id: u8, device_type: DeviceType) {
+        self.attach_port(port_id, EmulatedPort::new(device_type));
+    }
+
+    /// Plug in a device of a type the library doesn't recognise, which
+    /// is announced with `io_type_id` and has no modes
+    pub fn attach_io_type(&mut self, port_id: u8, io_type_id: u16) {
+        let mut port = EmulatedPort::new(DeviceType::Unknown);
+        port.io_type_id = io_type_id;
+        self.attach_port(port_id, port);
+    }
+
+    fn attach_port(&mut self, port_id: u8, port: EmulatedPort) {
+        let io_type_id = port.io_type_id;
+        self.ports.insert(port_id, port);
+        if self.announced {
+            self.attached_io(port_id, Self::attached_event(io_type_id));
+        }
+    }
+
+    /// Unplug the device on a port
+    pub fn detach(&mut self, port_id: u8) {
+        if self.ports.remove(&port_id).is_some() && self.announced {
+            self.attached_io(port_id, IoAttachEvent::DetachedIo);
+        }
+    }
+
+    /// Stop a motor turning beyond `min` and `max` degrees, as when it
+    /// drives a mechanism with limited travel. A motor driven against
+    /// an end stop reports a speed of zero and draws more current.
+    pub fn set_end_stops(&mut self, port_id: u8, min: i32, max: i32) {
+        if let Some(port) = self.ports.get_mut(&port_id) {
+            port.end_stops = Some((min as f64, max as f64));
+        }
+    }
+
+    /// Press or release the hub's button
+    pub fn set_button(&mut self, pressed: bool) {
+        if self.button != pressed {
+            self.button = pressed;
+            self.property_changed(HubPropertyReference::Button);
+        }
+    }
+
+    /// The central enabled notifications. Every attached device is
+    /// reported, as a hub does the first time.
+    pub fn subscribe(&mut self) {
+        if self.announced {
+            return;
+        }
+        self.announced = true;
+        let attached: Vec<_> = self
+            .ports
+            .iter()
+            .filter(|(_, port)| port.members.is_none())
+            .map(|(port_id, port)| (*port_id, port.io_type_id))
+            .collect();
+        for (port_id, io_type_id) in attached {
+            self.attached_io(port_id, Self::attached_event(io_type_id));
+        }
+    }
+
+    /// The central went away. Motors stop, and the set up of ports and
+    /// property updates is forgotten, ready for the next connection.
+    pub fn disconnect(&mut self) {
+        self.announced = false;
+        self.updates.clear();
+        self.outbox.clear();
+        self.ports.retain(|_, port| port.members.is_none());
+        for port in self.ports.values_mut() {
+            port.stop();
+            port.input = None;
+            port.busy = false;
+        }
+    }
+
+    /// Take the notifications waiting to be sent to the central
+    pub fn notifications(
+        &mut self,
+    ) -> impl Iterator<Item = NotificationMessage> + '_ {
+        self.outbox.drain(..)
+    }
+
+    fn send(&mut self, msg: NotificationMessage) {
+        self.outbox.push(msg);
+    }
+
+    fn error(&mut self, command_type: u8, error_code: ErrorCode) {
+        self.send(NotificationMessage::GenericErrorMessages(
+            ErrorMessageFormat {
+                command_type,
+                error_code,
+            },
+        ));
+    }
+
+    fn feedback(&mut self, port_id: u8, completed: bool, discarded: bool) {
+        self.send(NotificationMessage::PortOutputCommandFeedback(
+            PortOutputCommandFeedbackFormat {
+                msg1: FeedbackMessage {
+                    port_id,
+                    empty_cmd_in_progress: !completed,
+                    empty_cmd_completed: completed,
+                    discarded,
+                    idle: completed,
+                    busy_full: false,
+                },
+                msg2: None,
+                msg3: None,
+            },
+        ));
+    }
+
+    fn attached_io(&mut self, port: u8, event: IoAttachEvent) {
+        self.send(NotificationMessage::HubAttachedIo(AttachedIo {
+            port,
+            event,
+        }));
+    }
+
+    fn attached_event(io_type_id: u16) -> IoAttachEvent {
+        IoAttachEvent::AttachedIo {
+            io_type_id,
+            hw_rev: REVISION,
+            fw_rev: REVISION,
+        }
+    }
+
+    /// Handle a message written by the central
+    pub fn handle(&mut self, msg: &[u8]) {
+        // Property requests carry no value, which the parser can't
+        // represent, so pick them out first
+        if msg.get(2) == Some(&(MessageType::HubProperties as u8)) {
+            return self.hub_property(msg);
+        }
+        let command_type = msg.get(2).copied().unwrap_or_default();
+        match NotificationMessage::parse(msg) {
+            Ok(NotificationMessage::PortInformationRequest(req)) => {
+                self.port_information(req)
+            }
+            Ok(NotificationMessage::PortModeInformationRequest(req)) => {
+                self.mode_information(req)
+            }
+            Ok(NotificationMessage::PortInputFormatSetupSingle(setup)) => {
+                self.input_setup(setup)
+            }
+            Ok(NotificationMessage::VirtualPortSetup(setup)) => {
+                self.virtual_port_setup(setup)
+            }
+            Ok(NotificationMessage::PortOutputCommand(cmd)) => {
+                self.output_command(cmd)
+            }
+            Ok(NotificationMessage::HubActions(_)) => {}
+            Ok(other) => {
+                debug!("Emulated hub ignoring {:?}", other);
+                self.error(command_type, ErrorCode::CommandNotRecognized);
+            }
+            Err(e) => {
+                debug!("Emulated hub can't parse {:02x?}: {}", msg, e);
+                self.error(command_type, ErrorCode::CommandNotRecognized);
+            }
+        }
+    }
+
+    fn hub_property(&mut self, msg: &[u8]) {
+        use num_traits::FromPrimitive;
+
+        let command_type = MessageType::HubProperties as u8;
+        let (Some(reference), Some(operation)) = (
+            msg.get(3).copied().and_then(HubPropertyReference::from_u8),
+            msg.get(4).copied().and_then(HubPropertyOperation::from_u8),
+        ) else {
+            return self.error(command_type, ErrorCode::InvalidUse);
+        };
+        match operation {
+            HubPropertyOperation::SetDownstream
+                if reference == HubPropertyReference::AdvertisingName =>
+            {
+                self.name = msg[5..].to_vec();
+                self.property_changed(reference);
+            }
+            HubPropertyOperation::RequestUpdateDownstream
+            | HubPropertyOperation::EnableUpdatesDownstream => {
+                let Some(property) = self.property(reference) else {
+                    return self.error(command_type, ErrorCode::InvalidUse);
+                };
+                if operation == HubPropertyOperation::EnableUpdatesDownstream
+                    && !self.updates.contains(&reference)
+                {
+                    self.updates.push(reference);
+                }
+                self.send(NotificationMessage::HubProperties(HubProperty {
+                    property,
+                    operation: HubPropertyOperation::UpdateUpstream,
+                }));
+            }
+            HubPropertyOperation::DisableUpdatesDownstream => {
+                self.updates.retain(|update| *update != reference);
+            }
+            _ => self.error(command_type, ErrorCode::InvalidUse),
+        }
+    }
+
+    /// Send a property if the central has enabled updates of it
+    fn property_changed(&mut self, reference: HubPropertyReference) {
+        if !self.updates.contains(&reference) {
+            return;
+        }
+        if let Some(property) = self.property(reference) {
+            self.send(NotificationMessage::HubProperties(HubProperty {
+                property,
+                operation: HubPropertyOperation::UpdateUpstream,
+            }));
+        }
+    }
+
+    fn property(
+        &self,
+        reference: HubPropertyReference,
+    ) -> Option<HubPropertyValue> {
+        use HubPropertyValue::*;
+
+        Some(match reference {
+            HubPropertyReference::AdvertisingName => {
+                AdvertisingName(self.name.clone())
+            }
+            HubPropertyReference::Button => Button(self.button as u8),
+            HubPropertyReference::FwVersion => FwVersion(0x1100_0000),
+            HubPropertyReference::HwVersion => HwVersion(0x0100_0000),
+            HubPropertyReference::Rssi => Rssi(RSSI),
+            HubPropertyReference::BatteryVoltage => BatteryVoltage(100),
+            HubPropertyReference::BatteryType => {
+                BatteryType(HubBatteryType::Normal)
+            }
+            HubPropertyReference::ManufacturerName => {
+                ManufacturerName(b"LEGO System A/S".to_vec())
+            }
+            HubPropertyReference::RadioFirmwareVersion => {
+                RadioFirmwareVersion(b"2_02_01".to_vec())
+            }
+            HubPropertyReference::LegoWirelessProtocolVersion => {
+                LegoWirelessProtocolVersion(0x0300)
+            }
+            HubPropertyReference::SystemTypeId => {
+                SystemTypeId(TECHNIC_MEDIUM_HUB_SYSTEM_TYPE)
+            }
+            HubPropertyReference::HwNetworkId => HwNetworkId(0),
+            HubPropertyReference::PrimaryMacAddress => {
+                PrimaryMacAddress(MAC_ADDRESS)
+            }
+            HubPropertyReference::SecondaryMacAddress
+            | HubPropertyReference::HardwareNetworkFamily => return None,
+        })
+    }
+
+    fn port_information(&mut self, req: InformationRequest) {
+        let command_type = MessageType::PortInformationRequest as u8;
+        let Some(port) = self.ports.get(&req.port_id) else {
+            return self.error(command_type, ErrorCode::InvalidUse);
+        };
+        let kind = port.kind();
+        let (capabilities, input_modes, output_modes) = kind.port_info();
+        let information_type = match req.information_type {
+            InformationType::PortValue => {
+                let mode = port.input.map(|input| input.mode).unwrap_or(0);
+                return match self.reading(req.port_id, mode) {
+                    Some((_, data)) => {
+                        self.send(NotificationMessage::PortValueSingle(
+                            PortValueSingleFormat::new(req.port_id, &data),
+                        ))
+                    }
+                    None => self.error(command_type, ErrorCode::InvalidUse),
+                };
+            }
+            InformationType::ModeInfo => PortInformationType::ModeInfo {
+                capabilities: PortCapabilities(capabilities),
+                mode_count: kind.modes().len() as u8,
+                input_modes,
+                output_modes,
+            },
+            InformationType::PossibleModeCombinations => {
+                if capabilities & PortCapabilities::LOGICAL_COMBINABLE == 0 {
+                    return self.error(command_type, ErrorCode::InvalidUse);
+                }
+                let mut combinations = input_modes.to_le_bytes().to_vec();
+                combinations.extend_from_slice(&[0, 0]);
+                PortInformationType::PossibleModeCombinations(combinations)
+            }
+        };
+        self.send(NotificationMessage::PortInformation(PortInformationValue {
+            port_id: req.port_id,
+            information_type,
+        }));
+    }
+
+    fn mode_information(&mut self, req: ModeInformationRequest) {
+        let command_type = MessageType::PortModeInformationRequest as u8;
+        let spec = self
+            .ports
+            .get(&req.port_id)
+            .and_then(|port| port.kind().modes().get(req.mode as usize));
+        let Some(spec) = spec else {
+            return self.error(command_type, ErrorCode::InvalidUse);
+        };
+        let information_type = match req.information_type {
+            ModeInformationType::Name => {
+                PortModeInformationType::Name(spec.name.as_bytes().to_vec())
+            }
+            ModeInformationType::Raw => PortModeInformationType::RawRange {
+                min: spec.raw.0,
+                max: spec.raw.1,
+            },
+            ModeInformationType::Pct => PortModeInformationType::PctRange {
+                min: spec.pct.0,
+                max: spec.pct.1,
+            },
+            ModeInformationType::Si => PortModeInformationType::SiRange {
+                min: spec.si.0,
+                max: spec.si.1,
+            },
+            ModeInformationType::Symbol => {
+                PortModeInformationType::Symbol(spec.symbol.as_bytes().to_vec())
+            }
+            ModeInformationType::Mapping => PortModeInformationType::Mapping {
+                input: MappingValue(spec.mapping.0),
+                output: MappingValue(spec.mapping.1),
+            },
+            ModeInformationType::ValueFormat => {
+                PortModeInformationType::ValueFormat(ValueFormatType {
+                    number_of_datasets: spec.datasets,
+                    dataset_type: spec.dataset_type,
+                    total_figures: spec.total_figures,
+                    decimals: spec.decimals,
+                })
+            }
+            ModeInformationType::UsedInternally
+            | ModeInformationType::MotorBias
+            | ModeInformationType::CapabilityBits => {
+                return self.error(command_type, ErrorCode::InvalidUse)
+            }
+        };
+        self.send(NotificationMessage::PortModeInformation(
+            PortModeInformationValue {
+                port_id: req.port_id,
+                mode: req.mode,
+                information_type,
+            },
+        ));
+    }
+
+    fn input_setup(&mut self, setup: InputSetupSingle) {
+        let command_type = MessageType::PortInputFormatSetupSingle as u8;
+        let Some(port) = self.ports.get_mut(&setup.port_id) else {
+            return self.error(command_type, ErrorCode::InvalidUse);
+        };
+        if setup.mode as usize >= port.kind().modes().len() {
+            return self.error(command_type, ErrorCode::InvalidUse);
+        }
+        port.input = Some(InputFormat {
+            mode: setup.mode,
+            delta: setup.delta,
+            enabled: setup.notification_enabled,
+            last_sent: None,
+        });
+        self.send(NotificationMessage::PortInputFormatSingle(
+            PortInputFormatSingleFormat {
+                port_id: setup.port_id,
+                mode: setup.mode,
+                delta: setup.delta,
+                notification_enabled: setup.notification_enabled,
+            },
+        ));
+        // Hubs send the current value straight away
+        self.report(setup.port_id);
+    }
+
+    fn virtual_port_setup(&mut self, setup: VirtualPortSetupFormat) {
+        let command_type = MessageType::VirtualPortSetup as u8;
+        match setup {
+            VirtualPortSetupFormat::Connect { port_a, port_b } => {
+                let device_type =
+                    match (self.ports.get(&port_a), self.ports.get(&port_b)) {
+                        (Some(a), Some(b))
+                            if a.kind().is_motor() && b.kind().is_motor() =>
+                        {
+                            a.device_type
+                        }
+                        _ => {
+                            return self
+                                .error(command_type, ErrorCode::InvalidUse)
+                        }
+                    };
+                let port_id = (FIRST_VIRTUAL_PORT..=u8::MAX)
+                    .find(|id| !self.ports.contains_key(id))
+                    .unwrap_or(u8::MAX);
+                let mut port = EmulatedPort::new(device_type);
+                port.members = Some((port_a, port_b));
+                self.ports.insert(port_id, port);
+                self.attached_io(
+                    port_id,
+                    IoAttachEvent::AttachedVirtualIo { port_a, port_b },
+                );
+            }
+            VirtualPortSetupFormat::Disconnect { port_id } => {
+                match self.ports.get(&port_id) {
+                    Some(port) if port.members.is_some() => {
+                        self.ports.remove(&port_id);
+                        self.attached_io(port_id, IoAttachEvent::DetachedIo);
+                    }
+                    _ => self.error(command_type, ErrorCode::InvalidUse),
+                }
+            }
+        }
+    }
+
+    fn output_command(&mut self, cmd: PortOutputCommandFormat) {
+        use PortOutputSubcommand::*;
+
+        let command_type = MessageType::PortOutputCommand as u8;
+        let port_id = cmd.port_id;
+        let Some(port) = self.ports.get(&port_id) else {
+            return self.error(command_type, ErrorCode::InvalidUse);
+        };
+        if !port.kind().is_motor() {
+            // Accept and forget commands for lights, LEDs and so on
+            return self.feedback(port_id, true, false);
+        }
+        let was_busy = port.busy;
+
+        let actions = match (cmd.subcommand, port.members) {
+            (SetAccTime { .. } | SetDecTime { .. }, _) => Vec::new(),
+            (StartSpeed { speed, .. }, None) => {
+                vec![(port_id, Action::Speed(speed))]
+            }
+            (StartSpeedForTime { time, speed, .. }, None) => {
+                vec![(port_id, Action::Timed(speed, millis(time)))]
+            }
+            (StartSpeedForDegrees { degrees, speed, .. }, None) => {
+                vec![(port_id, Action::Degrees(degrees, speed))]
+            }
+            (GotoAbsolutePosition { abs_pos, speed, .. }, None) => {
+                vec![(port_id, Action::Goto(abs_pos, speed))]
+            }
+            (WriteDirectModeData(payload), None) => match payload {
+                WriteDirectModeDataPayload::StartPower(power) => {
+                    vec![(port_id, Action::Speed(power_to_speed(power)))]
+                }
+                WriteDirectModeDataPayload::PresetEncoder(position) => {
+                    vec![(port_id, Action::Preset(position))]
+                }
+                _ => Vec::new(),
+            },
+            (StartPower2 { power1, power2 }, Some((a, b))) => vec![
+                (a, Action::Speed(power_to_speed(power1))),
+                (b, Action::Speed(power_to_speed(power2))),
+            ],
+            (StartSpeed2 { speed1, speed2, .. }, Some((a, b))) => {
+                vec![(a, Action::Speed(speed1)), (b, Action::Speed(speed2))]
+            }
+            (
+                StartSpeedForTime2 {
+                    time,
+                    speed_l,
+                    speed_r,
+                    ..
+                },
+                Some((a, b)),
+            ) => vec![
+                (a, Action::Timed(speed_l, millis(time))),
+                (b, Action::Timed(speed_r, millis(time))),
+            ],
+            (
+                StartSpeedForDegrees2 {
+                    degrees,
+                    speed_l,
+                    speed_r,
+                    ..
+                },
+                Some((a, b)),
+            ) => vec![
+                (a, Action::Degrees(degrees, speed_l)),
+                (b, Action::Degrees(degrees, speed_r)),
+            ],
+            (
+                GotoAbsolutePosition2 {
+                    abs_pos1,
+                    abs_pos2,
+                    speed,
+                    ..
+                },
+                Some((a, b)),
+            ) => vec![
+                (a, Action::Goto(abs_pos1, speed)),
+                (b, Action::Goto(abs_pos2, speed)),
+            ],
+            (
+                PresetEncoder2 {
+                    left_position,
+                    right_position,
+                },
+                Some((a, b)),
+            ) => vec![
+                (a, Action::Preset(left_position)),
+                (b, Action::Preset(right_position)),
+            ],
+            _ => return self.error(command_type, ErrorCode::InvalidUse),
+        };
+
+        let mut idle = true;
+        for (target, action) in actions {
+            if let Some(port) = self.ports.get_mut(&target) {
+                port.apply(action);
+                idle &= port.idle();
+            }
+        }
+        if let Some(port) = self.ports.get_mut(&port_id) {
+            port.busy = !idle;
+        }
+        self.feedback(port_id, idle, was_busy);
+    }
+
+    /// Move every motor on by `elapsed`, then report what changed
+    pub fn step(&mut self, elapsed: Duration) {
+        for port in self.ports.values_mut() {
+            if port.kind().is_motor() && port.members.is_none() {
+                port.step(elapsed);
+            }
+        }
+
+        let finished: Vec<u8> = self
+            .ports
+            .iter()
+            .filter(|(_, port)| port.busy)
+            .filter(|(_, port)| match port.members {
+                Some((a, b)) => [a, b].iter().all(|id| {
+                    self.ports.get(id).is_none_or(|member| member.idle())
+                }),
+                None => port.idle(),
+            })
+            .map(|(port_id, _)| *port_id)
+            .collect();
+        for port_id in finished {
+            if let Some(port) = self.ports.get_mut(&port_id) {
+                port.busy = false;
+            }
+            self.feedback(port_id, true, false);
+        }
+
+        let port_ids: Vec<u8> = self.ports.keys().copied().collect();
+        for port_id in port_ids {
+            self.report(port_id);
+        }
+    }
+
+    /// Send the value of a port if it has changed by at least the delta
+    fn report(&mut self, port_id: u8) {
+        let Some(input) = self.ports.get(&port_id).and_then(|port| port.input)
+        else {
+            return;
+        };
+        if !input.enabled {
+            return;
+        }
+        let Some((value, data)) = self.reading(port_id, input.mode) else {
+            return;
+        };
+        let changed = match input.last_sent {
+            Some(last) => {
+                (value - last).unsigned_abs() >= input.delta.max(1) as u64
+            }
+            None => true,
+        };
+        if !changed {
+            return;
+        }
+        if let Some(InputFormat { last_sent, .. }) = self
+            .ports
+            .get_mut(&port_id)
+            .and_then(|port| port.input.as_mut())
+        {
+            *last_sent = Some(value);
+        }
+        self.send(NotificationMessage::PortValueSingle(
+            PortValueSingleFormat::new(port_id, &data),
+        ));
+    }
+
+    /// Current value of a mode, as a number and as the bytes the hub
+    /// would send
+    fn reading(&self, port_id: u8, mode: u8) -> Option<(i64, Vec<u8>)> {
+        let port = self.ports.get(&port_id)?;
+        match (port.kind(), mode) {
+            (Kind::TachoMotor | Kind::Motor, 0) | (Kind::TachoMotor, 1) => {
+                let speed = port.actual_speed();
+                Some((speed as i64, speed.to_le_bytes().to_vec()))
+            }
+            (Kind::TachoMotor, 2) => {
+                let position = port.position.round() as i32;
+                Some((position as i64, position.to_le_bytes().to_vec()))
+            }
+            (Kind::TachoMotor, 3) => {
+                let position = (port.position - port.preset).round() as i64;
+                let absolute = ((position + 180).rem_euclid(360) - 180) as i16;
+                Some((absolute as i64, absolute.to_le_bytes().to_vec()))
+            }
+            (Kind::Accelerometer, 0) => Some(triple(ACCELERATION_RAW)),
+            (Kind::Gyro, 0) => Some(triple(ANGULAR_RATE_RAW)),
+            (Kind::Temperature, 0) => Some((
+                TEMPERATURE_RAW as i64,
+                TEMPERATURE_RAW.to_le_bytes().to_vec(),
+            )),
+            (Kind::Voltage, 0) => {
+                Some((VOLTAGE_RAW as i64, VOLTAGE_RAW.to_le_bytes().to_vec()))
+            }
+            (Kind::Current, 0) => {
+                // Each motor draws current in proportion to its speed,
+                // and more when it is stalled
+                let current = self
+                    .ports
+                    .values()
+                    .filter(|port| port.members.is_none())
+                    .map(|port| {
+                        let per_speed = if port.blocked { 40 } else { 10 };
+                        port.speed.unsigned_abs() as u16 * per_speed
+                    })
+                    .sum::<u16>()
+                    .saturating_add(IDLE_CURRENT_RAW)
+                    .min(4095);
+                Some((current as i64, current.to_le_bytes().to_vec()))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A three axis value, as the hub would send it. Only the first axis
+/// counts towards the delta.
+fn triple(raw: [i16; 3]) -> (i64, Vec<u8>) {
+    let data = raw.iter().flat_map(|axis| axis.to_le_bytes()).collect();
+    (raw[0] as i64, data)
+}
+
+fn millis(time: i16) -> Duration {
+    Duration::from_millis(time.max(0) as u64)
+}
+
+fn power_to_speed(power: Power) -> i8 {
+    match power {
+        Power::Cw(p) => p as i8,
+        Power::Ccw(p) => -(p as i8),
+        Power::Float | Power::Brake => 0,
+    }
+}
+
+/// Something that happened on the radio side of an emulated hub
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LinkEvent {
+    /// A central connected
+    Connected,
+    /// The central enabled notifications on the LPF2 characteristic
+    Subscribed,
+    /// The central wrote a message to the LPF2 characteristic
+    Write(Vec<u8>),
+    /// The central disconnected
+    Disconnected,
+}
+
+/// The radio side of an emulated hub: advertising it, and carrying
+/// messages to and from a central
+#[async_trait]
+pub trait Backend: Send {
+    /// Start advertising, or change what is advertised
+    async fn advertise(
+        &mut self,
+        advertisement: &AdvertisementData,
+    ) -> Result<()>;
+
+    async fn stop_advertising(&mut self) -> Result<()>;
+
+    /// Wait for the next event, or `None` once the backend has shut down
+    async fn next_event(&mut self) -> Option<LinkEvent>;
+
+    /// Send a notification to the connected central
+    async fn notify(&mut self, msg: &[u8]) -> Result<()>;
+}
+
+/// Run an emulated hub on a backend until the backend shuts down. The
+/// hub advertises whenever no central is connected.
+pub async fn serve(
+    mut emulator: HubEmulator,
+    mut backend: impl Backend,
+) -> Result<()> {
+    let mut advertised = emulator.advertisement();
+    backend.advertise(&advertised).await?;
+    let mut connected = false;
+
+    let mut ticks = tokio::time::interval(TICK);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_tick = Instant::now();
+    loop {
+        tokio::select! {
+            event = backend.next_event() => match event {
+                Some(LinkEvent::Connected) => {
+                    connected = true;
+                    backend.stop_advertising().await?;
+                }
+                Some(LinkEvent::Subscribed) => emulator.subscribe(),
+                Some(LinkEvent::Write(msg)) => emulator.handle(&msg),
+                Some(LinkEvent::Disconnected) => {
+                    connected = false;
+                    emulator.disconnect();
+                    advertised = emulator.advertisement();
+                    backend.advertise(&advertised).await?;
+                }
+                None => return Ok(()),
+            },
+            now = ticks.tick() => {
+                emulator.step(now - last_tick);
+                last_tick = now;
+            }
+        }
+        if connected {
+            for msg in emulator.notifications().collect::<Vec<_>>() {
+                backend.notify(&msg.serialise()).await?;
+            }
+        } else if emulator.advertisement() != advertised {
+            // A button press shows up in the advertisement
+            advertised = emulator.advertisement();
+            backend.advertise(&advertised).await?;
+        }
+    }
+}
+
+/// Create a backend which is connected to in memory rather than over
+/// the air, for testing. The central's end is a `Transport`.
+pub fn memory_backend() -> (MemoryBackend, MemoryCentral) {
+    let (events, receiver) = mpsc::unbounded_channel();
+    let (notifications, _) = broadcast::channel(256);
+    let (advertisement, watcher) = watch::channel(None);
+    (
+        MemoryBackend {
+            events: receiver,
+            notifications: notifications.clone(),
+            advertisement,
+        },
+        MemoryCentral {
+            events,
+            notifications,
+            advertisement: watcher,
+            connected: AtomicBool::new(false),
+        },
+    )
+}
+
+/// The hub's end of an in-memory link
+#[derive(Debug)]
+pub struct MemoryBackend {
+    events: mpsc::UnboundedReceiver<LinkEvent>,
+    notifications: broadcast::Sender<Vec<u8>>,
+    advertisement: watch::Sender<Option<AdvertisementData>>,
+}
+
+#[async_trait]
+impl Backend for MemoryBackend {
+    async fn advertise(
+        &mut self,
+        advertisement: &AdvertisementData,
+    ) -> Result<()> {
+        self.advertisement.send_replace(Some(advertisement.clone()));
+        Ok(())
+    }
+
+    async fn stop_advertising(&mut self) -> Result<()> {
+        self.advertisement.send_replace(None);
+        Ok(())
+    }
+
+    async fn next_event(&mut self) -> Option<LinkEvent> {
+        self.events.recv().await
+    }
+
+    async fn notify(&mut self, msg: &[u8]) -> Result<()> {
+        // Nobody listening is fine, as with a real hub
+        let _ = self.notifications.send(msg.to_vec());
+        Ok(())
+    }
+}
+
+/// The central's end of an in-memory link
+#[derive(Debug)]
+pub struct MemoryCentral {
+    events: mpsc::UnboundedSender<LinkEvent>,
+    notifications: broadcast::Sender<Vec<u8>>,
+    advertisement: watch::Receiver<Option<AdvertisementData>>,
+    connected: AtomicBool,
+}
+
+impl MemoryCentral {
+    /// What the hub is advertising, if anything
+    pub fn advertisement(&self) -> Option<AdvertisementData> {
+        self.advertisement.borrow().clone()
+    }
+
+    /// Wait for the hub to advertise, then connect to it
+    pub async fn connect(&self) -> Result<()> {
+        let mut advertisement = self.advertisement.clone();
+        advertisement
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|_| Error::HubError("Emulated hub has stopped".into()))?;
+        self.event(LinkEvent::Connected)?;
+        self.connected.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn event(&self, event: LinkEvent) -> Result<()> {
+        self.events
+            .send(event)
+            .map_err(|_| Error::HubError("Emulated hub has stopped".into()))
+    }
+
+    fn check_connected(&self) -> Result<()> {
+        if self.connected.load(Ordering::Relaxed) {
+            Ok(())
+        } else {
+            Err(Error::HubError("Not connected to emulated hub".into()))
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for MemoryCentral {
+    async fn write(&self, msg: &[u8]) -> Result<()> {
+        self.check_connected()?;
+        self.event(LinkEvent::Write(msg.to_vec()))
+    }
+
+    async fn notifications(&self) -> Result<MessageStream> {
+        Ok(broadcast_stream(self.notifications.subscribe()))
+    }
+
+    async fn subscribe(&self) -> Result<()> {
+        self.check_connected()?;
+        self.event(LinkEvent::Subscribed)
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        Ok(self.connected.load(Ordering::Relaxed))
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        if self.connected.swap(false, Ordering::Relaxed) {
+            self.event(LinkEvent::Disconnected)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hubs::{Hub, Port, TechnicHub};
+    use std::sync::Arc;
+
+    #[test]
+    fn state_machine() {
+        let mut emulator = HubEmulator::new("Emu");
+        emulator.attach(1, DeviceType::TechnicLargeLinearMotor);
+        assert_eq!(emulator.notifications().count(), 0);
+        emulator.subscribe();
+        let attached = emulator.notifications().collect::<Vec<_>>();
+        assert_eq!(attached.len(), 9);
+        assert!(attached.contains(&NotificationMessage::HubAttachedIo(
+            AttachedIo {
+                port: 1,
+                event: HubEmulator::attached_event(
+                    DeviceType::TechnicLargeLinearMotor as u16
+                ),
+            }
+        )));
+
+        // Start at 50% and read the position
+        emulator.handle(&[9, 0, 0x81, 1, 0x11, 0x07, 50, 100, 0]);
+        emulator.handle(&[10, 0, 0x41, 1, 2, 1, 0, 0, 0, 1]);
+        emulator.step(Duration::from_millis(100));
+        let port = emulator.port(1).unwrap();
+        assert_eq!((port.speed, port.position), (50, 50));
+        assert_eq!(port.input_mode, Some(2));
+        let values = emulator
+            .notifications()
+            .filter(|msg| {
+                matches!(msg, NotificationMessage::PortValueSingle(_))
+            })
+            .count();
+        assert_eq!(values, 2);
+
+        // Button updates, once enabled
+        emulator.set_button(true);
+        assert_eq!(emulator.notifications().count(), 0);
+        emulator.handle(&[5, 0, 0x01, 0x02, 0x02]);
+        emulator.set_button(false);
+        assert_eq!(
+            emulator.notifications().last(),
+            Some(NotificationMessage::HubProperties(HubProperty {
+                property: HubPropertyValue::Button(0),
+                operation: HubPropertyOperation::UpdateUpstream,
+            }))
+        );
+
+        emulator.disconnect();
+        let port = emulator.port(1).unwrap();
+        assert_eq!((port.speed, port.input_mode), (0, None));
+        emulator.set_button(true);
+        assert_eq!(emulator.notifications().count(), 0);
+        assert_eq!(emulator.advertisement().manufacturer_data[0], 1);
+    }
+
+    #[test]
+    fn network_commands_not_recognised() {
+        use crate::consts::HwNetworkCommandType;
+        use num_traits::FromPrimitive;
+
+        let mut emulator = HubEmulator::new("Emu");
+        emulator.subscribe();
+        emulator.notifications().count();
+        let command_type = MessageType::HwNetworkCommands as u8;
+        let commands = (0..=u8::MAX).filter_map(HwNetworkCommandType::from_u8);
+        for command in commands {
+            // Without and with a byte of payload
+            emulator.handle(&[4, 0, command_type, command as u8]);
+            emulator.handle(&[5, 0, command_type, command as u8, 1]);
+            let error =
+                NotificationMessage::GenericErrorMessages(ErrorMessageFormat {
+                    command_type,
+                    error_code: ErrorCode::CommandNotRecognized,
+                });
+            assert_eq!(
+                emulator.notifications().collect::<Vec<_>>(),
+                vec![error.clone(), error],
+                "{:?}",
+                command
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn serve_over_memory_backend() {
+        let mut emulator = HubEmulator::new("Emu");
+        emulator.attach(0, DeviceType::TechnicLargeAngularMotor);
+        let properties = emulator.properties();
+        let (backend, central) = memory_backend();
+        let server = tokio::spawn(serve(emulator, backend));
+
+        central.connect().await.unwrap();
+        let central = Arc::new(central);
+        let hub = TechnicHub::init(central.clone(), properties).await.unwrap();
+        let description = hub.describe_port(Port::A).await.unwrap();
+        assert_eq!(description.modes.len(), 4);
+        assert_eq!(central.advertisement(), None);
+
+        // Renaming the hub changes what it advertises once disconnected
+        let mut rename = vec![6, 0, 0x01, 0x01, 0x01];
+        rename.extend_from_slice(b"New");
+        rename[0] = rename.len() as u8;
+        central.write(&rename).await.unwrap();
+        hub.disconnect().await.unwrap();
+        let mut advertisement = central.advertisement.clone();
+        let advertisement = advertisement
+            .wait_for(Option::is_some)
+            .await
+            .unwrap()
+            .clone()
+            .unwrap();
+        assert_eq!(advertisement.name, "New");
+        assert_eq!(advertisement.service, *LPF2_HUB);
+
+        drop(hub);
+        drop(central);
+        server.await.unwrap().unwrap();
+    }
+}
@@ -21,6 +21,8 @@ pub enum Error {
     NotImplementedError(String),
     #[error("Hub error: {0}")]
     HubError(String),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -4,11 +4,27 @@
 
 //! Specific implementations for each of the supported hubs.
 
-use crate::devices::{self, Device};
-use crate::error::{OptionContext, Result};
-use btleplug::api::{Characteristic, Peripheral as _, WriteType};
-use btleplug::platform::Peripheral;
+use crate::consts::DeviceType;
+use crate::devices::{self, Device, PortDescription};
+use crate::error::{Error, OptionContext, Result};
+use crate::notifications::{
+    self, AttachedIo, IoAttachEvent, NotificationMessage,
+    VirtualPortSetupFormat,
+};
+use crate::transport::{MessageStream, Transport};
+use futures::stream::StreamExt;
+use num_traits::FromPrimitive;
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+/// How long to wait for the hub to announce a newly created virtual port
+const VIRTUAL_PORT_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long to wait for the hub to announce the device on a port when
+/// the port is opened shortly after connecting
+const ATTACH_TIMEOUT: Duration = Duration::from_millis(500);
 
 /// Trait describing a generic hub.
 #[async_trait::async_trait]
@@ -25,25 +41,36 @@ pub trait Hub {
     // }
 
     // cannot provide a default implementation without access to the
-    // Peripheral trait from here
+    // transport from here
     async fn send_raw(&self, msg: &[u8]) -> Result<()>;
 
-    // fn send(&self, msg: NotificationMessage) -> Result<()>;
-
-    async fn subscribe(&self, char: Characteristic) -> Result<()>;
+    async fn send(&self, msg: NotificationMessage) -> Result<()>;
 
     /// Ideally the vec should be sorted somehow
     async fn attached_io(&self) -> Vec<ConnectedIo>;
 
-    // fn process_io_event(&mut self, _evt: AttachedIo);
-
     async fn port(&self, port_id: Port) -> Result<Box<dyn Device>>;
+
+    /// Combine two motor ports into a virtual port so that both motors
+    /// can be driven synchronously. The returned device lives on
+    /// `Port::Virtual` with the id assigned by the hub.
+    async fn connect_virtual_port(
+        &self,
+        port_a: Port,
+        port_b: Port,
+    ) -> Result<Box<dyn Device>>;
+
+    /// Tear down a virtual port created by `connect_virtual_port`
+    async fn disconnect_virtual_port(&self, port: Port) -> Result<()>;
+
+    /// Ask the hub about every mode of the device attached to a port
+    async fn describe_port(&self, port: Port) -> Result<PortDescription>;
 }
 
 pub type VersionNumber = u8;
 
 /// Propeties of a hub
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct HubProperties {
     /// Friendly name, set via the PoweredUp or Control+ apps
     pub name: String,
@@ -83,6 +110,7 @@ pub enum Port {
     GyroSensor,
     TiltSensor,
     GestureSensor,
+    TemperatureSensor,
     Virtual(u8),
 }
 
@@ -100,41 +128,44 @@ pub struct ConnectedIo {
     pub port: Port,
     /// Internal numeric ID of the device
     pub port_id: u8,
+    /// Type of the attached device, `Unknown` if we don't recognise it
+    pub device_type: DeviceType,
+    /// Type id the hub reported, as it is the only way to tell apart
+    /// devices we don't recognise
+    pub io_type_id: u16,
     /// Device firmware revision
-    pub fw_rev: VersionNumber,
+    pub fw_rev: notifications::VersionNumber,
     /// Device hardware revision
-    pub hw_rev: VersionNumber,
+    pub hw_rev: notifications::VersionNumber,
 }
 
+type ConnectedIoMap = Arc<Mutex<HashMap<u8, ConnectedIo>>>;
+
 /// Definition for the TechnicMediumHub
 pub struct TechnicHub {
-    peripheral: Peripheral,
-    lpf_characteristic: Characteristic,
+    transport: Arc<dyn Transport>,
     properties: HubProperties,
-    connected_io: HashMap<u8, ConnectedIo>,
+    connected_io: ConnectedIoMap,
+    /// Signalled whenever `connected_io` changes
+    io_changed: Arc<Notify>,
+    listener: JoinHandle<()>,
 }
 
 #[async_trait::async_trait]
 impl Hub for TechnicHub {
     async fn name(&self) -> Result<String> {
-        Ok(self
-            .peripheral
-            .properties()
-            .await?
-            .context("No properties found for hub")?
-            .local_name
-            .unwrap_or_default())
+        Ok(self.properties.name.clone())
     }
 
     async fn disconnect(&self) -> Result<()> {
         if self.is_connected().await? {
-            self.peripheral.disconnect().await?;
+            self.transport.disconnect().await?;
         }
         Ok(())
     }
 
     async fn is_connected(&self) -> Result<bool> {
-        Ok(self.peripheral.is_connected().await?)
+        self.transport.is_connected().await
     }
 
     async fn properties(&self) -> &HubProperties {
@@ -142,26 +173,18 @@ impl Hub for TechnicHub {
     }
 
     async fn send_raw(&self, msg: &[u8]) -> Result<()> {
-        let write_type = WriteType::WithoutResponse;
-        Ok(self
-            .peripheral
-            .write(&self.lpf_characteristic, msg, write_type)
-            .await?)
+        self.transport.write(msg).await
     }
 
-    // fn send(&self, msg: NotificationMessage) -> Result<()> {
-    //     let msg = msg.serialise();
-    //     self.send_raw(&msg)?;
-    //     Ok(())
-    // }
-
-    async fn subscribe(&self, char: Characteristic) -> Result<()> {
-        Ok(self.peripheral.subscribe(&char).await?)
+    async fn send(&self, msg: NotificationMessage) -> Result<()> {
+        let msg = msg.serialise();
+        self.send_raw(&msg).await
     }
 
     async fn attached_io(&self) -> Vec<ConnectedIo> {
-        let mut ret = Vec::with_capacity(self.connected_io.len());
-        for (_k, v) in self.connected_io.iter() {
+        let connected_io = self.connected_io.lock().unwrap();
+        let mut ret = Vec::with_capacity(connected_io.len());
+        for (_k, v) in connected_io.iter() {
             ret.push(v.clone());
         }
 
@@ -170,65 +193,202 @@ impl Hub for TechnicHub {
         ret
     }
 
-    // fn process_io_event(&mut self, evt: AttachedIo) {
-    //     match evt.event {
-    //         IoAttachEvent::AttachedIo { hw_rev, fw_rev } => {
-    //             if let Some(port) = self.port_from_id(evt.port) {
-    //                 let io = ConnectedIo {
-    //                     port_id: evt.port,
-    //                     port,
-    //                     fw_rev,
-    //                     hw_rev,
-    //                 };
-    //                 self.connected_io.insert(evt.port, io);
-    //             }
-    //         }
-    //         IoAttachEvent::DetachedIo { io_type_id: _ } => {}
-    //         IoAttachEvent::AttachedVirtualIo {
-    //             port_a: _,
-    //             port_b: _,
-    //         } => {}
-    //     }
-    // }
-
     async fn port(&self, port_id: Port) -> Result<Box<dyn Device>> {
-        let port =
-            *self.properties.port_map.get(&port_id).ok_or_else(|| {
-                crate::Error::NoneError(format!(
-                    "Port type `{port_id:?}` not supported"
-                ))
-            })?;
+        let port = self.port_id(port_id)?;
         Ok(match port_id {
-            Port::HubLed => Box::new(devices::HubLED::new(
-                self.peripheral.clone(),
-                self.lpf_characteristic.clone(),
+            Port::HubLed => {
+                Box::new(devices::HubLED::new(self.transport.clone(), port))
+            }
+            Port::A | Port::B | Port::C | Port::D => {
+                let io = self.attached(port).await;
+                match io.as_ref().map(|io| io.device_type) {
+                    Some(DeviceType::ColorDistanceSensor) => {
+                        Box::new(devices::ColorDistanceSensor::new(
+                            self.transport.clone(),
+                            port_id,
+                            port,
+                        ))
+                    }
+                    Some(DeviceType::TechnicDistanceSensor) => {
+                        Box::new(devices::DistanceSensor::new(
+                            self.transport.clone(),
+                            port_id,
+                            port,
+                        ))
+                    }
+                    Some(DeviceType::TechnicColorSensor) => {
+                        Box::new(devices::TechnicColorSensor::new(
+                            self.transport.clone(),
+                            port_id,
+                            port,
+                        ))
+                    }
+                    Some(DeviceType::TechnicForceSensor) => {
+                        Box::new(devices::ForceSensor::new(
+                            self.transport.clone(),
+                            port_id,
+                            port,
+                        ))
+                    }
+                    Some(DeviceType::TiltSensor) => {
+                        Box::new(devices::ExternalTiltSensor::new(
+                            self.transport.clone(),
+                            port_id,
+                            port,
+                        ))
+                    }
+                    Some(DeviceType::MoveHubTiltSensor) => {
+                        Box::new(devices::MoveHubTiltSensor::new(
+                            self.transport.clone(),
+                            port_id,
+                            port,
+                        ))
+                    }
+                    Some(DeviceType::MotionSensor) => {
+                        Box::new(devices::MotionSensor::new(
+                            self.transport.clone(),
+                            port_id,
+                            port,
+                        ))
+                    }
+                    Some(DeviceType::PiezoBuzzer) => {
+                        Box::new(devices::PiezoBuzzer::new(
+                            self.transport.clone(),
+                            port_id,
+                            port,
+                        ))
+                    }
+                    _ => self.motor_or_generic(port_id, port, io),
+                }
+            }
+            Port::Virtual(_) => {
+                let io = self.attached(port).await;
+                self.motor_or_generic(port_id, port, io)
+            }
+            Port::Accelerometer => Box::new(devices::Accelerometer::new(
+                self.transport.clone(),
+                port_id,
                 port,
             )),
-            Port::A | Port::B | Port::C | Port::D => {
-                Box::new(devices::Motor::new(
-                    self.peripheral.clone(),
-                    self.lpf_characteristic.clone(),
+            Port::GyroSensor => Box::new(devices::GyroSensor::new(
+                self.transport.clone(),
+                port_id,
+                port,
+            )),
+            Port::TiltSensor => Box::new(devices::TiltSensor::new(
+                self.transport.clone(),
+                port_id,
+                port,
+            )),
+            Port::GestureSensor => Box::new(devices::GestureSensor::new(
+                self.transport.clone(),
+                port_id,
+                port,
+            )),
+            Port::VoltageSensor => Box::new(devices::VoltageSensor::new(
+                self.transport.clone(),
+                port_id,
+                port,
+            )),
+            Port::CurrentSensor => Box::new(devices::CurrentSensor::new(
+                self.transport.clone(),
+                port_id,
+                port,
+            )),
+            Port::TemperatureSensor => {
+                Box::new(devices::TemperatureSensor::new(
+                    self.transport.clone(),
                     port_id,
                     port,
                 ))
             }
-            _ => todo!(),
         })
     }
+
+    async fn describe_port(&self, port: Port) -> Result<PortDescription> {
+        devices::describe_port(self.transport.as_ref(), self.port_id(port)?)
+            .await
+    }
+
+    async fn connect_virtual_port(
+        &self,
+        port_a: Port,
+        port_b: Port,
+    ) -> Result<Box<dyn Device>> {
+        let id_a = self.port_id(port_a)?;
+        let id_b = self.port_id(port_b)?;
+
+        // Subscribe before sending so that the announcement can't be missed
+        let mut notifications = self.transport.notifications().await?;
+        self.send(NotificationMessage::VirtualPortSetup(
+            VirtualPortSetupFormat::Connect {
+                port_a: id_a,
+                port_b: id_b,
+            },
+        ))
+        .await?;
+
+        let announcement = async {
+            while let Some(msg) = notifications.next().await {
+                if let Ok(NotificationMessage::HubAttachedIo(AttachedIo {
+                    port,
+                    event: IoAttachEvent::AttachedVirtualIo { port_a, port_b },
+                })) = NotificationMessage::parse(&msg)
+                {
+                    if port_a == id_a && port_b == id_b {
+                        return Some(port);
+                    }
+                }
+            }
+            None
+        };
+        let port = tokio::time::timeout(VIRTUAL_PORT_TIMEOUT, announcement)
+            .await
+            .map_err(|_| {
+                Error::TimeoutError(format!(
+                    "Hub did not create virtual port for {port_a:?} and \
+                    {port_b:?}"
+                ))
+            })?
+            .context("Notification stream ended")?;
+        debug!("Virtual port {} = {:?} + {:?}", port, port_a, port_b);
+
+        self.port(Port::Virtual(port)).await
+    }
+
+    async fn disconnect_virtual_port(&self, port: Port) -> Result<()> {
+        let Port::Virtual(port_id) = port else {
+            return Err(Error::HubError(format!(
+                "Port `{port:?}` is not a virtual port"
+            )));
+        };
+        self.send(NotificationMessage::VirtualPortSetup(
+            VirtualPortSetupFormat::Disconnect { port_id },
+        ))
+        .await
+    }
+}
+
+impl Drop for TechnicHub {
+    fn drop(&mut self) {
+        self.listener.abort();
+    }
 }
 
 impl TechnicHub {
-    /// Initialisation method
+    /// Initialisation method. `properties` holds what is already known
+    /// about the hub, e.g. its name and address from discovery; the port
+    /// map is filled in here.
     pub async fn init(
-        peripheral: Peripheral,
-        lpf_characteristic: Characteristic,
+        transport: Arc<dyn Transport>,
+        mut properties: HubProperties,
     ) -> Result<Self> {
-        // Peripheral is already connected before we get here
+        // Transport is already connected before we get here
 
-        let props = peripheral
-            .properties()
-            .await?
-            .context("No properties found for hub")?;
+        // The hub announces its attached devices as soon as notifications
+        // are enabled, so start listening before subscribing
+        let notifications = transport.notifications().await?;
+        transport.subscribe().await?;
 
         let mut port_map = PortMap::with_capacity(10);
         port_map.insert(Port::A, 0);
@@ -241,29 +401,173 @@ impl TechnicHub {
         port_map.insert(Port::Accelerometer, 97);
         port_map.insert(Port::GyroSensor, 98);
         port_map.insert(Port::TiltSensor, 99);
+        port_map.insert(Port::GestureSensor, 100);
+        port_map.insert(Port::TemperatureSensor, 96);
 
-        let properties = HubProperties {
-            mac_address: props.address.to_string(),
-            name: props.local_name.unwrap_or_default(),
-            rssi: props.tx_power_level.unwrap_or_default(),
-            port_map,
-            ..Default::default()
-        };
+        properties.port_map = port_map;
+
+        let connected_io = ConnectedIoMap::default();
+        let io_changed = Arc::new(Notify::new());
+        let listener = tokio::spawn(track_attached_io(
+            notifications,
+            properties.port_map.clone(),
+            connected_io.clone(),
+            io_changed.clone(),
+        ));
 
         Ok(Self {
-            peripheral,
-            lpf_characteristic,
+            transport,
             properties,
-            connected_io: Default::default(),
+            connected_io,
+            io_changed,
+            listener,
         })
     }
 
-    // async fn port_from_id(&self, _port_id: u8) -> Option<Port> {
-    // for (k, v) in self.port_map().await.iter() {
-    //     if *v == port_id {
-    //         return Some(*k);
-    //     }
-    // }
-    // None
-    // }
+    /// Motors without an encoder can only be driven by power. If the hub
+    /// hasn't said what is attached, assume a motor with an encoder.
+    /// Anything else, including types we don't recognise, is a device we
+    /// have no specific support for.
+    fn motor_or_generic(
+        &self,
+        port: Port,
+        port_id: u8,
+        io: Option<ConnectedIo>,
+    ) -> Box<dyn Device> {
+        let Some(io) = io else {
+            return Box::new(devices::Motor::new(
+                self.transport.clone(),
+                port,
+                port_id,
+                self.properties.port_map.get(&Port::CurrentSensor).copied(),
+            ));
+        };
+        match io.device_type {
+            DeviceType::SimpleMediumLinearMotor | DeviceType::TrainMotor => {
+                Box::new(devices::BasicMotor::new(
+                    self.transport.clone(),
+                    port,
+                    port_id,
+                ))
+            }
+            DeviceType::MediumLinearMotor
+            | DeviceType::MoveHubMediumLinearMotor
+            | DeviceType::DuploTrainBaseMotor
+            | DeviceType::TechnicLargeLinearMotor
+            | DeviceType::TechnicXlargeLinearMotor
+            | DeviceType::TechnicMediumAngularMotor
+            | DeviceType::TechnicLargeAngularMotor
+            | DeviceType::TechnicMediumAngularMotorGrey
+            | DeviceType::TechnicLargeAngularMotorGrey => {
+                Box::new(devices::Motor::new(
+                    self.transport.clone(),
+                    port,
+                    port_id,
+                    self.properties.port_map.get(&Port::CurrentSensor).copied(),
+                ))
+            }
+            _ => Box::new(devices::GenericDevice::new(
+                self.transport.clone(),
+                port,
+                port_id,
+                io.io_type_id,
+            )),
+        }
+    }
+
+    /// The device attached to a port. Right after connecting the hub may
+    /// not have announced it yet, so wait a little while for it.
+    async fn attached(&self, port_id: u8) -> Option<ConnectedIo> {
+        let deadline = Instant::now() + ATTACH_TIMEOUT;
+        loop {
+            // Register for changes before checking, so none are missed
+            let changed = self.io_changed.notified();
+            if let Some(io) = self.connected_io.lock().unwrap().get(&port_id) {
+                return Some(io.clone());
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if tokio::time::timeout(remaining, changed).await.is_err() {
+                return None;
+            }
+        }
+    }
+
+    /// Look up the hub's internal id for a port. Virtual ports carry
+    /// their id with them.
+    fn port_id(&self, port: Port) -> Result<u8> {
+        if let Port::Virtual(id) = port {
+            return Ok(id);
+        }
+        self.properties.port_map.get(&port).copied().ok_or_else(|| {
+            Error::NoneError(format!("Port type `{port:?}` not supported"))
+        })
+    }
+}
+
+/// Keep track of the devices attached to the hub's ports
+async fn track_attached_io(
+    mut notifications: MessageStream,
+    port_map: PortMap,
+    connected_io: ConnectedIoMap,
+    io_changed: Arc<Notify>,
+) {
+    while let Some(msg) = notifications.next().await {
+        let Ok(NotificationMessage::HubAttachedIo(AttachedIo { port, event })) =
+            NotificationMessage::parse(&msg)
+        else {
+            continue;
+        };
+
+        let mut connected_io = connected_io.lock().unwrap();
+        match event {
+            IoAttachEvent::AttachedIo {
+                io_type_id,
+                hw_rev,
+                fw_rev,
+            } => {
+                let Some(port_type) = port_from_id(&port_map, port) else {
+                    debug!("Device attached to unknown port {}", port);
+                    continue;
+                };
+                let device_type = DeviceType::from_u16(io_type_id)
+                    .unwrap_or(DeviceType::Unknown);
+                debug!("{:?} attached to {:?}", device_type, port_type);
+                connected_io.insert(
+                    port,
+                    ConnectedIo {
+                        port: port_type,
+                        port_id: port,
+                        device_type,
+                        io_type_id,
+                        fw_rev,
+                        hw_rev,
+                    },
+                );
+            }
+            IoAttachEvent::AttachedVirtualIo { port_a, .. } => {
+                // Both halves of a virtual port are the same kind of motor
+                if let Some(io) = connected_io.get(&port_a).cloned() {
+                    connected_io.insert(
+                        port,
+                        ConnectedIo {
+                            port: Port::Virtual(port),
+                            port_id: port,
+                            ..io
+                        },
+                    );
+                }
+            }
+            IoAttachEvent::DetachedIo => {
+                connected_io.remove(&port);
+            }
+        }
+        io_changed.notify_waiters();
+    }
+}
+
+fn port_from_id(port_map: &PortMap, port_id: u8) -> Option<Port> {
+    port_map
+        .iter()
+        .find(|(_, id)| **id == port_id)
+        .map(|(port, _)| *port)
 }
@@ -4,21 +4,34 @@ use btleplug::api::{
 };
 use btleplug::platform::{Adapter, Manager, PeripheralId};
 use futures::{stream::StreamExt, Stream};
-use num_traits::FromPrimitive;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[macro_use]
 extern crate log;
 
+pub mod advertisement;
+pub mod bridge;
+pub mod capture;
 pub mod consts;
 pub mod devices;
+pub mod discovery;
+pub mod emulator;
 pub mod error;
 pub mod hubs;
 pub mod notifications;
+pub mod recording;
+pub mod simulator;
+pub mod transport;
 
 pub use btleplug;
 pub use error::{Error, OptionContext, Result};
 
-use consts::{BLEManufacturerData, HubType};
+use advertisement::Advertisement;
+use consts::HubType;
+
+/// Company id under which hubs advertise their manufacturer data
+const LEGO_COMPANY_ID: u16 = 919;
 use hubs::Hub;
 
 pub struct PoweredUp {
@@ -72,14 +85,8 @@ impl PoweredUp {
         let mut hubs = Vec::new();
         for peripheral in peripherals {
             let Some(props) = peripheral.properties().await? else{continue;};
-            if let Some(hub_type) = identify_hub(&props).await? {
-                hubs.push(DiscoveredHub {
-                    hub_type,
-                    addr: peripheral.id(),
-                    name: props
-                        .local_name
-                        .unwrap_or_else(|| "unknown".to_string()),
-                });
+            if let Some(hub) = discovered_hub(peripheral.id(), props).await? {
+                hubs.push(hub);
             }
         }
         Ok(hubs)
@@ -96,16 +103,7 @@ impl PoweredUp {
             let peripheral = self.adapter.peripheral(&id).await.ok()?;
             // println!("{:?}", peripheral.properties().await?);
             let Some(props) = peripheral.properties().await.ok()? else { None? };
-            if let Some(hub_type) = identify_hub(&props).await.ok()? {
-                let hub = DiscoveredHub {
-                    hub_type,
-                    addr: id,
-                    name: props
-                        .local_name
-                        .unwrap_or_else(|| "unknown".to_string()),
-                };
-                Some(hub)
-            } else { None }
+            discovered_hub(id, props).await.ok()?
         }))
     }
 
@@ -113,39 +111,104 @@ impl PoweredUp {
         self.wait_for_hub_filter(HubFilter::Null).await
     }
 
+    /// Wait for any hub, giving up with `Error::TimeoutError` after
+    /// `timeout`
+    pub async fn wait_for_hub_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<DiscoveredHub> {
+        self.wait_for_hub_filter_timeout(HubFilter::Null, timeout)
+            .await
+    }
+
+    /// Wait for a hub matching `filter`, giving up with
+    /// `Error::TimeoutError` after `timeout`
+    pub async fn wait_for_hub_filter_timeout(
+        &mut self,
+        filter: HubFilter,
+        timeout: Duration,
+    ) -> Result<DiscoveredHub> {
+        tokio::time::timeout(timeout, self.wait_for_hub_filter(filter))
+            .await
+            .map_err(|_| {
+                Error::TimeoutError(format!(
+                    "No matching hub found within {:?}",
+                    timeout
+                ))
+            })?
+    }
+
+    /// Scan until a hub matching `filter` is found. Scanning stops when a
+    /// hub is found, or if the returned future is dropped.
     pub async fn wait_for_hub_filter(
         &mut self,
         filter: HubFilter,
     ) -> Result<DiscoveredHub> {
         let mut events = self.adapter.events().await?;
         self.adapter.start_scan(ScanFilter::default()).await?;
+        let scan = ScanGuard(Some(self.adapter.clone()));
         while let Some(event) = events.next().await {
-            let CentralEvent::DeviceDiscovered(id) = event else { continue };
+            // The name and signal strength may only arrive in an update
+            let (CentralEvent::DeviceDiscovered(id)
+            | CentralEvent::DeviceUpdated(id)) = event
+            else {
+                continue;
+            };
             // get peripheral info
             let peripheral = self.adapter.peripheral(&id).await?;
             // println!("{:?}", peripheral.properties().await?);
             let Some(props) = peripheral.properties().await? else { continue };
-            if let Some(hub_type) = identify_hub(&props).await? {
-                let hub = DiscoveredHub {
-                    hub_type,
-                    addr: id,
-                    name: props
-                        .local_name
-                        .unwrap_or_else(|| "unknown".to_string()),
-                };
+            if let Some(hub) = discovered_hub(id, props).await? {
                 if filter.matches(&hub) {
-                    self.adapter.stop_scan().await?;
+                    scan.stop().await?;
                     return Ok(hub);
                 }
             }
         }
-        panic!()
+        scan.stop().await?;
+        Err(Error::BluetoothError(btleplug::Error::Other(
+            "Adapter stopped reporting events while scanning".into(),
+        )))
+    }
+
+    /// Scan for `duration`, then pick the hub matching `filter` with the
+    /// strongest signal, such as the nearest of several identical hubs
+    pub async fn strongest_hub(
+        &mut self,
+        filter: HubFilter,
+        duration: Duration,
+    ) -> Result<DiscoveredHub> {
+        self.adapter.start_scan(ScanFilter::default()).await?;
+        let scan = ScanGuard(Some(self.adapter.clone()));
+        tokio::time::sleep(duration).await;
+        scan.stop().await?;
+        self.list_discovered_hubs()
+            .await?
+            .into_iter()
+            .filter(|hub| filter.matches(hub))
+            .max_by_key(|hub| hub.rssi.unwrap_or(i16::MIN))
+            .ok_or_else(|| {
+                Error::TimeoutError(format!(
+                    "No matching hub found within {:?}",
+                    duration
+                ))
+            })
     }
 
     pub async fn create_hub(
         &mut self,
         hub: &DiscoveredHub,
     ) -> Result<Box<dyn Hub>> {
+        let (transport, properties) = self.connect_transport(hub).await?;
+        init_hub(hub.hub_type, transport, properties).await
+    }
+
+    /// Connect to a discovered hub without initialising it, for handing
+    /// the connection on to something else such as a bridge
+    pub async fn connect_transport(
+        &mut self,
+        hub: &DiscoveredHub,
+    ) -> Result<(Arc<dyn transport::Transport>, hubs::HubProperties)> {
         info!("Connecting to hub {}...", hub.addr,);
 
         let peripheral = self.adapter.peripheral(&hub.addr).await?;
@@ -161,19 +224,79 @@ impl PoweredUp {
             .find(|c| c.uuid == *consts::blecharacteristic::LPF2_ALL)
             .context("Device does not advertise LPF2_ALL characteristic")?
             .clone();
-        peripheral.subscribe(&lpf_char).await?;
 
-        Ok(Box::new(match hub.hub_type {
-            HubType::TechnicMediumHub => {
-                hubs::TechnicHub::init(peripheral, lpf_char).await?
-            }
-            _ => unimplemented!(),
-        }))
+        let props = peripheral
+            .properties()
+            .await?
+            .context("No properties found for hub")?;
+        let properties = hubs::HubProperties {
+            mac_address: props.address.to_string(),
+            name: props.local_name.unwrap_or_default(),
+            rssi: props.tx_power_level.unwrap_or_default(),
+            ..Default::default()
+        };
+        let transport =
+            Arc::new(transport::BtleplugTransport::new(peripheral, lpf_char));
+        Ok((transport, properties))
     }
 }
 
-/// Properties by which to filter discovered hubs
-#[derive(Debug)]
+/// Stops a scan when dropped, so that a scan isn't left running if
+/// waiting for a hub is cancelled
+struct ScanGuard(Option<Adapter>);
+
+impl ScanGuard {
+    async fn stop(mut self) -> Result<()> {
+        if let Some(adapter) = self.0.take() {
+            adapter.stop_scan().await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ScanGuard {
+    fn drop(&mut self) {
+        let Some(adapter) = self.0.take() else { return };
+        // Stopping is async, so finish it on a task if there's a runtime
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                if let Err(e) = adapter.stop_scan().await {
+                    warn!("Failed to stop scanning: {}", e);
+                }
+            });
+        }
+    }
+}
+
+/// Initialise a hub of the given type over a connected transport
+pub(crate) async fn init_hub(
+    hub_type: HubType,
+    transport: Arc<dyn transport::Transport>,
+    properties: hubs::HubProperties,
+) -> Result<Box<dyn Hub>> {
+    Ok(Box::new(match hub_type {
+        HubType::TechnicMediumHub => {
+            hubs::TechnicHub::init(transport, properties).await?
+        }
+        _ => {
+            return Err(Error::NotImplementedError(format!(
+                "Support for {} hubs",
+                hub_type
+            )))
+        }
+    }))
+}
+
+/// Properties by which to filter discovered hubs. Filters can be
+/// combined, e.g. to find a Technic hub named `crane-` something:
+///
+/// ```
+/// use lego_powered_up::consts::HubType;
+/// use lego_powered_up::HubFilter;
+///
+/// let filter = HubFilter::Type(HubType::TechnicMediumHub)
+///     .and(HubFilter::NameGlob("crane-*".to_string()));
+/// ```
 pub enum HubFilter {
     /// Hub name must match the provided value
     Name(String),
@@ -181,6 +304,27 @@ pub enum HubFilter {
     Addr(String),
     /// Always matches
     Null,
+    /// Hub must be of the provided type
+    Type(HubType),
+    /// Hub name must start with the provided value
+    NamePrefix(String),
+    /// Hub name must match the provided pattern, in which `*` matches
+    /// any run of characters and `?` any one character
+    NameGlob(String),
+    /// Hub signal strength must be at least the provided value, in dBm
+    MinRssi(i16),
+    /// A byte of the hub's manufacturer data must have the provided value
+    ManufacturerData { index: usize, value: u8 },
+    /// The hub's button must be held down
+    ButtonPressed,
+    /// Every filter must match
+    And(Vec<HubFilter>),
+    /// At least one filter must match
+    Or(Vec<HubFilter>),
+    /// The filter must not match
+    Not(Box<HubFilter>),
+    /// The function must return true
+    Custom(Box<dyn Fn(&DiscoveredHub) -> bool + Send + Sync>),
 }
 
 impl HubFilter {
@@ -191,13 +335,120 @@ impl HubFilter {
             Name(n) => hub.name == *n,
             Addr(a) => format!("{:?}", hub.addr) == *a,
             Null => true,
+            Type(hub_type) => hub.hub_type == *hub_type,
+            NamePrefix(prefix) => hub.name.starts_with(prefix.as_str()),
+            NameGlob(pattern) => glob_match(pattern, &hub.name),
+            MinRssi(rssi) => hub.rssi.is_some_and(|r| r >= *rssi),
+            ManufacturerData { index, value } => {
+                hub.manufacturer_data.get(*index) == Some(value)
+            }
+            ButtonPressed => hub
+                .advertisement
+                .is_some_and(|advertisement| advertisement.button_pressed),
+            And(filters) => filters.iter().all(|f| f.matches(hub)),
+            Or(filters) => filters.iter().any(|f| f.matches(hub)),
+            Not(filter) => !filter.matches(hub),
+            Custom(f) => f(hub),
+        }
+    }
+
+    /// Filter on a function of the discovered hub
+    pub fn custom(
+        f: impl Fn(&DiscoveredHub) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        HubFilter::Custom(Box::new(f))
+    }
+
+    /// Match hubs which match both filters
+    pub fn and(self, other: HubFilter) -> Self {
+        match self {
+            HubFilter::And(mut filters) => {
+                filters.push(other);
+                HubFilter::And(filters)
+            }
+            _ => HubFilter::And(vec![self, other]),
+        }
+    }
+
+    /// Match hubs which match either filter
+    pub fn or(self, other: HubFilter) -> Self {
+        match self {
+            HubFilter::Or(mut filters) => {
+                filters.push(other);
+                HubFilter::Or(filters)
+            }
+            _ => HubFilter::Or(vec![self, other]),
+        }
+    }
+}
+
+impl std::ops::Not for HubFilter {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        HubFilter::Not(Box::new(self))
+    }
+}
+
+impl std::fmt::Debug for HubFilter {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use HubFilter::*;
+        match self {
+            Name(n) => fmt.debug_tuple("Name").field(n).finish(),
+            Addr(a) => fmt.debug_tuple("Addr").field(a).finish(),
+            Null => fmt.write_str("Null"),
+            Type(t) => fmt.debug_tuple("Type").field(t).finish(),
+            NamePrefix(p) => fmt.debug_tuple("NamePrefix").field(p).finish(),
+            NameGlob(p) => fmt.debug_tuple("NameGlob").field(p).finish(),
+            MinRssi(r) => fmt.debug_tuple("MinRssi").field(r).finish(),
+            ManufacturerData { index, value } => fmt
+                .debug_struct("ManufacturerData")
+                .field("index", index)
+                .field("value", value)
+                .finish(),
+            ButtonPressed => fmt.write_str("ButtonPressed"),
+            And(filters) => fmt.debug_tuple("And").field(filters).finish(),
+            Or(filters) => fmt.debug_tuple("Or").field(filters).finish(),
+            Not(filter) => fmt.debug_tuple("Not").field(filter).finish(),
+            Custom(_) => fmt.write_str("Custom(..)"),
         }
     }
 }
 
+/// Match `text` against a pattern of literal characters, `*` and `?`
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    // Where to resume after the last `*`, if a match fails after it
+    let mut star = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // Let the `*` take one more character
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
 /// Struct describing a discovered hub. This description may be passed
 /// to `PoweredUp::create_hub` to initialise a connection.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct DiscoveredHub {
     /// Type of hub, e.g. TechnicMediumHub
     pub hub_type: HubType,
@@ -205,6 +456,34 @@ pub struct DiscoveredHub {
     pub addr: PeripheralId,
     /// Friendly name of the hub, as set in the PoweredUp/Control+ apps
     pub name: String,
+    /// Signal strength when the hub was last seen, in dBm
+    pub rssi: Option<i16>,
+    /// Manufacturer data the hub advertises under LEGO's company id
+    pub manufacturer_data: Vec<u8>,
+    /// The manufacturer data decoded, if it is complete
+    pub advertisement: Option<Advertisement>,
+}
+
+async fn discovered_hub(
+    addr: PeripheralId,
+    props: PeripheralProperties,
+) -> Result<Option<DiscoveredHub>> {
+    let Some(hub_type) = identify_hub(&props).await? else {
+        return Ok(None);
+    };
+    let manufacturer_data = props
+        .manufacturer_data
+        .get(&LEGO_COMPANY_ID)
+        .cloned()
+        .unwrap_or_default();
+    Ok(Some(DiscoveredHub {
+        hub_type,
+        addr,
+        name: props.local_name.unwrap_or_else(|| "unknown".to_string()),
+        rssi: props.rssi,
+        advertisement: Advertisement::parse(&manufacturer_data).ok(),
+        manufacturer_data,
+    }))
 }
 
 async fn identify_hub(props: &PeripheralProperties) -> Result<Option<HubType>> {
@@ -216,21 +495,33 @@ async fn identify_hub(props: &PeripheralProperties) -> Result<Option<HubType>> {
     {
         return Ok(Some(Wedo2SmartHub));
     } else if props.services.contains(&consts::bleservice::LPF2_HUB) {
-        if let Some(manufacturer_id) = props.manufacturer_data.get(&919) {
-            // Can't do it with a match because some devices are just manufacturer
-            // data while some use other characteristics
-            if let Some(m) = BLEManufacturerData::from_u8(manufacturer_id[1]) {
-                use BLEManufacturerData::*;
-                return Ok(Some(match m {
-                    DuploTrainBaseId => DuploTrainBase,
-                    HubId => Hub,
-                    MarioId => Mario,
-                    MoveHubId => MoveHub,
-                    RemoteControlId => RemoteControl,
-                    TechnicMediumHubId => TechnicMediumHub,
-                }));
+        if let Some(manufacturer_id) =
+            props.manufacturer_data.get(&LEGO_COMPANY_ID)
+        {
+            if let Some(hub_type) = manufacturer_id
+                .get(1)
+                .and_then(|m| advertisement::system_hub_type(*m))
+            {
+                return Ok(Some(hub_type));
             }
         }
     }
     Ok(None)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("crane-*", "crane-1"));
+        assert!(glob_match("crane-*", "crane-"));
+        assert!(!glob_match("crane-*", "digger-1"));
+        assert!(glob_match("*-?", "crane-1"));
+        assert!(!glob_match("*-?", "crane-12"));
+        assert!(glob_match("c*e*1", "crane-1"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("?", ""));
+    }
+}
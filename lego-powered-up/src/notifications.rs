@@ -335,37 +335,33 @@ impl NotificationMessage {
         use NotificationMessage::*;
 
         let mut ser = match self {
-            HubProperties(_) => todo!(),
+            HubProperties(prop) => prop.serialise(),
             HubActions(_) => todo!(),
             HubAlerts(_) => todo!(),
-            HubAttachedIo(_) => todo!(),
-            GenericErrorMessages(_) => todo!(),
+            HubAttachedIo(io) => io.serialise(),
+            GenericErrorMessages(error) => error.serialise(),
             HwNetworkCommands(_) => todo!(),
             FwUpdateGoIntoBootMode(_) => todo!(),
             FwUpdateLockMemory(_) => todo!(),
             FwUpdateLockStatusRequest => todo!(),
             FwLockStatus(_) => todo!(),
-            PortInformationRequest(_) => todo!(),
-            PortModeInformationRequest(_) => {
-                todo!()
-            }
+            PortInformationRequest(req) => req.serialise(),
+            PortModeInformationRequest(req) => req.serialise(),
             PortInputFormatSetupSingle(msg) => msg.serialise(),
             PortInputFormatSetupCombinedmode(_) => {
                 todo!()
             }
-            PortInformation(_) => todo!(),
-            PortModeInformation(_) => todo!(),
-            PortValueSingle(_) => todo!(),
+            PortInformation(info) => info.serialise(),
+            PortModeInformation(info) => info.serialise(),
+            PortValueSingle(value) => value.serialise(),
             PortValueCombinedmode(_) => todo!(),
-            PortInputFormatSingle(_) => todo!(),
+            PortInputFormatSingle(fmt) => fmt.serialise(),
             PortInputFormatCombinedmode(_) => {
                 todo!()
             }
-            VirtualPortSetup(_) => todo!(),
+            VirtualPortSetup(setup) => setup.serialise(),
             PortOutputCommand(cmd) => cmd.serialise(),
-            PortOutputCommandFeedback(_) => {
-                todo!()
-            }
+            PortOutputCommandFeedback(feedback) => feedback.serialise(),
         };
         ser[0] = ser.len() as u8;
         debug!("Serialised to: {:02x?}", ser);
@@ -385,8 +381,8 @@ pub enum HubLedMode {
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct HubProperty {
-    property: HubPropertyValue,
-    operation: HubPropertyOperation,
+    pub(crate) property: HubPropertyValue,
+    pub(crate) operation: HubPropertyOperation,
 }
 
 impl HubProperty {
@@ -400,6 +396,22 @@ impl HubProperty {
             property,
         })
     }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        let mut msg = vec![
+            0,
+            0,
+            MessageType::HubProperties as u8,
+            self.property.reference() as u8,
+            self.operation as u8,
+        ];
+        msg.extend_from_slice(&self.property.serialise());
+        msg
+    }
+
+    pub fn property(&self) -> &HubPropertyValue {
+        &self.property
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -491,6 +503,57 @@ impl HubPropertyValue {
             }
         })
     }
+
+    /// Which property this is a value of
+    pub fn reference(&self) -> HubPropertyReference {
+        use HubPropertyValue::*;
+
+        match self {
+            AdvertisingName(_) => HubPropertyReference::AdvertisingName,
+            Button(_) => HubPropertyReference::Button,
+            FwVersion(_) => HubPropertyReference::FwVersion,
+            HwVersion(_) => HubPropertyReference::HwVersion,
+            Rssi(_) => HubPropertyReference::Rssi,
+            BatteryVoltage(_) => HubPropertyReference::BatteryVoltage,
+            BatteryType(_) => HubPropertyReference::BatteryType,
+            ManufacturerName(_) => HubPropertyReference::ManufacturerName,
+            RadioFirmwareVersion(_) => {
+                HubPropertyReference::RadioFirmwareVersion
+            }
+            LegoWirelessProtocolVersion(_) => {
+                HubPropertyReference::LegoWirelessProtocolVersion
+            }
+            SystemTypeId(_) => HubPropertyReference::SystemTypeId,
+            HwNetworkId(_) => HubPropertyReference::HwNetworkId,
+            PrimaryMacAddress(_) => HubPropertyReference::PrimaryMacAddress,
+            SecondaryMacAddress => HubPropertyReference::SecondaryMacAddress,
+            HardwareNetworkFamily(_) => {
+                HubPropertyReference::HardwareNetworkFamily
+            }
+        }
+    }
+
+    /// Payload of the value, without the property reference
+    pub fn serialise(&self) -> Vec<u8> {
+        use HubPropertyValue::*;
+
+        match self {
+            AdvertisingName(bytes)
+            | ManufacturerName(bytes)
+            | RadioFirmwareVersion(bytes) => bytes.clone(),
+            Button(b)
+            | BatteryVoltage(b)
+            | SystemTypeId(b)
+            | HwNetworkId(b)
+            | HardwareNetworkFamily(b) => vec![*b],
+            FwVersion(vers) | HwVersion(vers) => vers.to_le_bytes().to_vec(),
+            Rssi(rssi) => rssi.to_le_bytes().to_vec(),
+            BatteryType(t) => vec![*t as u8],
+            LegoWirelessProtocolVersion(vers) => vers.to_le_bytes().to_vec(),
+            PrimaryMacAddress(mac) => mac.to_vec(),
+            SecondaryMacAddress => Vec::new(),
+        }
+    }
 }
 
 #[repr(u8)]
@@ -536,15 +599,41 @@ impl AttachedIo {
         let event = IoAttachEvent::parse(&mut msg)?;
         Ok(Self { port, event })
     }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        let mut msg = vec![0, 0, MessageType::HubAttachedIo as u8, self.port];
+        match self.event {
+            IoAttachEvent::DetachedIo => msg.push(Event::DetachedIo as u8),
+            IoAttachEvent::AttachedIo {
+                io_type_id,
+                hw_rev,
+                fw_rev,
+            } => {
+                msg.push(Event::AttachedIo as u8);
+                msg.extend_from_slice(&io_type_id.to_le_bytes());
+                msg.extend_from_slice(&hw_rev.serialise());
+                msg.extend_from_slice(&fw_rev.serialise());
+            }
+            IoAttachEvent::AttachedVirtualIo { port_a, port_b } => {
+                msg.extend_from_slice(&[
+                    Event::AttachedVirtualIo as u8,
+                    port_a,
+                    port_b,
+                ]);
+            }
+        }
+        msg
+    }
 }
 
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum IoAttachEvent {
-    DetachedIo {
-        io_type_id: IoTypeId,
-    },
+    DetachedIo,
     AttachedIo {
+        /// Type of the attached device. Kept as the raw id because the
+        /// hub may report devices we don't know about.
+        io_type_id: u16,
         hw_rev: VersionNumber,
         fw_rev: VersionNumber,
     },
@@ -559,14 +648,16 @@ impl IoAttachEvent {
         let event_type = ok!(Event::from_u8(next!(msg)));
 
         Ok(match event_type {
-            Event::DetachedIo => {
-                let io_type_id = ok!(IoTypeId::from_u16(next_u16!(msg)));
-                IoAttachEvent::DetachedIo { io_type_id }
-            }
+            Event::DetachedIo => IoAttachEvent::DetachedIo,
             Event::AttachedIo => {
+                let io_type_id = next_u16!(msg);
                 let hw_rev = VersionNumber::parse(&mut msg)?;
                 let fw_rev = VersionNumber::parse(&mut msg)?;
-                IoAttachEvent::AttachedIo { hw_rev, fw_rev }
+                IoAttachEvent::AttachedIo {
+                    io_type_id,
+                    hw_rev,
+                    fw_rev,
+                }
             }
             Event::AttachedVirtualIo => {
                 let port_a = next!(msg);
@@ -683,8 +774,8 @@ pub enum IoTypeId {
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct ErrorMessageFormat {
-    command_type: u8,
-    error_code: ErrorCode,
+    pub(crate) command_type: u8,
+    pub(crate) error_code: ErrorCode,
 }
 
 impl ErrorMessageFormat {
@@ -697,6 +788,25 @@ impl ErrorMessageFormat {
             error_code,
         })
     }
+
+    /// Message type of the command which failed
+    pub fn command_type(&self) -> u8 {
+        self.command_type
+    }
+
+    pub fn error_code(&self) -> ErrorCode {
+        self.error_code
+    }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        vec![
+            0,
+            0,
+            MessageType::GenericErrorMessages as u8,
+            self.command_type,
+            self.error_code as u8,
+        ]
+    }
 }
 
 #[repr(u8)]
@@ -766,19 +876,10 @@ impl NetworkCommand {
                 let fam = NetworkFamily::parse(&mut msg)?;
                 FamilySet(fam)
             }
-            HwNetworkCommandType::JoinDenied => {
-                todo!()
-            }
-            HwNetworkCommandType::GetFamily => {
-                todo!()
-            }
             HwNetworkCommandType::Family => {
                 let fam = NetworkFamily::parse(&mut msg)?;
                 Family(fam)
             }
-            HwNetworkCommandType::GetSubfamily => {
-                todo!()
-            }
             HwNetworkCommandType::Subfamily => {
                 let fam = NetworkSubFamily::parse(&mut msg)?;
                 Subfamily(fam)
@@ -787,9 +888,6 @@ impl NetworkCommand {
                 let fam = NetworkSubFamily::parse(&mut msg)?;
                 SubfamilySet(fam)
             }
-            HwNetworkCommandType::GetExtendedFamily => {
-                todo!()
-            }
             HwNetworkCommandType::ExtendedFamily => {
                 // Bit 7 | sss | ffff
                 let byte = next!(msg);
@@ -808,8 +906,15 @@ impl NetworkCommand {
                 let subfamily = ok!(NetworkSubFamily::from_u8(sub_bytes));
                 ExtendedFamilySet { family, subfamily }
             }
-            HwNetworkCommandType::ResetLongPressTiming => {
-                todo!()
+            HwNetworkCommandType::JoinDenied
+            | HwNetworkCommandType::GetFamily
+            | HwNetworkCommandType::GetSubfamily
+            | HwNetworkCommandType::GetExtendedFamily
+            | HwNetworkCommandType::ResetLongPressTiming => {
+                return Err(Error::NotImplementedError(format!(
+                    "Parsing {:?} network commands",
+                    command_type
+                )))
             }
         })
     }
@@ -860,8 +965,8 @@ pub enum LockStatus {
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct InformationRequest {
-    port_id: u8,
-    information_type: InformationType,
+    pub(crate) port_id: u8,
+    pub(crate) information_type: InformationType,
 }
 
 impl InformationRequest {
@@ -873,6 +978,16 @@ impl InformationRequest {
             information_type,
         })
     }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        vec![
+            0,
+            0,
+            MessageType::PortInformationRequest as u8,
+            self.port_id,
+            self.information_type as u8,
+        ]
+    }
 }
 
 #[repr(u8)]
@@ -885,9 +1000,9 @@ pub enum InformationType {
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct ModeInformationRequest {
-    port_id: u8,
-    mode: u8,
-    information_type: ModeInformationType,
+    pub(crate) port_id: u8,
+    pub(crate) mode: u8,
+    pub(crate) information_type: ModeInformationType,
 }
 
 impl ModeInformationRequest {
@@ -901,6 +1016,17 @@ impl ModeInformationRequest {
             information_type,
         })
     }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        vec![
+            0,
+            0,
+            MessageType::PortModeInformationRequest as u8,
+            self.port_id,
+            self.mode,
+            self.information_type as u8,
+        ]
+    }
 }
 
 #[repr(u8)]
@@ -1030,8 +1156,8 @@ impl InputSetupCombinedSubcommand {
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct PortInformationValue {
-    port_id: u8,
-    information_type: PortInformationType,
+    pub(crate) port_id: u8,
+    pub(crate) information_type: PortInformationType,
 }
 
 impl PortInformationValue {
@@ -1043,6 +1169,36 @@ impl PortInformationValue {
             information_type,
         })
     }
+
+    pub fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    pub fn information_type(&self) -> &PortInformationType {
+        &self.information_type
+    }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        let mut msg =
+            vec![0, 0, MessageType::PortInformation as u8, self.port_id];
+        match &self.information_type {
+            PortInformationType::ModeInfo {
+                capabilities,
+                mode_count,
+                input_modes,
+                output_modes,
+            } => {
+                msg.extend_from_slice(&[1, capabilities.0, *mode_count]);
+                msg.extend_from_slice(&input_modes.to_le_bytes());
+                msg.extend_from_slice(&output_modes.to_le_bytes());
+            }
+            PortInformationType::PossibleModeCombinations(combinations) => {
+                msg.push(2);
+                msg.extend_from_slice(combinations);
+            }
+        }
+        msg
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -1088,20 +1244,25 @@ impl PortInformationType {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub struct PortCapabilities(u8);
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PortCapabilities(pub(crate) u8);
 impl PortCapabilities {
     pub const LOGICAL_SYNCHRONIZABLE: u8 = 0b1000;
     pub const LOGICAL_COMBINABLE: u8 = 0b0100;
     pub const INPUT: u8 = 0b0010;
     pub const OUTPUT: u8 = 0b0001;
+
+    /// Whether all the capabilities in `flags` are present
+    pub fn contains(&self, flags: u8) -> bool {
+        self.0 & flags == flags
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct PortModeInformationValue {
-    port_id: u8,
-    mode: u8,
-    information_type: PortModeInformationType,
+    pub(crate) port_id: u8,
+    pub(crate) mode: u8,
+    pub(crate) information_type: PortModeInformationType,
 }
 
 impl PortModeInformationValue {
@@ -1115,6 +1276,31 @@ impl PortModeInformationValue {
             information_type,
         })
     }
+
+    pub fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    pub fn mode(&self) -> u8 {
+        self.mode
+    }
+
+    pub fn information_type(&self) -> &PortModeInformationType {
+        &self.information_type
+    }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        let mut msg = vec![
+            0,
+            0,
+            MessageType::PortModeInformation as u8,
+            self.port_id,
+            self.mode,
+            self.information_type.kind() as u8,
+        ];
+        msg.extend_from_slice(&self.information_type.serialise());
+        msg
+    }
 }
 
 #[repr(u8)]
@@ -1144,6 +1330,23 @@ pub enum PortModeInformationType {
 }
 
 impl PortModeInformationType {
+    /// Which kind of information this is, i.e. what was requested
+    pub fn kind(&self) -> ModeInformationType {
+        use PortModeInformationType::*;
+
+        match self {
+            Name(_) => ModeInformationType::Name,
+            RawRange { .. } => ModeInformationType::Raw,
+            PctRange { .. } => ModeInformationType::Pct,
+            SiRange { .. } => ModeInformationType::Si,
+            Symbol(_) => ModeInformationType::Symbol,
+            Mapping { .. } => ModeInformationType::Mapping,
+            MotorBias(_) => ModeInformationType::MotorBias,
+            CapabilityBits(_) => ModeInformationType::CapabilityBits,
+            ValueFormat(_) => ModeInformationType::ValueFormat,
+        }
+    }
+
     pub fn parse<'a>(mut msg: impl Iterator<Item = &'a u8>) -> Result<Self> {
         use PortModeInformationType::*;
 
@@ -1219,24 +1422,74 @@ impl PortModeInformationType {
             }
         })
     }
+
+    /// Payload of the information, without its type
+    pub fn serialise(&self) -> Vec<u8> {
+        use PortModeInformationType::*;
+
+        match self {
+            Name(bytes) | Symbol(bytes) => bytes.clone(),
+            RawRange { min, max }
+            | PctRange { min, max }
+            | SiRange { min, max } => {
+                let mut range = min.to_le_bytes().to_vec();
+                range.extend_from_slice(&max.to_le_bytes());
+                range
+            }
+            Mapping { input, output } => vec![input.0, output.0],
+            MotorBias(bias) => vec![*bias],
+            CapabilityBits(bits) => bits.to_vec(),
+            ValueFormat(format) => vec![
+                format.number_of_datasets,
+                format.dataset_type as u8,
+                format.total_figures,
+                format.decimals,
+            ],
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct ValueFormatType {
-    number_of_datasets: u8,
-    dataset_type: DatasetType,
-    total_figures: u8,
-    decimals: u8,
+    pub(crate) number_of_datasets: u8,
+    pub(crate) dataset_type: DatasetType,
+    pub(crate) total_figures: u8,
+    pub(crate) decimals: u8,
+}
+
+impl ValueFormatType {
+    pub fn number_of_datasets(&self) -> u8 {
+        self.number_of_datasets
+    }
+
+    pub fn dataset_type(&self) -> DatasetType {
+        self.dataset_type
+    }
+
+    /// Number of figures to show when displaying a value
+    pub fn total_figures(&self) -> u8 {
+        self.total_figures
+    }
+
+    /// Number of those figures after the decimal point
+    pub fn decimals(&self) -> u8 {
+        self.decimals
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub struct MappingValue(u8);
+pub struct MappingValue(pub(crate) u8);
 impl MappingValue {
     pub const SUPPORTS_NULL: u8 = 0b1000_0000;
     pub const SUPPORTS_FUNCTIONAL2: u8 = 0b0100_0000;
     pub const ABS: u8 = 0b0001_0000;
     pub const REL: u8 = 0b0000_1000;
     pub const DIS: u8 = 0b0000_0100;
+
+    /// Whether all the flags in `flags` are set
+    pub fn contains(&self, flags: u8) -> bool {
+        self.0 & flags == flags
+    }
 }
 
 #[repr(u8)]
@@ -1275,6 +1528,30 @@ impl PortValueSingleFormat {
     pub fn process(&self, _type_mapping: ()) -> HashMap<u8, TypedValue> {
         unimplemented!()
     }
+
+    /// Port of the first value in the message. In practice hubs send a
+    /// separate message for each port in single-mode operation.
+    pub fn port_id(&self) -> Option<u8> {
+        self.values.first().copied()
+    }
+
+    /// Raw value bytes following the port id
+    pub fn data(&self) -> &[u8] {
+        self.values.get(1..).unwrap_or_default()
+    }
+
+    /// A message carrying one value for one port
+    pub fn new(port_id: u8, data: &[u8]) -> Self {
+        let mut values = vec![port_id];
+        values.extend_from_slice(data);
+        Self { values }
+    }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        let mut msg = vec![0, 0, MessageType::PortValueSingle as u8];
+        msg.extend_from_slice(&self.values);
+        msg
+    }
 }
 
 /// The PortValueCombinedFormat is some horrific set of pointers to
@@ -1322,6 +1599,19 @@ impl PortInputFormatSingleFormat {
             notification_enabled,
         })
     }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        let mut msg = vec![
+            0,
+            0,
+            MessageType::PortInputFormatSingle as u8,
+            self.port_id,
+            self.mode,
+        ];
+        msg.extend_from_slice(&self.delta.to_le_bytes());
+        msg.push(self.notification_enabled as u8);
+        msg
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -1378,6 +1668,22 @@ impl VirtualPortSetupFormat {
             ))),
         }
     }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        use VirtualPortSetupFormat::*;
+        let mut msg = vec![
+            0, // len
+            0, // hub id
+            MessageType::VirtualPortSetup as u8,
+        ];
+        match self {
+            Disconnect { port_id } => msg.extend_from_slice(&[0x00, *port_id]),
+            Connect { port_a, port_b } => {
+                msg.extend_from_slice(&[0x01, *port_a, *port_b])
+            }
+        }
+        msg
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -1418,22 +1724,121 @@ impl PortOutputCommandFormat {
                 let profile =
                     ((*use_acc_profile as u8) << 1) | (*use_dec_profile as u8);
                 let speed = speed.to_le_bytes()[0];
-                vec![
-                    0, // len
-                    0, // hub id
-                    MessageType::PortOutputCommand as u8,
-                    self.port_id,
-                    0x11,
-                    0x01,
-                    speed,
-                    max_power.to_u8(),
-                    profile,
-                ]
+                let mut msg = self.header(0x01);
+                msg.extend_from_slice(&[speed, max_power.to_u8(), profile]);
+                msg
+            }
+            StartPower2 { power1, power2 } => {
+                let mut msg = self.header(0x02);
+                msg.extend_from_slice(&[power1.to_u8(), power2.to_u8()]);
+                msg
+            }
+            StartSpeed2 {
+                speed1,
+                speed2,
+                max_power,
+                use_acc_profile,
+                use_dec_profile,
+            } => {
+                let mut msg = self.header(0x08);
+                msg.extend_from_slice(&[
+                    speed1.to_le_bytes()[0],
+                    speed2.to_le_bytes()[0],
+                    max_power.to_le_bytes()[0],
+                    use_profile(*use_acc_profile, *use_dec_profile),
+                ]);
+                msg
+            }
+            StartSpeedForDegrees2 {
+                degrees,
+                speed_l,
+                speed_r,
+                max_power,
+                end_state,
+                use_acc_profile,
+                use_dec_profile,
+            } => {
+                let mut msg = self.header(0x0c);
+                msg.extend_from_slice(&degrees.to_le_bytes());
+                msg.extend_from_slice(&[
+                    speed_l.to_le_bytes()[0],
+                    speed_r.to_le_bytes()[0],
+                    max_power.to_le_bytes()[0],
+                    *end_state as u8,
+                    use_profile(*use_acc_profile, *use_dec_profile),
+                ]);
+                msg
+            }
+            GotoAbsolutePosition {
+                abs_pos,
+                speed,
+                max_power,
+                end_state,
+                use_acc_profile,
+                use_dec_profile,
+            } => {
+                let mut msg = self.header(0x0d);
+                msg.extend_from_slice(&abs_pos.to_le_bytes());
+                msg.extend_from_slice(&[
+                    speed.to_le_bytes()[0],
+                    max_power.to_le_bytes()[0],
+                    *end_state as u8,
+                    use_profile(*use_acc_profile, *use_dec_profile),
+                ]);
+                msg
+            }
+            GotoAbsolutePosition2 {
+                abs_pos1,
+                abs_pos2,
+                speed,
+                max_power,
+                end_state,
+                use_acc_profile,
+                use_dec_profile,
+            } => {
+                let mut msg = self.header(0x0e);
+                msg.extend_from_slice(&abs_pos1.to_le_bytes());
+                msg.extend_from_slice(&abs_pos2.to_le_bytes());
+                msg.extend_from_slice(&[
+                    speed.to_le_bytes()[0],
+                    max_power.to_le_bytes()[0],
+                    *end_state as u8,
+                    use_profile(*use_acc_profile, *use_dec_profile),
+                ]);
+                msg
+            }
+            PresetEncoder2 {
+                left_position,
+                right_position,
+            } => {
+                let mut msg = self.header(0x14);
+                msg.extend_from_slice(&left_position.to_le_bytes());
+                msg.extend_from_slice(&right_position.to_le_bytes());
+                msg
             }
             WriteDirectModeData(data) => data.serialise(self),
             _ => todo!(),
         }
     }
+
+    /// Common prefix of a port output command: length placeholder, hub
+    /// id, message type, port, startup/completion byte and subcommand
+    fn header(&self, subcommand: u8) -> Vec<u8> {
+        vec![
+            0, // len
+            0, // hub id
+            MessageType::PortOutputCommand as u8,
+            self.port_id,
+            self.startup_info.serialise(&self.completion_info),
+            subcommand,
+        ]
+    }
+}
+
+/// Encode the UseProfile byte: bit 0 selects the acceleration profile and
+/// bit 1 the deceleration profile
+fn use_profile(use_acc_profile: bool, use_dec_profile: bool) -> u8 {
+    (use_acc_profile as u8) | ((use_dec_profile as u8) << 1)
 }
 
 #[repr(u8)]
@@ -1557,6 +1962,21 @@ impl PortOutputSubcommand {
         let subcomm = next!(msg);
         trace!("Port output subcommand: {:x}", subcomm);
         Ok(match subcomm {
+            0x01 | 0x07 => {
+                // StartSpeed(Speed, MaxPower, UseProfile). `serialise`
+                // sends this with subcommand 0x01.
+                let speed = next_i8!(msg);
+                let max_power = Power::parse(&mut msg)?;
+                let use_prof = next!(msg);
+                let use_acc_profile = (use_prof & 0x01) != 0;
+                let use_dec_profile = (use_prof & 0x02) != 0;
+                StartSpeed {
+                    speed,
+                    max_power,
+                    use_acc_profile,
+                    use_dec_profile,
+                }
+            }
             0x02 => {
                 // StartPower(Power1, Power2)
                 let power1 = Power::parse(&mut msg)?;
@@ -1581,20 +2001,6 @@ impl PortOutputSubcommand {
                     profile_number,
                 }
             }
-            0x07 => {
-                // StartSpeed(Speed, MaxPower, UseProfile)
-                let speed = next_i8!(msg);
-                let max_power = Power::parse(&mut msg)?;
-                let use_prof = next!(msg);
-                let use_acc_profile = (use_prof & 0x01) != 0;
-                let use_dec_profile = (use_prof & 0x02) != 0;
-                StartSpeed {
-                    speed,
-                    max_power,
-                    use_acc_profile,
-                    use_dec_profile,
-                }
-            }
             0x08 => {
                 // StartSpeed(Speed1, Speed2, MaxPower, UseProfile)
                 let speed1 = next_i8!(msg);
@@ -1739,12 +2145,12 @@ impl PortOutputSubcommand {
                     right_position,
                 }
             }
-            50 => {
+            0x50 => {
                 // WriteDirect(Byte[0],Byte[0 + n])
                 let data = WriteDirectPayload::parse(&mut msg)?;
                 WriteDirect(data)
             }
-            51 => {
+            0x51 => {
                 // WriteDirectModeData(Mode, PayLoad[0] PayLoad [0 + n]
                 let data = WriteDirectModeDataPayload::parse(&mut msg)?;
                 WriteDirectModeData(data)
@@ -1810,7 +2216,9 @@ pub enum WriteDirectPayload {
 
 impl WriteDirectPayload {
     pub fn parse<'a>(_msg: impl Iterator<Item = &'a u8>) -> Result<Self> {
-        todo!()
+        Err(Error::NotImplementedError(
+            "Parsing WriteDirect payloads".to_string(),
+        ))
     }
 }
 
@@ -1851,6 +2259,12 @@ pub enum WriteDirectModeDataPayload {
         green: u8,
         blue: u8,
     },
+    /// Write to an arbitrary mode of a device, e.g. to drive the outputs
+    /// of a sensor
+    ModeData {
+        mode: u8,
+        data: Vec<u8>,
+    },
 }
 
 impl WriteDirectModeDataPayload {
@@ -1859,7 +2273,7 @@ impl WriteDirectModeDataPayload {
 
         let mode = next!(msg);
         Ok(match mode {
-            0x01 => {
+            0x00 => {
                 // StartPower(Power)
                 let power = Power::parse(&mut msg)?;
                 StartPower(power)
@@ -1903,11 +2317,10 @@ impl WriteDirectModeDataPayload {
                 let blue = next!(msg);
                 SetRgbColors { red, green, blue }
             }
-            m => {
-                return Err(Error::ParseError(format!(
-                    "Invalid write direct mode {}",
-                    m
-                )))
+            mode => {
+                // Meaning depends on the device, so leave it to the caller
+                let data = msg.copied().collect();
+                ModeData { mode, data }
             }
         })
     }
@@ -1946,7 +2359,49 @@ impl WriteDirectModeDataPayload {
                     power,
                 ]
             }
-            _ => todo!(),
+            ModeData { mode, data } => {
+                let mut msg = meta.header(0x51); // WriteDirect
+                msg.push(*mode);
+                msg.extend_from_slice(data);
+                msg
+            }
+            SetRgbColorNo(color) => {
+                let mut msg = meta.header(0x51); // WriteDirect
+                msg.extend_from_slice(&[
+                    HubLedMode::Colour as u8,
+                    color.to_le_bytes()[0],
+                ]);
+                msg
+            }
+            PresetEncoder(position) => {
+                let mut msg = meta.header(0x51); // WriteDirect
+                msg.push(0x02); // mode
+                msg.extend_from_slice(&position.to_le_bytes());
+                msg
+            }
+            TiltImpactPreset(count) => {
+                let mut msg = meta.header(0x51); // WriteDirect
+                msg.push(0x03); // mode
+                msg.extend_from_slice(&count.to_le_bytes());
+                msg
+            }
+            TiltConfigOrientation(orientation) => {
+                let mut msg = meta.header(0x51); // WriteDirect
+                msg.extend_from_slice(&[0x05, *orientation as u8]);
+                msg
+            }
+            TiltConfigImpact {
+                impact_threshold,
+                bump_holdoff,
+            } => {
+                let mut msg = meta.header(0x51); // WriteDirect
+                msg.extend_from_slice(&[
+                    0x06,
+                    impact_threshold.to_le_bytes()[0],
+                    bump_holdoff.to_le_bytes()[0],
+                ]);
+                msg
+            }
         }
     }
 }
@@ -1971,9 +2426,9 @@ impl Orientation {
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct PortOutputCommandFeedbackFormat {
-    msg1: FeedbackMessage,
-    msg2: Option<FeedbackMessage>,
-    msg3: Option<FeedbackMessage>,
+    pub(crate) msg1: FeedbackMessage,
+    pub(crate) msg2: Option<FeedbackMessage>,
+    pub(crate) msg3: Option<FeedbackMessage>,
 }
 
 impl PortOutputCommandFeedbackFormat {
@@ -1983,16 +2438,31 @@ impl PortOutputCommandFeedbackFormat {
         let msg3 = FeedbackMessage::parse(&mut msg).ok();
         Ok(PortOutputCommandFeedbackFormat { msg1, msg2, msg3 })
     }
+
+    /// Feedback for each port mentioned in the message
+    pub fn messages(&self) -> impl Iterator<Item = &FeedbackMessage> {
+        std::iter::once(&self.msg1)
+            .chain(self.msg2.as_ref())
+            .chain(self.msg3.as_ref())
+    }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        let mut msg = vec![0, 0, MessageType::PortOutputCommandFeedback as u8];
+        for feedback in self.messages() {
+            msg.extend_from_slice(&[feedback.port_id, feedback.bitfields()]);
+        }
+        msg
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct FeedbackMessage {
-    port_id: u8,
-    empty_cmd_in_progress: bool,
-    empty_cmd_completed: bool,
-    discarded: bool,
-    idle: bool,
-    busy_full: bool,
+    pub(crate) port_id: u8,
+    pub(crate) empty_cmd_in_progress: bool,
+    pub(crate) empty_cmd_completed: bool,
+    pub(crate) discarded: bool,
+    pub(crate) idle: bool,
+    pub(crate) busy_full: bool,
 }
 
 impl FeedbackMessage {
@@ -2013,6 +2483,38 @@ impl FeedbackMessage {
             busy_full,
         })
     }
+
+    pub fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    /// A command is running on the port
+    pub fn in_progress(&self) -> bool {
+        self.empty_cmd_in_progress
+    }
+
+    /// The port's buffer is empty and the last command has completed
+    pub fn completed(&self) -> bool {
+        self.empty_cmd_completed
+    }
+
+    /// The port has finished all its commands and is waiting for more
+    pub fn idle(&self) -> bool {
+        self.idle
+    }
+
+    /// A command was dropped in favour of a newer one
+    pub fn discarded(&self) -> bool {
+        self.discarded
+    }
+
+    fn bitfields(&self) -> u8 {
+        (self.empty_cmd_in_progress as u8)
+            | (self.empty_cmd_completed as u8) << 1
+            | (self.discarded as u8) << 2
+            | (self.idle as u8) << 3
+            | (self.busy_full as u8) << 4
+    }
 }
 
 #[cfg(test)]
@@ -2052,6 +2554,51 @@ mod test {
         }
     }
 
+    #[test]
+    fn attach_io_event() {
+        init();
+        let msg = NotificationMessage::parse(&[
+            15, 0, 4, 0, 1, 47, 0, 0, 0, 0, 16, 0, 0, 0, 16,
+        ])
+        .unwrap();
+        let NotificationMessage::HubAttachedIo(AttachedIo {
+            port: 0,
+            event:
+                IoAttachEvent::AttachedIo {
+                    io_type_id,
+                    hw_rev,
+                    fw_rev,
+                },
+        }) = msg
+        else {
+            panic!("wrong type: {msg:?}");
+        };
+        assert_eq!(io_type_id, DeviceType::TechnicXlargeLinearMotor as u16);
+        assert_eq!(hw_rev.major, 1);
+        assert_eq!(fw_rev.major, 1);
+
+        let msg = NotificationMessage::parse(&[5, 0, 4, 0, 0]).unwrap();
+        assert_eq!(
+            msg,
+            NotificationMessage::HubAttachedIo(AttachedIo {
+                port: 0,
+                event: IoAttachEvent::DetachedIo,
+            })
+        );
+
+        let msg = NotificationMessage::parse(&[7, 0, 4, 16, 2, 0, 1]).unwrap();
+        assert_eq!(
+            msg,
+            NotificationMessage::HubAttachedIo(AttachedIo {
+                port: 16,
+                event: IoAttachEvent::AttachedVirtualIo {
+                    port_a: 0,
+                    port_b: 1
+                },
+            })
+        );
+    }
+
     #[test]
     fn error_message() {
         init();
@@ -2312,9 +2859,311 @@ mod test {
                 subcommand,
             });
         let serialised = msg.serialise();
-        let correct = &mut [0, 0, 0x81, 1, 0x11, 0x01, 0x12, 0x34, 0x03];
+        let correct = &mut [0, 0, 0x81, 1, 0x10, 0x01, 0x12, 0x34, 0x03];
         correct[0] = correct.len() as u8;
 
         assert_eq!(&serialised, correct);
     }
+
+    #[test]
+    fn virtual_port_setup() {
+        init();
+        let test_cases: &[(VirtualPortSetupFormat, &[u8])] = &[
+            (
+                VirtualPortSetupFormat::Connect {
+                    port_a: 0,
+                    port_b: 1,
+                },
+                &[6, 0, 0x61, 0x01, 0, 1],
+            ),
+            (
+                VirtualPortSetupFormat::Disconnect { port_id: 0x10 },
+                &[5, 0, 0x61, 0x00, 0x10],
+            ),
+        ];
+
+        for (setup, correct) in test_cases {
+            let msg = NotificationMessage::VirtualPortSetup(setup.clone());
+            let serialised = msg.serialise();
+            assert_eq!(&serialised, correct);
+            assert_eq!(NotificationMessage::parse(&serialised).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn motor_pair_commands() {
+        init();
+        let subcommands = [
+            PortOutputSubcommand::StartSpeed2 {
+                speed1: 50,
+                speed2: -50,
+                max_power: 100,
+                use_acc_profile: true,
+                use_dec_profile: false,
+            },
+            PortOutputSubcommand::StartSpeedForDegrees2 {
+                degrees: 720,
+                speed_l: -30,
+                speed_r: 30,
+                max_power: 80,
+                end_state: EndState::Hold,
+                use_acc_profile: false,
+                use_dec_profile: true,
+            },
+            PortOutputSubcommand::GotoAbsolutePosition2 {
+                abs_pos1: -90,
+                abs_pos2: 90,
+                speed: 20,
+                max_power: 50,
+                end_state: EndState::Brake,
+                use_acc_profile: true,
+                use_dec_profile: true,
+            },
+            PortOutputSubcommand::PresetEncoder2 {
+                left_position: 0,
+                right_position: -1234,
+            },
+        ];
+
+        for subcommand in subcommands {
+            let msg = NotificationMessage::PortOutputCommand(
+                PortOutputCommandFormat {
+                    port_id: 0x10,
+                    startup_info: StartupInfo::ExecuteImmediately,
+                    completion_info: CompletionInfo::CommandFeedback,
+                    subcommand,
+                },
+            );
+            let serialised = msg.serialise();
+            assert_eq!(serialised[0] as usize, serialised.len());
+            assert_eq!(&serialised[2..5], &[0x81, 0x10, 0x11]);
+            assert_eq!(NotificationMessage::parse(&serialised).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn port_value_single() {
+        init();
+        // Motor on port 2 reporting position 0x0168 = 360 degrees
+        let msg: &[u8] = &[8, 0, 0x45, 2, 0x68, 0x01, 0, 0];
+        let NotificationMessage::PortValueSingle(value) =
+            NotificationMessage::parse(msg).unwrap()
+        else {
+            panic!("wrong type");
+        };
+        assert_eq!(value.port_id(), Some(2));
+        assert_eq!(value.data(), &[0x68, 0x01, 0, 0]);
+    }
+
+    #[test]
+    fn motor_position_commands() {
+        init();
+        let msg =
+            NotificationMessage::PortOutputCommand(PortOutputCommandFormat {
+                port_id: 3,
+                startup_info: StartupInfo::ExecuteImmediately,
+                completion_info: CompletionInfo::NoAction,
+                subcommand: PortOutputSubcommand::GotoAbsolutePosition {
+                    abs_pos: -45,
+                    speed: 40,
+                    max_power: 60,
+                    end_state: EndState::Hold,
+                    use_acc_profile: true,
+                    use_dec_profile: true,
+                },
+            });
+        let serialised = msg.serialise();
+        let correct = &mut [
+            0_u8, 0, 0x81, 3, 0x10, 0x0d, 0xd3, 0xff, 0xff, 0xff, 40, 60, 126,
+            0x03,
+        ];
+        correct[0] = correct.len() as u8;
+        assert_eq!(&serialised, correct);
+        assert_eq!(NotificationMessage::parse(&serialised).unwrap(), msg);
+
+        let msg =
+            NotificationMessage::PortOutputCommand(PortOutputCommandFormat {
+                port_id: 3,
+                startup_info: StartupInfo::ExecuteImmediately,
+                completion_info: CompletionInfo::NoAction,
+                subcommand: PortOutputSubcommand::WriteDirectModeData(
+                    WriteDirectModeDataPayload::PresetEncoder(360),
+                ),
+            });
+        let serialised = msg.serialise();
+        let correct =
+            &mut [0_u8, 0, 0x81, 3, 0x10, 0x51, 0x02, 0x68, 0x01, 0, 0];
+        correct[0] = correct.len() as u8;
+        assert_eq!(&serialised, correct);
+    }
+
+    #[test]
+    fn serialise_set_colour() {
+        init();
+        let msg =
+            NotificationMessage::PortOutputCommand(PortOutputCommandFormat {
+                port_id: 50,
+                startup_info: StartupInfo::ExecuteImmediately,
+                completion_info: CompletionInfo::NoAction,
+                subcommand: PortOutputSubcommand::WriteDirectModeData(
+                    WriteDirectModeDataPayload::SetRgbColorNo(Color::Red as i8),
+                ),
+            });
+
+        let serialised = msg.serialise();
+        let correct = &mut [0_u8, 0, 0x81, 50, 0x10, 0x51, 0x00, 9];
+        correct[0] = correct.len() as u8;
+
+        assert_eq!(&serialised, correct);
+    }
+
+    #[test]
+    fn mode_information() {
+        let msg = NotificationMessage::PortModeInformationRequest(
+            ModeInformationRequest {
+                port_id: 60,
+                mode: 0,
+                information_type: ModeInformationType::Si,
+            },
+        );
+        assert_eq!(msg.serialise(), vec![6, 0, 0x22, 60, 0, 3]);
+
+        let mut reply = vec![14, 0, 0x44, 60, 0, 3];
+        reply.extend_from_slice(&0.0_f32.to_le_bytes());
+        reply.extend_from_slice(&9620.0_f32.to_le_bytes());
+        let NotificationMessage::PortModeInformation(info) =
+            NotificationMessage::parse(&reply).unwrap()
+        else {
+            panic!("Not a mode information message");
+        };
+        assert_eq!(info.port_id(), 60);
+        assert_eq!(info.mode(), 0);
+        assert_eq!(info.information_type().kind(), ModeInformationType::Si);
+        assert_eq!(
+            info.information_type(),
+            &PortModeInformationType::SiRange {
+                min: 0.0,
+                max: 9620.0
+            }
+        );
+    }
+
+    #[test]
+    fn tilt_config_commands() {
+        let cmd = |payload| {
+            NotificationMessage::PortOutputCommand(PortOutputCommandFormat {
+                port_id: 58,
+                startup_info: StartupInfo::ExecuteImmediately,
+                completion_info: CompletionInfo::NoAction,
+                subcommand: PortOutputSubcommand::WriteDirectModeData(payload),
+            })
+            .serialise()
+        };
+        assert_eq!(
+            cmd(WriteDirectModeDataPayload::TiltImpactPreset(258)),
+            vec![11, 0, 0x81, 58, 0x10, 0x51, 0x03, 2, 1, 0, 0]
+        );
+        assert_eq!(
+            cmd(WriteDirectModeDataPayload::TiltConfigOrientation(
+                Orientation::Top
+            )),
+            vec![8, 0, 0x81, 58, 0x10, 0x51, 0x05, 5]
+        );
+        assert_eq!(
+            cmd(WriteDirectModeDataPayload::TiltConfigImpact {
+                impact_threshold: 10,
+                bump_holdoff: 20,
+            }),
+            vec![9, 0, 0x81, 58, 0x10, 0x51, 0x06, 10, 20]
+        );
+    }
+
+    #[test]
+    fn power_commands() {
+        let cmd = |subcommand| {
+            NotificationMessage::PortOutputCommand(PortOutputCommandFormat {
+                port_id: 16,
+                startup_info: StartupInfo::ExecuteImmediately,
+                completion_info: CompletionInfo::NoAction,
+                subcommand,
+            })
+            .serialise()
+        };
+        assert_eq!(
+            cmd(PortOutputSubcommand::StartPower2 {
+                power1: Power::Cw(50),
+                power2: Power::Ccw(50),
+            }),
+            vec![8, 0, 0x81, 16, 0x10, 0x02, 50, 0xce]
+        );
+        assert_eq!(
+            cmd(PortOutputSubcommand::WriteDirectModeData(
+                WriteDirectModeDataPayload::StartPower(Power::Brake)
+            )),
+            vec![8, 0, 0x81, 16, 0x10, 0x51, 0x00, 127]
+        );
+    }
+
+    #[test]
+    fn port_information() {
+        let msg =
+            NotificationMessage::PortInformationRequest(InformationRequest {
+                port_id: 1,
+                information_type: InformationType::ModeInfo,
+            });
+        assert_eq!(msg.serialise(), vec![5, 0, 0x21, 1, 1]);
+
+        let reply = [11, 0, 0x43, 1, 1, 0x0f, 6, 0x1e, 0, 0x01, 0];
+        let NotificationMessage::PortInformation(info) =
+            NotificationMessage::parse(&reply).unwrap()
+        else {
+            panic!("Not a port information message");
+        };
+        assert_eq!(info.port_id(), 1);
+        let PortInformationType::ModeInfo {
+            capabilities,
+            mode_count,
+            input_modes,
+            output_modes,
+        } = info.information_type()
+        else {
+            panic!("Not mode info");
+        };
+        assert!(capabilities.contains(
+            PortCapabilities::INPUT | PortCapabilities::LOGICAL_COMBINABLE
+        ));
+        assert_eq!(*mode_count, 6);
+        assert_eq!(*input_modes, 0x1e);
+        assert_eq!(*output_modes, 0x01);
+    }
+
+    #[test]
+    fn hub_messages_round_trip() {
+        let msgs: &[&[u8]] = &[
+            &[15, 0, 4, 0, 1, 47, 0, 0, 16, 0, 0, 0, 16, 0, 0],
+            &[7, 0, 4, 16, 2, 0, 1],
+            &[8, 0, 1, 1, 6, 0x53, 0x69, 0x6d],
+            &[5, 0, 5, 0x81, 6],
+            &[11, 0, 0x43, 1, 1, 0x0f, 6, 0x1e, 0, 0x01, 0],
+            &[14, 0, 0x44, 0, 2, 0x03, 0, 0, 0xb4, 0xc3, 0, 0, 0xb4, 0x43],
+            &[10, 0, 0x44, 0, 2, 0x80, 1, 2, 4, 0],
+            &[8, 0, 0x45, 0, 0x10, 0x0e, 0, 0],
+            &[10, 0, 0x47, 0, 2, 1, 0, 0, 0, 1],
+            &[7, 0, 0x82, 0, 0x0a, 1, 0x01],
+            &[9, 0, 0x81, 0, 0x11, 0x07, 50, 100, 3],
+            &[8, 0, 0x81, 0, 0x10, 0x51, 0, 100],
+        ];
+        for msg in msgs {
+            let parsed = NotificationMessage::parse(msg).unwrap();
+            let mut serialised = parsed.serialise();
+            if let NotificationMessage::PortOutputCommand(cmd) = &parsed {
+                // StartSpeed is always sent with subcommand 0x01
+                if let PortOutputSubcommand::StartSpeed { .. } = cmd.subcommand
+                {
+                    serialised[5] = 0x07;
+                }
+            }
+            assert_eq!(&serialised, msg);
+        }
+    }
 }
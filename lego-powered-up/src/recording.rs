@@ -0,0 +1,450 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Recording of hub sessions, and replaying them without the hub.
+//!
+//! `RecordingTransport` wraps another transport and logs every message
+//! written to the hub and received from it, with the time since the
+//! recording started. Received messages are recorded before the library
+//! sees them, so the recording keeps the order of requests and replies.
+//! `ReplayTransport` plays a recording back to the library, so that a
+//! session reported in a bug can be reproduced offline.
+//!
+//! Recordings are a compact binary format: the 8 byte magic `LPUREC01`,
+//! then one entry per message of the timestamp in microseconds (u64),
+//! the direction (0 for sent, 1 for received), the length (u16) and the
+//! message itself, all little endian.
+
+use crate::error::{Error, Result};
+use crate::notifications::NotificationMessage;
+use crate::transport::{broadcast_stream, MessageStream, Transport};
+use async_trait::async_trait;
+use futures::stream::StreamExt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+
+const MAGIC: &[u8; 8] = b"LPUREC01";
+
+/// Which way a message went
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Written to the hub
+    Sent = 0,
+    /// Notified by the hub
+    Received = 1,
+}
+
+/// One message in a recording
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// Time since the recording started
+    pub timestamp: Duration,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    /// Decode the message
+    pub fn parse(&self) -> Result<NotificationMessage> {
+        NotificationMessage::parse(&self.data)
+    }
+
+    /// Fails without writing anything if the message is too long for
+    /// the length field
+    fn write_to(&self, mut writer: impl Write) -> std::io::Result<()> {
+        let len = u16::try_from(self.data.len()).map_err(|_| {
+            std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Message of {} bytes is too long", self.data.len()),
+            )
+        })?;
+        let micros = self.timestamp.as_micros() as u64;
+        writer.write_all(&micros.to_le_bytes())?;
+        writer.write_all(&[self.direction as u8])?;
+        writer.write_all(&len.to_le_bytes())?;
+        writer.write_all(&self.data)
+    }
+
+    /// Read the next record, or `None` at the end of the recording
+    fn read_from(mut reader: impl Read) -> Result<Option<Self>> {
+        let mut micros = [0; 8];
+        match reader.read_exact(&mut micros) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        let mut header = [0; 3];
+        reader.read_exact(&mut header)?;
+        let direction = match header[0] {
+            0 => Direction::Sent,
+            1 => Direction::Received,
+            d => {
+                return Err(Error::ParseError(format!(
+                    "Invalid direction {} in recording",
+                    d
+                )))
+            }
+        };
+        let mut data =
+            vec![0; u16::from_le_bytes([header[1], header[2]]) as usize];
+        reader.read_exact(&mut data)?;
+        Ok(Some(Self {
+            timestamp: Duration::from_micros(u64::from_le_bytes(micros)),
+            direction,
+            data,
+        }))
+    }
+}
+
+/// Read every record from a recording
+pub fn read_recording(reader: impl Read) -> Result<Vec<Record>> {
+    let mut reader = BufReader::new(reader);
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::ParseError("Not a hub recording".to_string()));
+    }
+    let mut records = Vec::new();
+    while let Some(record) = Record::read_from(&mut reader)? {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Read every record from a recording file
+pub fn open_recording(path: impl AsRef<Path>) -> Result<Vec<Record>> {
+    read_recording(File::open(path)?)
+}
+
+/// Appends records to the output, stamped relative to when it started
+struct Recorder {
+    start: Instant,
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Recorder {
+    fn record(&self, direction: Direction, data: &[u8]) {
+        // Stamp under the lock, so that timestamps follow the file order
+        let mut output = self.output.lock().unwrap();
+        let record = Record {
+            timestamp: self.start.elapsed(),
+            direction,
+            data: data.to_vec(),
+        };
+        // Flush every record, so that the recording survives a crash
+        if let Err(e) =
+            record.write_to(&mut *output).and_then(|_| output.flush())
+        {
+            warn!("Failed to record message: {}", e);
+        }
+    }
+}
+
+/// Transport which records everything passing through another one
+pub struct RecordingTransport {
+    inner: Arc<dyn Transport>,
+    recorder: Arc<Recorder>,
+    /// Received messages, passed on once recorded. Kept only for
+    /// subscribing more receivers; it ends with the inner stream.
+    notifications: broadcast::Receiver<Vec<u8>>,
+    listener: JoinHandle<()>,
+}
+
+impl std::fmt::Debug for RecordingTransport {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("RecordingTransport")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl RecordingTransport {
+    /// Record to a new file at `path`
+    pub async fn create(
+        inner: Arc<dyn Transport>,
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        Self::new(inner, file).await
+    }
+
+    /// Record to any writer. Messages received are recorded from now on,
+    /// whether or not anything is listening for them.
+    pub async fn new(
+        inner: Arc<dyn Transport>,
+        mut output: impl Write + Send + 'static,
+    ) -> Result<Self> {
+        output.write_all(MAGIC)?;
+        output.flush()?;
+        let recorder = Arc::new(Recorder {
+            start: Instant::now(),
+            output: Mutex::new(Box::new(output)),
+        });
+        let mut received = inner.notifications().await?;
+        let (sender, notifications) = broadcast::channel(256);
+        let listener = {
+            let recorder = recorder.clone();
+            tokio::spawn(async move {
+                while let Some(msg) = received.next().await {
+                    // Record before passing the message on, so that any
+                    // reply to it is recorded after it
+                    recorder.record(Direction::Received, &msg);
+                    // Nobody listening is fine, as with a real hub
+                    let _ = sender.send(msg);
+                }
+            })
+        };
+        Ok(Self {
+            inner,
+            recorder,
+            notifications,
+            listener,
+        })
+    }
+}
+
+impl Drop for RecordingTransport {
+    fn drop(&mut self) {
+        self.listener.abort();
+    }
+}
+
+#[async_trait]
+impl Transport for RecordingTransport {
+    async fn write(&self, msg: &[u8]) -> Result<()> {
+        self.recorder.record(Direction::Sent, msg);
+        self.inner.write(msg).await
+    }
+
+    async fn notifications(&self) -> Result<MessageStream> {
+        Ok(broadcast_stream(self.notifications.resubscribe()))
+    }
+
+    async fn subscribe(&self) -> Result<()> {
+        self.inner.subscribe().await
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        self.inner.is_connected().await
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.inner.disconnect().await
+    }
+}
+
+/// Transport which plays a recording back in place of the hub.
+///
+/// Playback starts when the library subscribes to notifications. The
+/// received messages are sent in order, but wherever the recording has
+/// a message sent to the hub, playback waits for the library to write
+/// one, so that replies follow their requests. Writes which differ from
+/// the recording are logged.
+#[derive(Debug)]
+pub struct ReplayTransport {
+    records: Mutex<Option<Vec<Record>>>,
+    realtime: bool,
+    writes: mpsc::UnboundedSender<Vec<u8>>,
+    pending_writes: Mutex<Option<mpsc::UnboundedReceiver<Vec<u8>>>>,
+    notifications: broadcast::Sender<Vec<u8>>,
+    player: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl ReplayTransport {
+    pub fn new(records: Vec<Record>) -> Self {
+        let (writes, pending_writes) = mpsc::unbounded_channel();
+        let (notifications, _) = broadcast::channel(256);
+        Self {
+            records: Mutex::new(Some(records)),
+            realtime: false,
+            writes,
+            pending_writes: Mutex::new(Some(pending_writes)),
+            notifications,
+            player: Mutex::new(None),
+        }
+    }
+
+    /// Play back a recording file
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(open_recording(path)?))
+    }
+
+    /// Keep the gaps between received messages, rather than sending them
+    /// as fast as possible
+    pub fn realtime(mut self) -> Self {
+        self.realtime = true;
+        self
+    }
+}
+
+impl Drop for ReplayTransport {
+    fn drop(&mut self) {
+        if let Some(player) = self.player.lock().unwrap().take() {
+            player.abort();
+        }
+    }
+}
+
+async fn play(
+    records: Vec<Record>,
+    mut writes: mpsc::UnboundedReceiver<Vec<u8>>,
+    notifications: broadcast::Sender<Vec<u8>>,
+    realtime: bool,
+) {
+    let mut last = records.first().map(|r| r.timestamp).unwrap_or_default();
+    for record in records {
+        match record.direction {
+            Direction::Sent => {
+                let Some(msg) = writes.recv().await else {
+                    return;
+                };
+                if msg != record.data {
+                    warn!(
+                        "Replay expected {:02x?} but was sent {:02x?}",
+                        record.data, msg
+                    );
+                }
+            }
+            Direction::Received => {
+                if realtime {
+                    let gap = record.timestamp.saturating_sub(last);
+                    tokio::time::sleep(gap).await;
+                }
+                let _ = notifications.send(record.data);
+            }
+        }
+        last = record.timestamp;
+    }
+    debug!("Replay finished");
+}
+
+#[async_trait]
+impl Transport for ReplayTransport {
+    async fn write(&self, msg: &[u8]) -> Result<()> {
+        self.writes
+            .send(msg.to_vec())
+            .map_err(|_| Error::HubError("Replay has stopped".to_string()))
+    }
+
+    async fn notifications(&self) -> Result<MessageStream> {
+        Ok(broadcast_stream(self.notifications.subscribe()))
+    }
+
+    async fn subscribe(&self) -> Result<()> {
+        let (Some(records), Some(writes)) = (
+            self.records.lock().unwrap().take(),
+            self.pending_writes.lock().unwrap().take(),
+        ) else {
+            // Already playing
+            return Ok(());
+        };
+        let player = tokio::spawn(play(
+            records,
+            writes,
+            self.notifications.clone(),
+            self.realtime,
+        ));
+        *self.player.lock().unwrap() = Some(player);
+        Ok(())
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        Ok(self.player.lock().unwrap().is_some()
+            || self.records.lock().unwrap().is_some())
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.records.lock().unwrap().take();
+        if let Some(player) = self.player.lock().unwrap().take() {
+            player.abort();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::consts::DeviceType;
+    use crate::hubs::{Hub, Port, TechnicHub};
+    use crate::simulator::SimulatedHub;
+
+    #[test]
+    fn records_round_trip() {
+        let records = vec![
+            Record {
+                timestamp: Duration::from_micros(5),
+                direction: Direction::Sent,
+                data: vec![5, 0, 0x21, 1, 1],
+            },
+            Record {
+                timestamp: Duration::from_millis(12),
+                direction: Direction::Received,
+                data: vec![7, 0, 0x82, 0, 0x0a, 1, 0x01],
+            },
+        ];
+        let mut buf = MAGIC.to_vec();
+        for record in &records {
+            record.write_to(&mut buf).unwrap();
+        }
+        assert_eq!(read_recording(&buf[..]).unwrap(), records);
+        assert!(records[1].parse().is_ok());
+        assert!(read_recording(&buf[1..]).is_err());
+        assert!(read_recording(&buf[..buf.len() - 1]).is_err());
+
+        let oversized = Record {
+            data: vec![0; 65536],
+            ..records[0].clone()
+        };
+        let mut buf = Vec::new();
+        assert!(oversized.write_to(&mut buf).is_err());
+        assert!(buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn replay_recorded_session() {
+        let path = std::env::temp_dir()
+            .join(format!("lpu-recording-{}", std::process::id()));
+
+        let simulated = SimulatedHub::new("Sim")
+            .attach(0, DeviceType::TechnicLargeAngularMotor)
+            .connect();
+        let properties = simulated.properties();
+        let recording =
+            RecordingTransport::create(simulated, &path).await.unwrap();
+        let hub = TechnicHub::init(Arc::new(recording), properties)
+            .await
+            .unwrap();
+        let description = hub.describe_port(Port::A).await.unwrap();
+        drop(hub);
+
+        let records = open_recording(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(records.iter().any(|r| r.direction == Direction::Sent));
+        assert!(records.iter().all(|r| r.parse().is_ok()));
+        // No mode information is recorded before it was asked for
+        let mut outstanding = 0;
+        for record in &records {
+            match record.parse().unwrap() {
+                NotificationMessage::PortModeInformationRequest(_) => {
+                    outstanding += 1
+                }
+                NotificationMessage::PortModeInformation(_) => {
+                    assert!(outstanding > 0, "reply recorded before request");
+                    outstanding -= 1;
+                }
+                _ => {}
+            }
+        }
+
+        let replay = Arc::new(ReplayTransport::new(records));
+        let hub = TechnicHub::init(replay, Default::default()).await.unwrap();
+        assert_eq!(hub.describe_port(Port::A).await.unwrap(), description);
+    }
+}
@@ -0,0 +1,471 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A simulated Technic hub, for exercising hubs and devices without any
+//! hardware. The hub runs as a task on the current tokio runtime and
+//! exchanges LWP3 messages with the library over in-memory channels.
+//!
+//! ```no_run
+//! # async fn example() -> lego_powered_up::Result<()> {
+//! use lego_powered_up::consts::DeviceType;
+//! use lego_powered_up::hubs::{Hub, Port, TechnicHub};
+//! use lego_powered_up::simulator::SimulatedHub;
+//!
+//! let transport = SimulatedHub::new("Simulated hub")
+//!     .attach(0, DeviceType::TechnicLargeLinearMotor)
+//!     .connect();
+//! let hub = TechnicHub::init(transport.clone(), transport.properties()).await?;
+//! let mut motor = hub.port(Port::A).await?;
+//! motor.start_speed(50, lego_powered_up::notifications::Power::Cw(100)).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Motors turn at a fixed rate for a given speed and obey speed, power,
+//! timed, degree and position commands; hub sensors report constant
+//! values. Other devices are announced but have no modes. The hub is
+//! a `HubEmulator`, connected directly to the library.
+
+use crate::consts::DeviceType;
+use crate::emulator::{HubEmulator, TICK};
+use crate::error::{Error, Result};
+use crate::hubs::HubProperties;
+use crate::transport::{broadcast_stream, MessageStream, Transport};
+use async_trait::async_trait;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, MissedTickBehavior};
+
+/// Builder for a simulated Technic hub. The hub's own sensors are always
+/// attached; devices on the external ports are added with `attach`.
+#[derive(Debug, Clone)]
+pub struct SimulatedHub {
+    emulator: HubEmulator,
+}
+
+impl SimulatedHub {
+    pub fn new(name: &str) -> Self {
+        Self {
+            emulator: HubEmulator::new(name),
+        }
+    }
+
+    /// Attach a device to a port. Ports A to D have ids 0 to 3.
+    pub fn attach(mut self, port_id: u8, device_type: DeviceType) -> Self {
+        self.emulator.attach(port_id, device_type);
+        self
+    }
+
+    /// Attach a device the library doesn't recognise, with the type id
+    /// the hub announces for it
+    pub fn attach_io_type(mut self, port_id: u8, io_type_id: u16) -> Self {
+        self.emulator.attach_io_type(port_id, io_type_id);
+        self
+    }
+
+    /// Limit the travel of the motor on a port, as if it drove a
+    /// mechanism with end stops at `min` and `max` degrees
+    pub fn end_stops(mut self, port_id: u8, min: i32, max: i32) -> Self {
+        self.emulator.set_end_stops(port_id, min, max);
+        self
+    }
+
+    /// Start the hub on the current tokio runtime, returning the
+    /// transport through which to talk to it
+    pub fn connect(self) -> Arc<SimulatedTransport> {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let (notifications, _) = broadcast::channel(256);
+        let properties = self.emulator.properties();
+        let task =
+            tokio::spawn(run(self.emulator, receiver, notifications.clone()));
+        Arc::new(SimulatedTransport {
+            properties,
+            commands,
+            notifications: Mutex::new(Some(notifications)),
+            connected: AtomicBool::new(true),
+            task,
+        })
+    }
+}
+
+/// Things to do on the hub's task
+#[derive(Debug)]
+enum Command {
+    /// Notifications were enabled
+    Subscribe,
+    /// A message was written to the hub
+    Message(Vec<u8>),
+    Attach(u8, DeviceType),
+    Detach(u8),
+}
+
+/// The host's end of a connection to a `SimulatedHub`
+#[derive(Debug)]
+pub struct SimulatedTransport {
+    properties: HubProperties,
+    commands: mpsc::UnboundedSender<Command>,
+    /// Dropped on disconnect so that notification streams end
+    notifications: Mutex<Option<broadcast::Sender<Vec<u8>>>>,
+    connected: AtomicBool,
+    task: JoinHandle<()>,
+}
+
+impl SimulatedTransport {
+    /// What discovery would have told us about the hub, for passing to
+    /// `TechnicHub::init`
+    pub fn properties(&self) -> HubProperties {
+        self.properties.clone()
+    }
+
+    /// Plug a device into a port while the hub is running
+    pub fn attach(&self, port_id: u8, device_type: DeviceType) -> Result<()> {
+        self.command(Command::Attach(port_id, device_type))
+    }
+
+    /// Unplug the device on a port while the hub is running
+    pub fn detach(&self, port_id: u8) -> Result<()> {
+        self.command(Command::Detach(port_id))
+    }
+
+    fn command(&self, command: Command) -> Result<()> {
+        if !self.connected.load(Ordering::Relaxed) {
+            return Err(Error::HubError(
+                "Simulated hub is disconnected".to_string(),
+            ));
+        }
+        self.commands.send(command).map_err(|_| {
+            Error::HubError("Simulated hub has stopped".to_string())
+        })
+    }
+}
+
+impl Drop for SimulatedTransport {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[async_trait]
+impl Transport for SimulatedTransport {
+    async fn write(&self, msg: &[u8]) -> Result<()> {
+        self.command(Command::Message(msg.to_vec()))
+    }
+
+    async fn notifications(&self) -> Result<MessageStream> {
+        let notifications = self.notifications.lock().unwrap();
+        let sender = notifications.as_ref().ok_or_else(|| {
+            Error::HubError("Simulated hub is disconnected".to_string())
+        })?;
+        Ok(broadcast_stream(sender.subscribe()))
+    }
+
+    async fn subscribe(&self) -> Result<()> {
+        self.command(Command::Subscribe)
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        Ok(self.connected.load(Ordering::Relaxed))
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.connected.store(false, Ordering::Relaxed);
+        self.task.abort();
+        self.notifications.lock().unwrap().take();
+        Ok(())
+    }
+}
+
+async fn run(
+    mut emulator: HubEmulator,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    notifications: broadcast::Sender<Vec<u8>>,
+) {
+    let mut ticks = tokio::time::interval(TICK);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_tick = Instant::now();
+    loop {
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(Command::Subscribe) => emulator.subscribe(),
+                Some(Command::Message(msg)) => emulator.handle(&msg),
+                Some(Command::Attach(port_id, device_type)) => {
+                    emulator.attach(port_id, device_type)
+                }
+                Some(Command::Detach(port_id)) => emulator.detach(port_id),
+                None => break,
+            },
+            now = ticks.tick() => {
+                emulator.step(now - last_tick);
+                last_tick = now;
+            }
+        }
+        for msg in emulator.notifications() {
+            // Nobody listening is fine, as with a real hub
+            let _ = notifications.send(msg.serialise());
+        }
+    }
+}
+
+/// Passes messages on to a simulated hub, keeping those written so that
+/// tests can check what devices send
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct Spy {
+    hub: Arc<SimulatedTransport>,
+    written: Mutex<Vec<Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl Spy {
+    pub(crate) fn new(hub: Arc<SimulatedTransport>) -> Arc<Self> {
+        Arc::new(Self {
+            hub,
+            written: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Messages written since the last call
+    pub(crate) fn take(&self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut *self.written.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Transport for Spy {
+    async fn write(&self, msg: &[u8]) -> Result<()> {
+        self.written.lock().unwrap().push(msg.to_vec());
+        self.hub.write(msg).await
+    }
+
+    async fn notifications(&self) -> Result<MessageStream> {
+        self.hub.notifications().await
+    }
+
+    async fn subscribe(&self) -> Result<()> {
+        self.hub.subscribe().await
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        self.hub.is_connected().await
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.hub.disconnect().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::consts::{
+        HubPropertyOperation, HubPropertyReference, MessageType,
+    };
+    use crate::devices::{
+        Accelerometer, Device, GyroSensor, Motor, TemperatureSensor,
+        VoltageSensor,
+    };
+    use crate::hubs::{Hub, Port, TechnicHub};
+    use crate::notifications::*;
+    use futures::StreamExt;
+    use std::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    async fn hub(motor: DeviceType) -> (Arc<SimulatedTransport>, TechnicHub) {
+        let transport = SimulatedHub::new("Sim").attach(0, motor).connect();
+        let hub = TechnicHub::init(transport.clone(), transport.properties())
+            .await
+            .unwrap();
+        (transport, hub)
+    }
+
+    #[tokio::test]
+    async fn describe_motor() {
+        let (_, hub) = hub(DeviceType::TechnicLargeAngularMotor).await;
+        let description = hub.describe_port(Port::A).await.unwrap();
+        assert_eq!(description.modes.len(), 4);
+        assert_eq!(description.combinations, vec![0b1110]);
+        let pos = description.mode("POS").unwrap();
+        assert_eq!(pos.symbol, "DEG");
+        assert!(pos.input && !pos.output);
+        assert_eq!(pos.decode(&1000_i32.to_le_bytes()), vec![1000.0]);
+    }
+
+    #[tokio::test]
+    async fn downcast_to_device_type() {
+        let (_, hub) = hub(DeviceType::TechnicLargeAngularMotor).await;
+        let mut device = hub.port(Port::A).await.unwrap();
+        assert!(device.downcast_mut::<VoltageSensor>().is_none());
+        assert_eq!(device.downcast_mut::<Motor>().unwrap().port(), Port::A);
+        let error = device.downcast::<VoltageSensor>().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Hub error: Device on port `A` is not a VoltageSensor"
+        );
+    }
+
+    #[tokio::test]
+    async fn motor_moves_to_position() {
+        let (transport, hub) = hub(DeviceType::TechnicLargeLinearMotor).await;
+        let mut feedback = transport.notifications().await.unwrap();
+        let mut motor = hub
+            .port(Port::A)
+            .await
+            .unwrap()
+            .downcast::<Motor>()
+            .unwrap();
+        let mut positions = motor.subscribe_position(1).await.unwrap();
+        motor
+            .goto_absolute_position(-90, 50, Power::Cw(100), EndState::Hold)
+            .await
+            .unwrap();
+
+        let reached = async {
+            while let Some(reading) = positions.next().await {
+                if reading.value == -90 {
+                    return true;
+                }
+            }
+            false
+        };
+        assert!(tokio::time::timeout(TIMEOUT, reached).await.unwrap());
+
+        let completed = async {
+            while let Some(msg) = feedback.next().await {
+                if let Ok(NotificationMessage::PortOutputCommandFeedback(f)) =
+                    NotificationMessage::parse(&msg)
+                {
+                    if f.messages().any(|m| m.port_id() == 0 && m.completed()) {
+                        return true;
+                    }
+                }
+            }
+            false
+        };
+        assert!(tokio::time::timeout(TIMEOUT, completed).await.unwrap());
+        assert_eq!(motor.read_mode(Motor::MODE_SPEED).await.unwrap(), vec![0]);
+    }
+
+    #[tokio::test]
+    async fn scaled_sensor_and_properties() {
+        let (transport, hub) = hub(DeviceType::TechnicLargeLinearMotor).await;
+        let mut voltage = hub
+            .port(Port::VoltageSensor)
+            .await
+            .unwrap()
+            .downcast::<VoltageSensor>()
+            .unwrap();
+        // 3500 / 4095 of 9620mV
+        assert_eq!(voltage.read_voltage().await.unwrap(), 8222);
+
+        let mut replies = transport.notifications().await.unwrap();
+        let request = [
+            5,
+            0,
+            MessageType::HubProperties as u8,
+            HubPropertyReference::AdvertisingName as u8,
+            HubPropertyOperation::RequestUpdateDownstream as u8,
+        ];
+        transport.write(&request).await.unwrap();
+        let reply = tokio::time::timeout(TIMEOUT, replies.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let NotificationMessage::HubProperties(property) =
+            NotificationMessage::parse(&reply).unwrap()
+        else {
+            panic!("wrong reply {:?}", reply);
+        };
+        assert_eq!(
+            property.property(),
+            &HubPropertyValue::AdvertisingName(b"Sim".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn hub_sensors_scaled_by_mode_information() {
+        let (_, hub) = hub(DeviceType::TechnicLargeLinearMotor).await;
+        let mut accelerometer = hub
+            .port(Port::Accelerometer)
+            .await
+            .unwrap()
+            .downcast::<Accelerometer>()
+            .unwrap();
+        let [x, y, z] = accelerometer.read_acceleration().await.unwrap();
+        assert_eq!((x, y, z), (0.0, 0.0, 1.0));
+        let mut gyro = hub
+            .port(Port::GyroSensor)
+            .await
+            .unwrap()
+            .downcast::<GyroSensor>()
+            .unwrap();
+        let rates = gyro.read_angular_rate().await.unwrap();
+        assert!(rates.iter().all(|rate| rate.abs() < 0.01), "{:?}", rates);
+        let mut temperature = hub
+            .port(Port::TemperatureSensor)
+            .await
+            .unwrap()
+            .downcast::<TemperatureSensor>()
+            .unwrap();
+        assert_eq!(temperature.read_temperature().await.unwrap(), 25.0);
+    }
+
+    #[tokio::test]
+    async fn unknown_port_is_an_error() {
+        let (transport, hub) = hub(DeviceType::TechnicLargeLinearMotor).await;
+        transport.detach(0).unwrap();
+        assert!(hub.describe_port(Port::A).await.is_err());
+        transport.disconnect().await.unwrap();
+        assert!(!hub.is_connected().await.unwrap());
+        assert!(transport.notifications().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn grey_angular_motors() {
+        let transport = SimulatedHub::new("Sim")
+            .attach(0, DeviceType::TechnicMediumAngularMotorGrey)
+            .attach(1, DeviceType::TechnicLargeAngularMotorGrey)
+            .connect();
+        let hub = TechnicHub::init(transport.clone(), transport.properties())
+            .await
+            .unwrap();
+        for port in [Port::A, Port::B] {
+            let mut motor =
+                hub.port(port).await.unwrap().downcast::<Motor>().unwrap();
+            let mut positions = motor.subscribe_position(1).await.unwrap();
+            motor.start_speed(50, Power::Cw(100)).await.unwrap();
+            let turning = async {
+                while let Some(reading) = positions.next().await {
+                    if reading.value > 0 {
+                        return true;
+                    }
+                }
+                false
+            };
+            assert!(tokio::time::timeout(TIMEOUT, turning).await.unwrap());
+            motor.start_speed(0, Power::Cw(100)).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn unrecognised_device_is_generic() {
+        let transport =
+            SimulatedHub::new("Sim").attach_io_type(1, 0x7f00).connect();
+        let hub = TechnicHub::init(transport.clone(), transport.properties())
+            .await
+            .unwrap();
+        let device = hub.port(Port::B).await.unwrap();
+        let description = format!("{:?}", device);
+        assert!(description.starts_with("GenericDevice"), "{}", description);
+        assert!(description.contains("io_type_id: 32512"), "{}", description);
+
+        let io = hub.attached_io().await;
+        let io = io.iter().find(|io| io.port == Port::B).unwrap();
+        assert_eq!(io.device_type, DeviceType::Unknown);
+        assert_eq!(io.io_type_id, 0x7f00);
+    }
+}
@@ -0,0 +1,117 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The link over which LWP3 messages are exchanged with a hub. Hubs and
+//! devices only talk to a `Transport`, so anything which can carry the
+//! messages can stand in for Bluetooth.
+
+use crate::consts::blecharacteristic;
+use crate::error::Result;
+use async_trait::async_trait;
+use btleplug::api::{Characteristic, Peripheral as _, WriteType};
+use btleplug::platform::Peripheral;
+use futures::stream::{Stream, StreamExt};
+use std::fmt::Debug;
+use std::pin::Pin;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Stream of raw messages received from a hub
+pub type MessageStream = Pin<Box<dyn Stream<Item = Vec<u8>> + Send>>;
+
+#[async_trait]
+pub trait Transport: Debug + Send + Sync {
+    /// Send one serialised message to the hub
+    async fn write(&self, msg: &[u8]) -> Result<()>;
+
+    /// Messages received from the hub. Each call returns an independent
+    /// stream, which sees every message received from then on.
+    async fn notifications(&self) -> Result<MessageStream>;
+
+    /// Ask the hub to start sending messages. Hubs announce their
+    /// attached devices straight away, so take a `notifications` stream
+    /// first to see them.
+    async fn subscribe(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn is_connected(&self) -> Result<bool>;
+
+    async fn disconnect(&self) -> Result<()>;
+}
+
+/// Transport over Bluetooth LE, using btleplug
+#[derive(Debug, Clone)]
+pub struct BtleplugTransport {
+    peripheral: Peripheral,
+    characteristic: Characteristic,
+}
+
+impl BtleplugTransport {
+    /// `characteristic` is the hub's LPF2 characteristic, on an already
+    /// connected peripheral
+    pub fn new(peripheral: Peripheral, characteristic: Characteristic) -> Self {
+        Self {
+            peripheral,
+            characteristic,
+        }
+    }
+
+    pub fn peripheral(&self) -> &Peripheral {
+        &self.peripheral
+    }
+}
+
+#[async_trait]
+impl Transport for BtleplugTransport {
+    async fn write(&self, msg: &[u8]) -> Result<()> {
+        Ok(self
+            .peripheral
+            .write(&self.characteristic, msg, WriteType::WithoutResponse)
+            .await?)
+    }
+
+    async fn notifications(&self) -> Result<MessageStream> {
+        let notifications = self.peripheral.notifications().await?;
+        Ok(Box::pin(notifications.filter_map(
+            |notification| async move {
+                (notification.uuid == *blecharacteristic::LPF2_ALL)
+                    .then_some(notification.value)
+            },
+        )))
+    }
+
+    async fn subscribe(&self) -> Result<()> {
+        Ok(self.peripheral.subscribe(&self.characteristic).await?)
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        Ok(self.peripheral.is_connected().await?)
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        Ok(self.peripheral.disconnect().await?)
+    }
+}
+
+/// Turn a broadcast receiver into a `MessageStream`. Messages missed
+/// because the receiver lagged are skipped, and the stream ends once
+/// every sender has gone.
+pub(crate) fn broadcast_stream(
+    receiver: broadcast::Receiver<Vec<u8>>,
+) -> MessageStream {
+    Box::pin(futures::stream::unfold(
+        receiver,
+        |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(msg) => return Some((msg, receiver)),
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Dropped {} messages", skipped);
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        },
+    ))
+}
@@ -11,11 +11,23 @@ pub struct Args {
 }
 
 pub enum Command {
+    Bridge(BridgeArgs),
+    Decode(DecodeArgs),
     Devices(DevicesArgs),
     Hubs(HubArgs),
     MotorTest(MotorTestArgs),
 }
 
+pub struct BridgeArgs {
+    pub device_index: Option<usize>,
+    pub listen: String,
+    pub names: Vec<String>,
+}
+
+pub struct DecodeArgs {
+    pub path: String,
+}
+
 pub struct DevicesArgs {
     pub index: Option<usize>,
 }
@@ -44,6 +56,38 @@ pub fn parse_args() -> Args {
                 .action(ArgAction::Count)
                 .help("Increase verbosity"),
         )
+        .subcommand(
+            App::new("bridge")
+                .about("Serve hubs to programs on other machines over TCP")
+                .arg(
+                    Arg::new("device")
+                        .long("device")
+                        .help("Device index (from `devices`)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("listen")
+                        .long("listen")
+                        .help("Address to listen on")
+                        .default_value("0.0.0.0:4000"),
+                )
+                .arg(
+                    Arg::new("name")
+                        .long("name")
+                        .help("Bridge the hub with this name (repeatable)")
+                        .takes_value(true)
+                        .multiple_occurrences(true),
+                ),
+        )
+        .subcommand(
+            App::new("decode")
+                .about("Decode the hub messages in a recording or capture")
+                .arg(
+                    Arg::new("file")
+                        .help("Recording, or btsnoop or btmon capture")
+                        .required(true),
+                ),
+        )
         .subcommand(
             App::new("devices")
                 .about("Information about connected Bluetooth devices")
@@ -94,7 +138,23 @@ pub fn parse_args() -> Args {
 
     let verbosity = min(matches.occurrences_of("verbose"), 2);
 
-    let command = if let Some(matches) = matches.subcommand_matches("devices") {
+    let command = if let Some(matches) = matches.subcommand_matches("bridge") {
+        Command::Bridge(BridgeArgs {
+            device_index: matches.value_of("device").map(|v| {
+                v.parse()
+                    .expect("Device index must be a nonnegative integer")
+            }),
+            listen: matches.value_of("listen").unwrap().to_string(),
+            names: matches
+                .values_of("name")
+                .map(|names| names.map(String::from).collect())
+                .unwrap_or_default(),
+        })
+    } else if let Some(matches) = matches.subcommand_matches("decode") {
+        Command::Decode(DecodeArgs {
+            path: matches.value_of("file").unwrap().to_string(),
+        })
+    } else if let Some(matches) = matches.subcommand_matches("devices") {
         let index = matches
             .value_of("index")
             .map(|v| v.parse().expect("Index must be a nonnegative integer"));
@@ -0,0 +1,36 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::argparse::BridgeArgs;
+use anyhow::Result;
+use lego_powered_up::{bridge::BridgeServer, HubFilter, PoweredUp};
+
+pub async fn run(args: &BridgeArgs) -> Result<()> {
+    let mut pu = if let Some(dev) = args.device_index {
+        PoweredUp::with_device_index(dev).await?
+    } else {
+        PoweredUp::init().await?
+    };
+
+    let server = BridgeServer::bind(&args.listen).await?;
+    println!("Bridge listening on {}", server.local_addr());
+
+    // Without names, bridge the first hub found
+    let filters = if args.names.is_empty() {
+        vec![HubFilter::Null]
+    } else {
+        args.names.iter().cloned().map(HubFilter::Name).collect()
+    };
+    for filter in filters {
+        println!("Listening for hub announcements...");
+        let hub = pu.wait_for_hub_filter(filter).await?;
+        let (transport, properties) = pu.connect_transport(&hub).await?;
+        println!("Bridging `{}` `{}`", hub.name, hub.hub_type);
+        server.add_hub(&hub.name, hub.hub_type, transport, properties);
+    }
+
+    // Serve until killed
+    std::future::pending::<()>().await;
+    Ok(())
+}
@@ -0,0 +1,33 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::argparse::DecodeArgs;
+use anyhow::Result;
+use lego_powered_up::capture::open_capture;
+use lego_powered_up::recording::{open_recording, Direction};
+
+pub async fn run(args: &DecodeArgs) -> Result<()> {
+    // Recordings made by the library decode the same way as captures
+    let records = match open_recording(&args.path) {
+        Ok(records) => records,
+        Err(_) => open_capture(&args.path)?,
+    };
+
+    for record in records {
+        let direction = match record.direction {
+            Direction::Sent => "->",
+            Direction::Received => "<-",
+        };
+        let timestamp = record.timestamp.as_secs_f64();
+        match record.parse() {
+            Ok(msg) => println!("{:>12.6} {} {:?}", timestamp, direction, msg),
+            Err(e) => println!(
+                "{:>12.6} {} {:02x?} ({})",
+                timestamp, direction, record.data, e
+            ),
+        }
+    }
+
+    Ok(())
+}
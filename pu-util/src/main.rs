@@ -7,6 +7,8 @@ use argparse::Command;
 use env_logger::Env;
 
 mod argparse;
+mod bridge;
+mod decode;
 mod devices;
 mod hubs;
 mod motor_test;
@@ -25,6 +27,8 @@ async fn main() -> Result<()> {
     .init();
 
     match args.command {
+        Command::Bridge(bridge_args) => bridge::run(&bridge_args).await?,
+        Command::Decode(decode_args) => decode::run(&decode_args).await?,
         Command::Devices(dev_args) => devices::run(&dev_args).await?,
         Command::Hubs(hub_args) => hubs::run(&hub_args).await?,
         Command::MotorTest(mot_args) => motor_test::run(&mot_args).await?,